@@ -0,0 +1,78 @@
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use tracing::info;
+
+use crate::load_error::LoadError;
+use crate::tsv::KeyedTsv;
+
+// Nodes with no acknowledgement in `by_node`
+const NONE: u32 = u32::MAX;
+
+// Attributions from --acknowledgements, kept out of the nodes' metadata so /nodes/
+// doesn't carry them. Labs acknowledge many samples each, so every distinct row is
+// stored once and nodes refer to it.
+pub struct Acknowledgements {
+    columns: Vec<String>,
+    rows: Vec<Vec<String>>,
+    // Parallel to AppState.nodes: the row acknowledging each node, or NONE
+    by_node: Vec<u32>,
+}
+
+impl Acknowledgements {
+    // Reads the TSV at `path`, matching its `key_column` to node names through
+    // `name_index`; the other columns are what's acknowledged
+    pub fn load(path: &str, key_column: &str, name_index: &HashMap<String, Vec<usize>>, num_nodes: usize) -> Result<Acknowledgements, LoadError> {
+        let tsv = KeyedTsv::open("--acknowledgements", path, key_column)?;
+        let key_index = tsv.key_index;
+        let columns: Vec<String> = tsv.columns.iter().enumerate().filter(|&(i, _)| i != key_index).map(|(_, c)| c.clone()).collect();
+        let source = tsv.describe_path().to_string();
+
+        let mut rows = Vec::new();
+        let mut row_ids: HashMap<Vec<String>, u32> = HashMap::new();
+        let mut by_node = vec![NONE; num_nodes];
+        let matched = tsv.match_rows(name_index, |indexes, fields| {
+            let row: Vec<String> = fields.iter().enumerate().filter(|&(i, _)| i != key_index).map(|(_, f)| f.to_string()).collect();
+            let id = *row_ids.entry(row).or_insert_with_key(|row| {
+                rows.push(row.clone());
+                (rows.len() - 1) as u32
+            });
+            for &idx in indexes {
+                by_node[idx] = id;
+            }
+        })?;
+        info!(
+            matched = matched.rows,
+            unmatched = matched.unmatched.len(),
+            distinct = rows.len(),
+            "Loaded acknowledgements from {}{}",
+            source,
+            matched.examples()
+        );
+        Ok(Acknowledgements { columns, rows, by_node })
+    }
+
+    fn row(&self, id: u32) -> Value {
+        let fields: Map<String, Value> = self.columns.iter().cloned().zip(self.rows[id as usize].iter().map(|f| json!(f))).collect();
+        Value::Object(fields)
+    }
+
+    // The acknowledgement for the node at `idx`, if it has one
+    pub fn for_node(&self, idx: usize) -> Option<Value> {
+        self.by_node.get(idx).filter(|&&id| id != NONE).map(|&id| self.row(id))
+    }
+
+    // The distinct acknowledgements of the nodes at `indexes`, each with how many of
+    // them it covers, most first
+    pub fn for_nodes(&self, indexes: impl Iterator<Item = usize>) -> Vec<Value> {
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for idx in indexes {
+            match self.by_node[idx] {
+                NONE => {}
+                id => *counts.entry(id).or_default() += 1,
+            }
+        }
+        let mut counts: Vec<(u32, usize)> = counts.into_iter().collect();
+        counts.sort_by_key(|&(id, count)| (std::cmp::Reverse(count), id));
+        counts.into_iter().map(|(id, count)| json!({ "acknowledgement": self.row(id), "tips": count })).collect()
+    }
+}
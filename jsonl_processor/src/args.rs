@@ -0,0 +1,514 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::compression::Codec;
+use crate::logging::{Filter, LogFormat};
+
+const USAGE: &str = "Usage: jsonl_processor [options] [<path_to_jsonl_file>]
+
+The file given as an argument is served at /config/, /nodes/ and so on; each --dataset
+is served under its own prefix, e.g. /<name>/nodes/. At least one is needed. Files may be
+gzip, zstd, xz or LZMA compressed; a path of - reads from stdin.
+
+Options:
+  --dataset <name>=<path>  Also serve the file at <path> under /<name>/; may be repeated
+  --host <address>         Address to listen on, e.g. 0.0.0.0 or [::]; may be repeated or
+                           comma-separated (default 127.0.0.1)
+  --port <port>            Port to listen on (default $PORT, or 8080)
+  --unix-socket <path>     Listen on a Unix socket, e.g. for a reverse proxy on the same
+                           host; TCP is then only listened on if --host or --port is given
+  --unix-socket-mode <mode>
+                           Permissions for the socket, in octal (default from the umask)
+  --unix-socket-owner <user>[:<group>]
+                           Owner and group for the socket, e.g. :www-data
+  --cors-origin <origin>   Origin allowed to make cross-origin requests, e.g.
+                           https://example.org, or * for any; may be repeated or
+                           comma-separated (default *)
+  --cors-allow-credentials Let the --cors-origin origins send cookies and credentials
+  --rate-limit <n>         Requests per second each client may make to /nodes/, /search/
+                           and the exports (default unlimited)
+  --rate-limit-burst <n>   Requests a client may make at once before --rate-limit
+                           applies (default one second's worth)
+  --trust-proxy            Identify clients by X-Forwarded-For rather than the connecting
+                           address, for running behind a reverse proxy
+  --max-search-limit <n>   Maximum page size for paginated searches (default 10000)
+  --autocomplete-case-insensitive
+                           Build a lowercased name index for case-insensitive autocomplete
+  --fuzzy-index            Build a BK-tree of names to enable name_fuzzy searches
+  --no-meta-index          Skip the inverted metadata index to save memory (meta searches scan instead)
+  --max-search-jobs <n>    Maximum concurrently running background searches (default 4)
+  --search-job-ttl <secs>  How long finished background search results are kept (default 600)
+  --max-export-tips <n>    Largest subtree, in tips, that can be exported (default 100000)
+  --max-nodes-returned <n> Most nodes a /nodes/ response may contain before it is thinned (default 200000)
+  --compression <codecs>   Codecs responses may be compressed with, in order of preference,
+                           or \"none\" (default gzip,br)
+  --compression-level <n>  Compression level, capped at each codec's maximum (default 6 for gzip, 4 for br)
+  --nodes-cache-mb <n>     Memory for caching /nodes/ responses, in MB; 0 disables the cache (default 256)
+  --max-concurrent-queries <n>
+                           Most /nodes/ and /search/ requests computed at once; more get a 503 (default 16)
+  --request-timeout <secs> How long computing a /nodes/ or /search/ response may take before
+                           it fails with a 503; 0 for no limit (default 30)
+  --threads <n>            Threads used to parse the data file and to reduce overplotting
+                           in large /nodes/ results (default: number of CPUs)
+  --log-level <filter>     What to log, as a level or RUST_LOG-style directives such as
+                           warn,jsonl_processor=debug (default $RUST_LOG, or info)
+  --log-format <format>    pretty or json (default pretty)
+  --shutdown-timeout <secs>
+                           How long in-flight requests may take to finish after SIGTERM
+                           or SIGINT (default 30)
+  --api-token <token>      Require \"Authorization: Bearer <token>\" on every request but
+                           /, /health and /ready (default $API_TOKEN)
+  --api-token-file <path>  Read the API token from a file instead
+  --admin-token <token>    Enables the /admin/ endpoints for requests sending
+                           \"Authorization: Bearer <token>\" (default $ADMIN_TOKEN)
+  --snapshot               Save a binary snapshot of each data file beside it, as
+                           <path>.snapshot, and load that instead while the file is unchanged
+  --snapshot-path <path>   Where to keep the snapshot, with a single dataset; implies --snapshot
+  --include-keys <keys>    Load only these metadata fields, comma-separated, with or without
+                           their meta_ prefix; the rest are dropped as the file is read
+  --exclude-keys <keys>    Drop these metadata fields as the file is read
+  --metadata-tsv <path>    Join the columns of a TSV into every dataset's metadata, matching
+                           its --metadata-key column to node names; may be compressed
+  --acknowledgements <path>
+                           Serve the attributions in a TSV at /acknowledgements/, matching its
+                           --metadata-key column to node names; may be compressed
+  --metadata-key <column>  The column of --metadata-tsv and --acknowledgements holding node
+                           names (default strain)
+  --metadata-prefix <prefix>
+                           Prefix for the joined fields, so they sit beside fields of the same
+                           name in the data file rather than replacing them
+  --gff <path>             Take gene annotations from the genes and CDS of a GFF3 file, replacing
+                           genes of the same name on the metadata line; may be compressed
+  --reference <path>       Rebuild nodes' sequences at /sequence/ from the first sequence of a
+                           FASTA file; may be compressed
+  --time-from-key <key>    Lay the tree out in time from a metadata field of dates (ISO dates,
+                           year-months or decimal years) when the data file has no x_time
+  --forest                 Load a file holding several trees: every node that is its own parent,
+                           or with none, whose parent isn't in the file, roots one
+  --recompute-num-tips     Count every node's tips from the tree rather than trusting num_tips
+                           in the data file, which is done anyway when it is missing or zero
+  --relayout               Lay the tree out vertically afresh rather than using y from the data
+                           file, which is done anyway when every node has the same y or none
+  --lenient                Skip malformed node lines and nodes reusing an earlier node's node_id,
+                           logging each, and detach nodes that don't lead to a root, rather
+                           than failing to load
+  --lenient-max-skipped <fraction>
+                           Most of the node lines --lenient may skip before the load fails
+                           anyway (default 0.01)
+  --watch                  Reload the data file whenever it changes
+  --watch-debounce <secs>  How long a changed file must stay unchanged before it is
+                           reloaded (default 5)
+  --no-signal-handlers     Leave SIGTERM and SIGINT alone, for when an embedding process
+                           manages the server's lifetime
+
+Exit status, when no dataset could be loaded:
+  3  the file couldn't be read
+  4  its compressed data is corrupt
+  5  the metadata line is malformed
+  6  a node line is malformed, or more than --lenient-max-skipped of them with --lenient
+  7  the file is empty
+  8  no node is the root, i.e. its own parent, or with none, several could be
+  9  two nodes have the same node_id
+  10 the --metadata-tsv or --acknowledgements file can't be used
+  11 the --gff file can't be parsed
+  12 the --reference file can't be used
+  13 some nodes don't lead to the root: their parent is missing, their parents form a
+     cycle or another node is its own parent
+  1  anything else";
+
+pub struct Args {
+    pub path: Option<String>,
+    // (name, path) for each --dataset
+    pub datasets: Vec<(String, String)>,
+    pub hosts: Vec<String>,
+    pub port: u16,
+    pub unix_socket: Option<String>,
+    pub unix_socket_mode: Option<u32>,
+    // uid and gid; None leaves that part as it is
+    pub unix_socket_owner: (Option<u32>, Option<u32>),
+    // Empty allows any origin
+    pub cors_origins: Vec<String>,
+    pub cors_allow_credentials: bool,
+    pub rate_limit: Option<f64>,
+    pub rate_limit_burst: Option<f64>,
+    pub trust_proxy: bool,
+    pub max_search_limit: usize,
+    pub autocomplete_case_insensitive: bool,
+    pub fuzzy_index: bool,
+    pub meta_index: bool,
+    pub max_search_jobs: usize,
+    pub search_job_ttl: u64,
+    pub max_export_tips: usize,
+    pub max_nodes_returned: usize,
+    pub compression: Vec<Codec>,
+    pub compression_level: Option<u32>,
+    pub nodes_cache_mb: usize,
+    pub max_concurrent_queries: usize,
+    pub request_timeout: u64,
+    pub threads: Option<usize>,
+    pub log_filter: Filter,
+    pub log_format: LogFormat,
+    pub shutdown_timeout: u64,
+    pub signal_handlers: bool,
+    pub api_token: Option<String>,
+    pub admin_token: Option<String>,
+    pub snapshot: bool,
+    pub snapshot_path: Option<String>,
+    pub key_filter: KeyFilter,
+    pub metadata_tsv: Option<String>,
+    pub acknowledgements: Option<String>,
+    pub metadata_key: String,
+    pub metadata_prefix: String,
+    pub gff: Option<String>,
+    pub reference: Option<String>,
+    pub time_from_key: Option<String>,
+    pub forest: bool,
+    pub recompute_num_tips: bool,
+    pub relayout: bool,
+    pub lenient: bool,
+    pub lenient_max_skipped: f64,
+    pub watch: bool,
+    pub watch_debounce: u64,
+}
+
+impl Args {
+    pub fn parse() -> Result<Args, String> {
+        Args::parse_from(std::env::args().skip(1))
+    }
+
+    // The arguments following the program name
+    pub fn parse_from(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+        let mut path = None;
+        let mut datasets: Vec<(String, String)> = Vec::new();
+        let mut hosts = Vec::new();
+        let mut port_given = false;
+        let mut unix_socket = None;
+        let mut unix_socket_mode = None;
+        let mut unix_socket_owner = (None, None);
+        let mut cors_origins = Vec::new();
+        let mut cors_allow_credentials = false;
+        let mut rate_limit = None;
+        let mut rate_limit_burst = None;
+        let mut trust_proxy = false;
+        let mut port = match std::env::var("PORT") {
+            Ok(port) => parse_value("PORT", &port)?,
+            Err(_) => 8080,
+        };
+        let mut max_search_limit = 10000;
+        let mut autocomplete_case_insensitive = false;
+        let mut fuzzy_index = false;
+        let mut meta_index = true;
+        let mut max_search_jobs = 4;
+        let mut search_job_ttl = 600;
+        let mut max_export_tips = 100000;
+        let mut max_nodes_returned = 200000;
+        let mut compression = vec![Codec::Gzip, Codec::Brotli];
+        let mut compression_level = None;
+        let mut nodes_cache_mb = 256;
+        let mut max_concurrent_queries = 16;
+        let mut request_timeout = 30;
+        let mut threads = None;
+        let mut log_filter = std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string());
+        let mut log_format = LogFormat::Pretty;
+        let mut shutdown_timeout = 30;
+        let mut signal_handlers = true;
+        let mut api_token = std::env::var("API_TOKEN").ok();
+        let mut admin_token = std::env::var("ADMIN_TOKEN").ok();
+        let mut snapshot = false;
+        let mut snapshot_path = None;
+        let mut key_filter = KeyFilter::default();
+        let mut metadata_tsv = None;
+        let mut acknowledgements = None;
+        let mut metadata_key = "strain".to_string();
+        let mut metadata_prefix = String::new();
+        let mut gff = None;
+        let mut reference = None;
+        let mut time_from_key = None;
+        let mut forest = false;
+        let mut recompute_num_tips = false;
+        let mut relayout = false;
+        let mut lenient = false;
+        let mut lenient_max_skipped = 0.01;
+        let mut watch = false;
+        let mut watch_debounce = 5;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // Accept both "--flag value" and "--flag=value"
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if arg.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (arg.clone(), None),
+            };
+            let mut value = || inline_value.clone().or_else(|| args.next()).ok_or_else(|| format!("Missing value for {}", flag));
+
+            match flag.as_str() {
+                "--dataset" => {
+                    let value = value()?;
+                    let (name, dataset_path) = value.split_once('=')
+                        .ok_or_else(|| format!("Invalid value for --dataset: {} (expected <name>=<path>)", value))?;
+                    crate::dataset::validate_name(name)?;
+                    if datasets.iter().any(|(existing, _)| existing == name) {
+                        return Err(format!("Dataset {} given more than once", name));
+                    }
+                    datasets.push((name.to_string(), dataset_path.to_string()));
+                }
+                // IPv6 addresses may be bracketed as in URLs
+                "--host" => hosts.extend(value()?.split(',').map(|host| host.trim().trim_start_matches('[').trim_end_matches(']').to_string())),
+                "--port" => {
+                    port = parse_value(&flag, &value()?)?;
+                    port_given = true;
+                }
+                "--unix-socket" => unix_socket = Some(value()?),
+                "--unix-socket-mode" => unix_socket_mode = Some(parse_mode(&value()?)?),
+                "--unix-socket-owner" => unix_socket_owner = parse_owner(&value()?)?,
+                "--cors-origin" => {
+                    for origin in value()?.split(',').map(str::trim) {
+                        cors_origins.push(parse_origin(origin)?);
+                    }
+                }
+                "--cors-allow-credentials" => cors_allow_credentials = true,
+                "--rate-limit" => rate_limit = Some(parse_positive(&flag, &value()?)?),
+                "--rate-limit-burst" => match parse_value(&flag, &value()?)? {
+                    burst if burst >= 1.0 => rate_limit_burst = Some(burst),
+                    _ => return Err(format!("{} must be at least 1", flag)),
+                },
+                "--trust-proxy" => trust_proxy = true,
+                "--max-search-limit" => max_search_limit = parse_value(&flag, &value()?)?,
+                "--autocomplete-case-insensitive" => autocomplete_case_insensitive = true,
+                "--fuzzy-index" => fuzzy_index = true,
+                "--no-meta-index" => meta_index = false,
+                "--max-search-jobs" => max_search_jobs = parse_value(&flag, &value()?)?,
+                "--search-job-ttl" => search_job_ttl = parse_value(&flag, &value()?)?,
+                "--max-export-tips" => max_export_tips = parse_value(&flag, &value()?)?,
+                "--max-nodes-returned" => max_nodes_returned = parse_value(&flag, &value()?)?,
+                "--compression" => compression = Codec::parse_list(&value()?)?,
+                "--compression-level" => compression_level = Some(parse_value(&flag, &value()?)?),
+                "--nodes-cache-mb" => nodes_cache_mb = parse_value(&flag, &value()?)?,
+                "--max-concurrent-queries" => max_concurrent_queries = parse_value(&flag, &value()?)?,
+                "--request-timeout" => request_timeout = parse_value(&flag, &value()?)?,
+                "--threads" => threads = Some(parse_value(&flag, &value()?)?),
+                "--log-level" => log_filter = value()?,
+                "--log-format" => log_format = LogFormat::parse(&value()?)?,
+                "--shutdown-timeout" => shutdown_timeout = parse_value(&flag, &value()?)?,
+                "--no-signal-handlers" => signal_handlers = false,
+                "--api-token" => api_token = Some(value()?),
+                "--api-token-file" => {
+                    let token_path = value()?;
+                    let token = std::fs::read_to_string(&token_path).map_err(|e| format!("Failed to read {}: {}", token_path, e))?;
+                    api_token = Some(token.trim().to_string());
+                }
+                "--admin-token" => admin_token = Some(value()?),
+                "--snapshot" => snapshot = true,
+                "--snapshot-path" => {
+                    snapshot_path = Some(value()?);
+                    snapshot = true;
+                }
+                "--include-keys" => key_filter.include.get_or_insert_with(Vec::new).extend(parse_keys(&value()?)),
+                "--exclude-keys" => key_filter.exclude.extend(parse_keys(&value()?)),
+                "--metadata-tsv" => metadata_tsv = Some(value()?),
+                "--acknowledgements" => acknowledgements = Some(value()?),
+                "--metadata-key" => metadata_key = value()?,
+                "--metadata-prefix" => metadata_prefix = value()?,
+                "--gff" => gff = Some(value()?),
+                "--reference" => reference = Some(value()?),
+                "--time-from-key" => time_from_key = Some(value()?),
+                "--forest" => forest = true,
+                "--recompute-num-tips" => recompute_num_tips = true,
+                "--relayout" => relayout = true,
+                "--lenient" => lenient = true,
+                "--lenient-max-skipped" => match parse_value(&flag, &value()?)? {
+                    fraction if (0.0..=1.0).contains(&fraction) => lenient_max_skipped = fraction,
+                    _ => return Err(format!("{} must be between 0 and 1", flag)),
+                },
+                "--watch" => watch = true,
+                "--watch-debounce" => watch_debounce = parse_value(&flag, &value()?)?,
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                _ if flag.starts_with("--") => return Err(format!("Unknown option: {}\n\n{}", flag, USAGE)),
+                _ if path.is_none() => path = Some(arg),
+                _ => return Err(format!("Unexpected argument: {}\n\n{}", arg, USAGE)),
+            }
+        }
+
+        if path.is_none() && datasets.is_empty() {
+            return Err(USAGE.to_string());
+        }
+        let from_stdin = path.iter().chain(datasets.iter().map(|(_, path)| path)).filter(|path| *path == "-").count();
+        if from_stdin > 1 {
+            return Err("Only one dataset can be read from stdin".to_string());
+        }
+        // It is read again for every dataset and reload
+        if [&metadata_tsv, &acknowledgements, &gff, &reference].iter().any(|path| path.as_deref() == Some("-")) {
+            return Err("--metadata-tsv, --acknowledgements, --gff and --reference can't be read from stdin".to_string());
+        }
+        if snapshot_path.is_some() && path.iter().count() + datasets.len() > 1 {
+            return Err("--snapshot-path can only be used with a single dataset".to_string());
+        }
+        if from_stdin > 0 && watch {
+            return Err("--watch can't be used with a dataset read from stdin".to_string());
+        }
+        // "*" anywhere means any origin
+        if cors_origins.iter().any(|origin| origin == "*") {
+            cors_origins.clear();
+        }
+        if cfg!(not(unix)) && unix_socket.is_some() {
+            return Err("--unix-socket is only supported on Unix".to_string());
+        }
+        // With a Unix socket, TCP is opt-in
+        if hosts.is_empty() && (unix_socket.is_none() || port_given) {
+            hosts.push("127.0.0.1".to_string());
+        }
+        if cors_allow_credentials && cors_origins.is_empty() {
+            return Err("--cors-allow-credentials needs --cors-origin set to specific origins, not *".to_string());
+        }
+
+        Ok(Args {
+            path,
+            datasets,
+            hosts,
+            port,
+            unix_socket,
+            unix_socket_mode,
+            unix_socket_owner,
+            cors_origins,
+            cors_allow_credentials,
+            rate_limit,
+            rate_limit_burst,
+            trust_proxy,
+            max_search_limit,
+            autocomplete_case_insensitive,
+            fuzzy_index,
+            meta_index,
+            max_search_jobs,
+            search_job_ttl,
+            max_export_tips,
+            max_nodes_returned,
+            compression,
+            compression_level,
+            nodes_cache_mb,
+            max_concurrent_queries,
+            request_timeout,
+            threads,
+            log_filter: Filter::parse(&log_filter)?,
+            log_format,
+            shutdown_timeout,
+            signal_handlers,
+            api_token: api_token.filter(|token| !token.is_empty()),
+            admin_token: admin_token.filter(|token| !token.is_empty()),
+            snapshot,
+            snapshot_path,
+            key_filter,
+            metadata_tsv,
+            acknowledgements,
+            metadata_key,
+            metadata_prefix,
+            gff,
+            reference,
+            time_from_key,
+            forest,
+            recompute_num_tips,
+            relayout,
+            lenient,
+            lenient_max_skipped,
+            watch,
+            watch_debounce,
+        })
+    }
+}
+
+// The metadata fields loaded. Recorded in snapshots, since one made with other fields
+// can't stand in for the file.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct KeyFilter {
+    // None keeps every field not excluded
+    pub include: Option<Vec<String>>,
+    pub exclude: Vec<String>,
+}
+
+impl KeyFilter {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_empty()
+    }
+
+    pub fn keeps(&self, key: &str) -> bool {
+        let names = |given: &String| names_field(given, key);
+        self.include.as_ref().is_none_or(|include| include.iter().any(names)) && !self.exclude.iter().any(names)
+    }
+}
+
+// Whether a key as given on the command line, with or without its meta_ prefix, is `field`
+pub fn names_field(given: &str, field: &str) -> bool {
+    field == given || field.strip_prefix("meta_") == Some(given)
+}
+
+fn parse_keys(value: &str) -> impl Iterator<Item = String> + '_ {
+    value.split(',').map(str::trim).filter(|key| !key.is_empty()).map(str::to_string)
+}
+
+// An origin as browsers send it: scheme://host[:port], with no path or trailing slash
+fn parse_origin(origin: &str) -> Result<String, String> {
+    if origin == "*" {
+        return Ok(origin.to_string());
+    }
+    let invalid = || format!("Invalid value for --cors-origin: {} (expected e.g. https://example.org)", origin);
+    let (scheme, authority) = origin.split_once("://").ok_or_else(invalid)?;
+    let (host, port) = match authority.rsplit_once(':') {
+        // The colons of a bracketed IPv6 address aren't a port separator
+        Some((host, port)) if !port.contains(']') => (host, Some(port)),
+        _ => (authority, None),
+    };
+    let host_valid = !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || "-.[]:".contains(c));
+    let port_valid = port.is_none_or(|port| port.parse::<u16>().is_ok());
+    if !matches!(scheme, "http" | "https") || !host_valid || !port_valid {
+        return Err(invalid());
+    }
+    Ok(origin.to_string())
+}
+
+// A --unix-socket-owner value: user, user:group or :group, each a name or numeric id
+fn parse_owner(value: &str) -> Result<(Option<u32>, Option<u32>), String> {
+    let (user, group) = value.split_once(':').unwrap_or((value, ""));
+    let user = match user {
+        "" => None,
+        user => Some(lookup_id("/etc/passwd", user).ok_or_else(|| format!("Unknown user: {}", user))?),
+    };
+    let group = match group {
+        "" => None,
+        group => Some(lookup_id("/etc/group", group).ok_or_else(|| format!("Unknown group: {}", group))?),
+    };
+    if (user, group) == (None, None) {
+        return Err(format!("Invalid value for --unix-socket-owner: {} (expected user, user:group or :group)", value));
+    }
+    Ok((user, group))
+}
+
+// A numeric id as given, or a name's id from `database` (/etc/passwd or /etc/group,
+// whose lines both start name:password:id)
+fn lookup_id(database: &str, name: &str) -> Option<u32> {
+    if let Ok(id) = name.parse() {
+        return Some(id);
+    }
+    let contents = std::fs::read_to_string(database).ok()?;
+    contents.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        (fields.next() == Some(name)).then(|| fields.nth(1)?.parse().ok()).flatten()
+    })
+}
+
+// A --unix-socket-mode value in octal, e.g. 660 or 0660
+fn parse_mode(value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("Invalid value for --unix-socket-mode: {} (expected octal permissions, e.g. 660)", value)),
+    }
+}
+
+fn parse_positive(flag: &str, value: &str) -> Result<f64, String> {
+    parse_value(flag, value).and_then(|n: f64| match n.is_finite() && n > 0.0 {
+        true => Ok(n),
+        false => Err(format!("{} must be a positive number", flag)),
+    })
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
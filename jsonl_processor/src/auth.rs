@@ -0,0 +1,52 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+
+use crate::args::Args;
+use crate::error::ApiError;
+
+// Paths served without a token, so a load balancer can check on the server
+const PUBLIC_PATHS: &[&str] = &["/", "/health", "/health/", "/ready", "/ready/"];
+
+// The token from an `Authorization: Bearer <token>` header
+pub fn bearer_token(headers: &header::HeaderMap) -> Option<&str> {
+    headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+// Compares without stopping at the first difference, so timing doesn't reveal the
+// length of the matching prefix
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Middleware requiring --api-token, when one is set, on everything but PUBLIC_PATHS. The
+// admin token is accepted too, so admin requests need only one. CORS preflights carry no
+// credentials and always pass.
+pub async fn require_token(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let args = req.app_data::<web::Data<Args>>();
+    if let Some(api_token) = args.and_then(|args| args.api_token.as_ref()) {
+        let preflight = req.method() == Method::OPTIONS && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+        if !preflight && !PUBLIC_PATHS.contains(&req.path()) {
+            let admin_token = args.and_then(|args| args.admin_token.as_ref());
+            let authorized = bearer_token(req.headers()).is_some_and(|given| {
+                [Some(api_token), admin_token].into_iter().flatten().any(|token| constant_time_eq(given.as_bytes(), token.as_bytes()))
+            });
+            if !authorized {
+                // A response rather than an error, so the CORS middleware still adds its
+                // headers and browsers let the frontend see the 401
+                let mut response = HttpResponse::from_error(ApiError::unauthorized("Missing or invalid API token"));
+                response.headers_mut().insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                return Ok(req.into_response(response));
+            }
+        }
+    }
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
@@ -0,0 +1,119 @@
+use actix_web::web::{self, Bytes};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use crate::AppState;
+
+struct Entry {
+    content_type: &'static str,
+    body: Bytes,
+    // Recency stamp; the entry's key is under this in `by_use`
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: HashMap<String, Entry>,
+    // last_used -> key, oldest first
+    by_use: BTreeMap<u64, String>,
+    bytes: usize,
+    clock: u64,
+}
+
+// Finished /nodes/ response bodies, evicted least recently used first once their total
+// size passes the capacity. A capacity of 0 disables the cache.
+pub struct ResponseCache {
+    entries: Mutex<Entries>,
+    capacity_bytes: usize,
+}
+
+impl ResponseCache {
+    pub fn new(capacity_bytes: usize) -> ResponseCache {
+        ResponseCache { entries: Mutex::new(Entries::default()), capacity_bytes }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity_bytes > 0
+    }
+
+    pub fn get(&self, key: &str) -> Option<(&'static str, Bytes)> {
+        let mut guard = self.entries.lock().unwrap();
+        let entries = &mut *guard;
+        entries.clock += 1;
+        let entry = entries.entries.get_mut(key)?;
+        entries.by_use.remove(&entry.last_used);
+        entry.last_used = entries.clock;
+        entries.by_use.insert(entry.last_used, key.to_string());
+        Some((entry.content_type, entry.body.clone()))
+    }
+
+    pub fn insert(&self, key: String, content_type: &'static str, body: Bytes) {
+        if body.len() > self.capacity_bytes {
+            return;
+        }
+        let mut guard = self.entries.lock().unwrap();
+        let entries = &mut *guard;
+        entries.clock += 1;
+        let last_used = entries.clock;
+        entries.bytes += body.len();
+        entries.by_use.insert(last_used, key.clone());
+        if let Some(replaced) = entries.entries.insert(key, Entry { content_type, body, last_used }) {
+            entries.bytes -= replaced.body.len();
+            entries.by_use.remove(&replaced.last_used);
+        }
+        while entries.bytes > self.capacity_bytes {
+            let Some((_, oldest)) = entries.by_use.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = entries.entries.remove(&oldest) {
+                entries.bytes -= evicted.body.len();
+            }
+        }
+    }
+}
+
+// Passes a streamed body through while keeping a copy, which is added to the /nodes/
+// cache once the body is complete. Bodies too large to cache stop being copied.
+pub struct CachingChunks<I> {
+    inner: I,
+    state: web::Data<AppState>,
+    key: Option<String>,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl<I> CachingChunks<I> {
+    pub fn new(inner: I, state: web::Data<AppState>, key: String, content_type: &'static str) -> CachingChunks<I> {
+        CachingChunks { inner, state, key: Some(key), content_type, body: Vec::new() }
+    }
+}
+
+impl<I> Iterator for CachingChunks<I>
+where
+    I: Iterator<Item = Result<Bytes, actix_web::Error>>,
+{
+    type Item = Result<Bytes, actix_web::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.inner.next();
+        match &item {
+            Some(Ok(chunk)) if self.key.is_some() => {
+                if self.body.len() + chunk.len() > self.state.nodes_cache.capacity_bytes {
+                    self.key = None;
+                    self.body = Vec::new();
+                } else {
+                    self.body.extend_from_slice(chunk);
+                }
+            }
+            Some(Err(_)) => self.key = None,
+            None => {
+                if let Some(key) = self.key.take() {
+                    let body = Bytes::from(std::mem::take(&mut self.body));
+                    self.state.nodes_cache.insert(key, self.content_type, body);
+                }
+            }
+            _ => {}
+        }
+        item
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::search::{meta_value_string, value_counts};
+use crate::{AppState, Node};
+
+// Dictionary of the distinct values of one metadata field. A value's index is its
+// position in the sorted list, so indexes are stable for a given dataset.
+pub struct ColorTable {
+    pub values: Vec<String>,
+    index_of: HashMap<String, u32>,
+}
+
+impl ColorTable {
+    fn build(state: &AppState, field: &str) -> ColorTable {
+        let mut values: Vec<String> = value_counts(state, field).into_keys().collect();
+        values.sort_unstable();
+        let index_of = values.iter().enumerate().map(|(idx, value)| (value.clone(), idx as u32)).collect();
+        ColorTable { values, index_of }
+    }
+
+    pub fn index(&self, node: &Node, field: &str) -> Option<u32> {
+        node.meta.get(field).and_then(|value| self.index_of.get(meta_value_string(value).as_ref()).copied())
+    }
+}
+
+// Color tables built on first use, one per metadata field
+#[derive(Default)]
+pub struct ColorTables {
+    tables: Mutex<HashMap<String, Arc<ColorTable>>>,
+}
+
+// Table for a meta field; callers check the field exists first. There is at most one
+// table per field, so nothing is ever evicted.
+pub fn color_table(state: &AppState, field: &str) -> Arc<ColorTable> {
+    if let Some(cached) = state.color_tables.tables.lock().unwrap().get(field) {
+        return cached.clone();
+    }
+
+    let table = Arc::new(ColorTable::build(state, field));
+    state.color_tables.tables.lock().unwrap().insert(field.to_string(), table.clone());
+    table
+}
+
+// A stable colour for a value: the hue comes from a hash of the value (FNV-1a), at
+// fixed saturation and lightness so all colours are similarly readable.
+pub fn value_color(value: &str) -> String {
+    let hash = value.bytes().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    let hue = (hash % 360) as f64;
+    let (saturation, lightness) = (0.65, 0.5);
+
+    let chroma = (1.0 - (2.0 * lightness - 1.0f64).abs()) * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let (r, g, b) = match (hue / 60.0) as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let channel = |c: f64| ((c + m) * 255.0).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", channel(r), channel(g), channel(b))
+}
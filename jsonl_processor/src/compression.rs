@@ -0,0 +1,240 @@
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::web::{self, Bytes};
+use flate2::write::GzEncoder;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+
+// Bodies smaller than this are sent as they are; compressing them saves next to nothing
+const MIN_COMPRESS_SIZE: u64 = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    Gzip,
+    Brotli,
+}
+
+impl Codec {
+    // Comma-separated codec names in order of preference; "none" disables compression
+    pub fn parse_list(list: &str) -> Result<Vec<Codec>, String> {
+        if list == "none" {
+            return Ok(Vec::new());
+        }
+        list.split(',')
+            .map(|name| match name.trim() {
+                "gzip" => Ok(Codec::Gzip),
+                "br" => Ok(Codec::Brotli),
+                other => Err(format!("Unknown compression codec: {} (expected gzip, br or none)", other)),
+            })
+            .collect()
+    }
+
+    // Name used in Accept-Encoding and Content-Encoding
+    fn name(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Brotli => "br",
+        }
+    }
+
+    fn max_level(self) -> u32 {
+        match self {
+            Codec::Gzip => 9,
+            Codec::Brotli => 11,
+        }
+    }
+
+    // Fast enough to keep up with streamed /nodes/ responses
+    fn default_level(self) -> u32 {
+        match self {
+            Codec::Gzip => 6,
+            Codec::Brotli => 4,
+        }
+    }
+}
+
+// Which codecs responses may be compressed with, and how hard
+pub struct Compression {
+    codecs: Vec<Codec>,
+    // None uses each codec's default; otherwise clamped to what the codec supports
+    level: Option<u32>,
+}
+
+impl Compression {
+    pub fn new(codecs: Vec<Codec>, level: Option<u32>) -> Compression {
+        Compression { codecs, level }
+    }
+
+    fn level(&self, codec: Codec) -> u32 {
+        self.level.map_or(codec.default_level(), |level| level.min(codec.max_level()))
+    }
+
+    // The codec to use for a request's Accept-Encoding: the one the client weights highest,
+    // with ties going to the order codecs were configured in.
+    pub fn negotiate(&self, headers: &HeaderMap) -> Option<Codec> {
+        let mut best: Option<(Codec, f32)> = None;
+        for value in headers.get_all(header::ACCEPT_ENCODING).filter_map(|value| value.to_str().ok()) {
+            for entry in value.split(',') {
+                let mut parts = entry.split(';');
+                let name = parts.next().unwrap_or("").trim();
+                let quality = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                if quality <= 0.0 {
+                    continue;
+                }
+                for &codec in &self.codecs {
+                    if (name == codec.name() || name == "*") && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                        best = Some((codec, quality));
+                    }
+                }
+            }
+        }
+        best.map(|(codec, _)| codec)
+    }
+
+    // Encodes a whole body at once, for responses that are compressed ahead of time
+    pub fn encode(&self, codec: Codec, body: &[u8]) -> io::Result<Bytes> {
+        let mut encoder = Encoder::new(codec, self.level(codec));
+        let mut out = encoder.write(body)?.to_vec();
+        out.extend_from_slice(&encoder.finish()?);
+        Ok(Bytes::from(out))
+    }
+
+    // Every allowed codec with the body encoded in it, computed now and served as is later
+    pub fn precompress(&self, body: Bytes) -> io::Result<Precompressed> {
+        let encoded = self.codecs.iter()
+            .map(|&codec| Ok((codec, self.encode(codec, &body)?)))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Precompressed { identity: body, encoded })
+    }
+}
+
+// A fixed body along with its encoded forms
+pub struct Precompressed {
+    identity: Bytes,
+    encoded: Vec<(Codec, Bytes)>,
+}
+
+impl Precompressed {
+    // The form to send for a request, with its Content-Encoding if any
+    pub fn select(&self, compression: &Compression, headers: &HeaderMap) -> (Option<&'static str>, Bytes) {
+        compression.negotiate(headers)
+            .and_then(|codec| self.encoded.iter().find(|(encoded_codec, _)| *encoded_codec == codec))
+            .map_or((None, self.identity.clone()), |(codec, body)| (Some(codec.name()), body.clone()))
+    }
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    fn new(codec: Codec, level: u32) -> Encoder {
+        match codec {
+            Codec::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::new(level))),
+            Codec::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(Vec::new(), 4096, level, 22))),
+        }
+    }
+
+    // Feeds in a chunk and takes whatever compressed output is ready so far
+    fn write(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        let out = match self {
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+            Encoder::Brotli(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+        };
+        Ok(Bytes::from(std::mem::take(out)))
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        let out = match self {
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Brotli(encoder) => encoder.into_inner(),
+        };
+        Ok(Bytes::from(out))
+    }
+}
+
+// A response body compressed as it streams, so a large body is never held in memory whole
+struct EncodedBody {
+    inner: BoxBody,
+    encoder: Option<Encoder>,
+}
+
+impl MessageBody for EncodedBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            let Some(encoder) = this.encoder.as_mut() else {
+                return Poll::Ready(None);
+            };
+            match Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(Some(Ok(chunk))) => match encoder.write(&chunk) {
+                    // The encoder buffers internally; keep feeding it until it has output
+                    Ok(out) if out.is_empty() => continue,
+                    Ok(out) => return Poll::Ready(Some(Ok(out))),
+                    Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                },
+                Poll::Ready(None) => {
+                    let encoder = this.encoder.take().expect("encoder present while streaming");
+                    return Poll::Ready(Some(encoder.finish().map_err(Into::into)));
+                }
+            }
+        }
+    }
+}
+
+// Middleware compressing responses in the best codec the client accepts. Responses that
+// already carry a Content-Encoding (like the precompressed /config/ body) pass through.
+pub async fn compress(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let codec = req.app_data::<web::Data<Compression>>().and_then(|compression| {
+        compression.negotiate(req.headers()).map(|codec| (codec, compression.level(codec)))
+    });
+    let is_head = req.method() == actix_web::http::Method::HEAD;
+    let mut res = next.call(req).await?.map_into_boxed_body();
+
+    let compressible = match res.response().body().size() {
+        BodySize::None => false,
+        BodySize::Sized(size) => size >= MIN_COMPRESS_SIZE,
+        BodySize::Stream => true,
+    };
+    if !compressible || is_head {
+        return Ok(res);
+    }
+    let varies = res.headers().get_all(header::VARY)
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.split(',').any(|name| name.trim().eq_ignore_ascii_case("accept-encoding")));
+    if !varies {
+        res.headers_mut().append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    let Some((codec, level)) = codec.filter(|_| !res.headers().contains_key(header::CONTENT_ENCODING)) else {
+        return Ok(res);
+    };
+
+    res.headers_mut().insert(header::CONTENT_ENCODING, HeaderValue::from_static(codec.name()));
+    res.headers_mut().remove(header::CONTENT_LENGTH);
+    Ok(res.map_body(|_, body| BoxBody::new(EncodedBody { inner: body, encoder: Some(Encoder::new(codec, level)) })))
+}
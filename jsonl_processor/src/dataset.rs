@@ -0,0 +1,260 @@
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+use futures_util::future::{ready, Ready};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info};
+
+use crate::args::Args;
+use crate::error::ApiError;
+use crate::load_error::LoadError;
+use crate::offload::HeavyWork;
+use crate::{auth, logging, AppState};
+
+// First path segments of the routes served for a dataset, which a dataset name would
+// shadow if served alongside the one given as an argument
+const RESERVED_NAMES: &[&str] = &[
+    "acknowledgements", "admin", "autocomplete", "colors", "config", "datasets", "export", "health", "minimap", "mrca",
+    "mutations", "nearest", "newick", "nextstrain_json", "node", "node_details", "node_mutations", "nodes",
+    "path", "ready", "search", "sequence", "status", "tip_atts", "values", "viewport_counts",
+];
+
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("Invalid dataset name: {:?} (use letters, digits, - and _)", name));
+    }
+    if RESERVED_NAMES.contains(&name) {
+        return Err(format!("Invalid dataset name: {} is already a route", name));
+    }
+    Ok(())
+}
+
+// A dataset being served. Its routes are up before it has loaded, answering 503 until
+// then. /admin/reload/ builds a new AppState and swaps it in here, while requests
+// already running carry on with the snapshot they started with.
+pub struct Dataset {
+    // None until the first load succeeds
+    current: RwLock<Option<web::Data<AppState>>>,
+    // Why the first load failed, if it did and nothing has loaded since
+    load_error: Mutex<Option<String>>,
+    // Set for the duration of a load or reload, so only one runs at a time
+    reloading: AtomicBool,
+    // Nodes read so far by the load or reload running, or the last one
+    nodes_processed: AtomicUsize,
+    last_reload: Mutex<Option<LastReload>>,
+    // None for the dataset served at the root
+    pub name: Option<String>,
+    pub path: String,
+    pub args: Arc<Args>,
+    heavy_work: HeavyWork,
+}
+
+// When the most recent reload finished and, if it failed, why
+struct LastReload {
+    at: SystemTime,
+    error: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LoadState {
+    Loading,
+    Ready,
+    Failed,
+}
+
+pub struct ReloadReport {
+    pub old_nodes: usize,
+    pub new_nodes: usize,
+    pub elapsed: Duration,
+}
+
+impl Dataset {
+    pub fn new(name: Option<String>, path: String, args: Arc<Args>, heavy_work: HeavyWork) -> Dataset {
+        Dataset {
+            current: RwLock::new(None),
+            load_error: Mutex::new(None),
+            reloading: AtomicBool::new(false),
+            nodes_processed: AtomicUsize::new(0),
+            last_reload: Mutex::new(None),
+            name,
+            path,
+            args,
+            heavy_work,
+        }
+    }
+
+    // Where its routes are mounted, e.g. "/" or "/flu/"
+    pub fn prefix(&self) -> String {
+        self.name.as_ref().map_or_else(|| "/".to_string(), |name| format!("/{}/", name))
+    }
+
+    // None while the dataset is loading, or if it failed to
+    pub fn current(&self) -> Option<web::Data<AppState>> {
+        self.current.read().unwrap().clone()
+    }
+
+    // Installs `state` for new requests and returns the snapshot it replaces
+    pub fn replace(&self, state: AppState) -> Option<web::Data<AppState>> {
+        self.current.write().unwrap().replace(web::Data::new(state))
+    }
+
+    pub fn state(&self) -> LoadState {
+        match (self.current.read().unwrap().is_some(), self.load_error.lock().unwrap().is_some()) {
+            (true, _) => LoadState::Ready,
+            (false, true) => LoadState::Failed,
+            (false, false) => LoadState::Loading,
+        }
+    }
+
+    // The first load, run in the background while the server is already up. The
+    // caller holds try_begin_reload(), so /admin/reload/ waits for it.
+    pub fn load(&self) -> Result<(), LoadError> {
+        // A panic fails the load rather than leaving the dataset loading for good
+        let state = panic::catch_unwind(AssertUnwindSafe(|| crate::build_state(&self.args, &self.path, self.heavy_work.clone(), None, &self.nodes_processed)))
+            .unwrap_or(Err(LoadError::Panicked));
+        match state {
+            Ok(state) => {
+                self.replace(state);
+                Ok(())
+            }
+            Err(e) => {
+                let message = self.failure(&e);
+                error!("{}", message);
+                *self.load_error.lock().unwrap() = Some(message);
+                Err(e)
+            }
+        }
+    }
+
+    fn failure(&self, e: &LoadError) -> String {
+        format!("Failed to load {}: {}", crate::describe_path(&self.path), e)
+    }
+
+    pub fn try_begin_reload(&self) -> Option<Reloading<'_>> {
+        let started = self.reloading.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok();
+        started.then_some(Reloading(&self.reloading))
+    }
+
+    // Rebuilds the dataset from the data file and swaps it in, blocking until done. The
+    // caller holds try_begin_reload(). On failure the current dataset stays in place.
+    pub fn reload(&self) -> Result<ReloadReport, String> {
+        let start = Instant::now();
+        let previous = self.current();
+        let result = crate::build_state(&self.args, &self.path, self.heavy_work.clone(), previous.as_deref().map(Arc::as_ref), &self.nodes_processed)
+            .map_err(|e| self.failure(&e));
+        *self.last_reload.lock().unwrap() = Some(LastReload { at: SystemTime::now(), error: result.as_ref().err().cloned() });
+        let state = result.inspect_err(|e| error!("Reload failed, still serving the previous dataset: {}", e))?;
+
+        let old_nodes = previous.map_or(0, |previous| previous.nodes.len());
+        let report = ReloadReport { old_nodes, new_nodes: state.nodes.len(), elapsed: start.elapsed() };
+        self.replace(state);
+        *self.load_error.lock().unwrap() = None;
+        info!(old_nodes = report.old_nodes, new_nodes = report.new_nodes, "Reloaded {} in {:?}", self.path, report.elapsed);
+        Ok(report)
+    }
+
+    // The /status/ body
+    pub fn status(&self) -> Value {
+        let current = self.current();
+        let last_reload = self.last_reload.lock().unwrap();
+        json!({
+            "name": self.name,
+            "path": self.path,
+            "state": self.state(),
+            "nodes": current.as_ref().map(|current| current.nodes.len()),
+            "loaded_at": current.as_ref().map(|current| logging::timestamp(current.loaded_at)),
+            "nodes_processed": self.nodes_processed.load(Ordering::Relaxed),
+            "load_error": self.load_error.lock().unwrap().clone(),
+            "watching": self.args.watch,
+            "reloading": self.reloading.load(Ordering::Relaxed),
+            "last_reload_at": last_reload.as_ref().map(|reload| logging::timestamp(reload.at)),
+            "last_reload_error": last_reload.as_ref().and_then(|reload| reload.error.clone()),
+        })
+    }
+
+    // Admin endpoints need `Authorization: Bearer <token>` matching --admin-token, and
+    // are disabled when no token was configured
+    pub fn check_admin_token(&self, req: &HttpRequest) -> Result<(), ApiError> {
+        let Some(expected) = &self.args.admin_token else {
+            return Err(ApiError::not_found("Admin endpoints are disabled; start the server with --admin-token to enable them"));
+        };
+        match auth::bearer_token(req.headers()) {
+            Some(given) if auth::constant_time_eq(given.as_bytes(), expected.as_bytes()) => Ok(()),
+            _ => Err(ApiError::unauthorized("Missing or invalid admin token")),
+        }
+    }
+}
+
+// Every dataset given on the command line, whether loaded, loading or failed, for
+// /datasets/ and /ready
+pub struct Catalog {
+    pub datasets: Vec<web::Data<Dataset>>,
+    // When the server started, for its uptime
+    pub started: Instant,
+}
+
+impl Catalog {
+    // Ready once nothing is still loading and at least one dataset loaded
+    pub fn ready(&self) -> bool {
+        let states: Vec<LoadState> = self.datasets.iter().map(|dataset| dataset.state()).collect();
+        !states.contains(&LoadState::Loading) && states.contains(&LoadState::Ready)
+    }
+
+    pub fn listing(&self) -> Value {
+        let datasets: Vec<Value> = self.datasets.iter().map(|dataset| {
+            let mut entry = json!({
+                "name": dataset.name,
+                "path": dataset.path,
+                "prefix": dataset.prefix(),
+                "state": dataset.state(),
+            });
+            match dataset.current() {
+                Some(current) => entry["nodes"] = json!(current.nodes.len()),
+                None => entry["nodes_processed"] = json!(dataset.nodes_processed.load(Ordering::Relaxed)),
+            }
+            if let Some(error) = dataset.load_error.lock().unwrap().clone() {
+                entry["error"] = json!(error);
+            }
+            entry
+        }).collect();
+        json!({ "datasets": datasets })
+    }
+}
+
+// Marks a reload as running until dropped
+pub struct Reloading<'a>(&'a AtomicBool);
+
+impl Drop for Reloading<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+// Extractor for the snapshot current when the request arrived. Handlers take it as
+// `Snapshot(data): Snapshot` and use `data` like any other web::Data.
+pub struct Snapshot(pub web::Data<AppState>);
+
+impl FromRequest for Snapshot {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Snapshot, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let Some(dataset) = req.app_data::<web::Data<Dataset>>() else {
+            return ready(Err(ApiError::internal("Internal error", "No dataset registered").into()));
+        };
+        ready(match dataset.current() {
+            Some(data) => Ok(Snapshot(data)),
+            None if dataset.state() == LoadState::Failed => Err(ApiError::unavailable("Dataset failed to load").into()),
+            None => {
+                let progress = json!({ "status": "loading", "nodes_processed": dataset.nodes_processed.load(Ordering::Relaxed) });
+                Err(InternalError::from_response("Dataset is still loading", HttpResponse::ServiceUnavailable().json(progress)).into())
+            }
+        })
+    }
+}
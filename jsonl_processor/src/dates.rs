@@ -0,0 +1,109 @@
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use tracing::{info, warn};
+
+use crate::{search, Node};
+
+// Unparseable dates quoted when reporting them
+const UNPARSEABLE_EXAMPLES: usize = 5;
+
+// Sets x_time from the dates in the metadata field `key`, in days since the earliest,
+// for trees with no time layout of their own. Each node takes the earliest date at or
+// beneath it, so times never decrease from the root to the tips, and nodes with no date
+// at or beneath them take their parent's time, or for a whole tree of a forest without
+// dates, the earliest. Returns the earliest date, or None if no node has one.
+pub fn assign_times(nodes: &mut [Node], children: &HashMap<i32, Vec<usize>>, roots: &[usize], key: &str) -> Option<String> {
+    let field = search::meta_field_name(key);
+    // Parents come before their children
+    let mut order = Vec::with_capacity(nodes.len());
+    let mut stack = roots.to_vec();
+    while let Some(idx) = stack.pop() {
+        order.push(idx);
+        stack.extend(children.get(&nodes[idx].node_id).into_iter().flatten());
+    }
+
+    let mut unparseable = 0;
+    let mut examples = BTreeSet::new();
+    let mut days: Vec<Option<f64>> = nodes.iter()
+        .map(|node| match node.meta.get(field.as_ref()) {
+            None | Some(Value::Null) => None,
+            Some(value) => {
+                let value = search::meta_value_string(value);
+                let day = parse_day(&value);
+                if day.is_none() && !value.is_empty() {
+                    unparseable += 1;
+                    if examples.len() < UNPARSEABLE_EXAMPLES {
+                        examples.insert(value.into_owned());
+                    }
+                }
+                day
+            }
+        })
+        .collect();
+    let node_children = |idx: usize| children.get(&nodes[idx].node_id).into_iter().flatten().copied();
+    for &idx in order.iter().rev() {
+        for child in node_children(idx) {
+            days[idx] = match (days[idx], days[child]) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+    }
+    let Some(origin) = roots.iter().filter_map(|&root| days[root]).reduce(f64::min) else {
+        warn!("No node has a date in {}, so there is no time layout", field);
+        return None;
+    };
+    for &root in roots {
+        days[root].get_or_insert(origin);
+    }
+    let mut inherited = 0;
+    for &idx in &order {
+        for child in node_children(idx) {
+            if days[child].is_none() {
+                days[child] = days[idx];
+                inherited += usize::from(node_children(child).next().is_none());
+            }
+        }
+    }
+
+    for (node, day) in nodes.iter_mut().zip(&days) {
+        node.x_time = day.map(|day| day - origin);
+    }
+    if inherited > 0 {
+        let examples = match unparseable {
+            0 => String::new(),
+            _ => format!(", {} unparseable, e.g. {}", unparseable, examples.into_iter().collect::<Vec<_>>().join(", ")),
+        };
+        warn!("{} tips have no date in {}{}; they take their parent's time", inherited, field, examples);
+    }
+    let origin = date_from_days(origin.floor() as i64);
+    info!("Computed x_time from {}, in days since {}", field, origin);
+    Some(origin)
+}
+
+// Days since 1970-01-01 of an ISO date, a year-month, a year or a decimal year
+fn parse_day(s: &str) -> Option<f64> {
+    if let Some(day) = search::parse_date(s) {
+        return Some(day);
+    }
+    let (whole, _) = s.trim().split_once('.')?;
+    let year: f64 = s.trim().parse().ok()?;
+    let whole: i64 = whole.parse().ok().filter(|_| whole.len() == 4)?;
+    let start = search::days_from_civil(whole, 1, 1) as f64;
+    let end = search::days_from_civil(whole + 1, 1, 1) as f64;
+    Some(start + (year - whole as f64) * (end - start))
+}
+
+// The ISO date of days since 1970-01-01; Howard Hinnant's civil_from_days
+fn date_from_days(days: i64) -> String {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
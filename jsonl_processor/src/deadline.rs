@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::error::ApiError;
+
+// How many items a long loop handles between deadline checks
+const CHECK_INTERVAL: usize = 4096;
+
+// When a request's computation should give up: once --request-timeout has passed, or
+// once its client has gone away. The heavy pipelines call check() between phases, and
+// wrap their long loops in watch().
+#[derive(Clone)]
+pub struct Deadline {
+    limit: Option<Duration>,
+    started: Instant,
+    cancelled: Arc<AtomicBool>,
+}
+
+// Held by the handler while the work runs, cancelling it if actix drops the handler
+// first: when an HTTP/2 client resets its stream, or shutdown stops waiting. Over HTTP/1
+// actix only notices a disconnect once it writes the response, which stops a streamed
+// body being produced any further.
+pub struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Deadline {
+    pub fn new(limit: Option<Duration>) -> (Deadline, CancelOnDrop) {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        (Deadline { limit, started: Instant::now(), cancelled: cancelled.clone() }, CancelOnDrop(cancelled))
+    }
+
+    // For work nobody waits on, like background searches
+    pub fn none() -> Deadline {
+        Deadline { limit: None, started: Instant::now(), cancelled: Arc::new(AtomicBool::new(false)) }
+    }
+
+    fn expired(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.limit.is_some_and(|limit| self.started.elapsed() > limit)
+    }
+
+    pub fn check(&self) -> Result<(), ApiError> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(ApiError::unavailable("Cancelled because the client disconnected"));
+        }
+        match self.limit {
+            Some(limit) if self.started.elapsed() > limit => {
+                Err(ApiError::unavailable(format!("Request took longer than the {:?} limit", limit)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Passes `iter` through, stopping early once the deadline passes; the check() that
+    // follows the loop then reports it
+    pub fn watch<'a, I: Iterator + 'a>(&'a self, iter: I) -> impl Iterator<Item = I::Item> + 'a {
+        iter.enumerate()
+            .take_while(move |(i, _)| i % CHECK_INTERVAL != 0 || !self.expired())
+            .map(|(_, item)| item)
+    }
+}
@@ -0,0 +1,141 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{BlockingError, QueryPayloadError};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use futures_util::FutureExt;
+use serde_json::json;
+use std::fmt;
+use std::panic::AssertUnwindSafe;
+
+// An error rendered as {"error": ..., "detail": ..., "parameter": ...}. `error` says what
+// went wrong with the request; `detail`, when there is one, carries the underlying cause,
+// and `parameter` names the query parameter at fault.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    error: String,
+    detail: Option<String>,
+    parameter: Option<String>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, error: impl fmt::Display) -> ApiError {
+        ApiError { status, error: error.to_string(), detail: None, parameter: None }
+    }
+
+    pub fn bad_request(error: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, error)
+    }
+
+    pub fn invalid_parameter(parameter: &str, error: impl fmt::Display) -> ApiError {
+        ApiError { parameter: Some(parameter.to_string()), ..ApiError::bad_request(error) }
+    }
+
+    pub fn unauthorized(error: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::UNAUTHORIZED, error)
+    }
+
+    pub fn not_found(error: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::NOT_FOUND, error)
+    }
+
+    pub fn payload_too_large(error: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, error)
+    }
+
+    pub fn too_many_requests(error: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::TOO_MANY_REQUESTS, error)
+    }
+
+    pub fn conflict(error: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::CONFLICT, error)
+    }
+
+    pub fn unavailable(error: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::SERVICE_UNAVAILABLE, error)
+    }
+
+    pub fn internal(error: impl fmt::Display, detail: impl fmt::Display) -> ApiError {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, error).with_detail(detail)
+    }
+
+    pub fn with_detail(mut self, detail: impl fmt::Display) -> ApiError {
+        self.detail = Some(detail.to_string());
+        self
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.detail {
+            Some(detail) => write!(f, "{}: {}", self.error, detail),
+            None => f.write_str(&self.error),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut body = json!({ "error": self.error });
+        if let Some(detail) = &self.detail {
+            body["detail"] = json!(detail);
+        }
+        if let Some(parameter) = &self.parameter {
+            body["parameter"] = json!(parameter);
+        }
+        HttpResponse::build(self.status).json(body)
+    }
+}
+
+// For a query string that failed to deserialize: the error, naming the parameter when
+// one has a value of the kind the error complains about
+pub fn query_error(e: QueryPayloadError, req: &HttpRequest) -> actix_web::Error {
+    let detail = e.to_string();
+    let expects: Option<fn(&str) -> bool> = if detail.contains("float") {
+        Some(|value| value.parse::<f64>().is_ok())
+    } else if detail.contains("digit") || detail.contains("number too") {
+        Some(|value| value.parse::<i64>().is_ok() || value.parse::<u64>().is_ok())
+    } else if detail.contains("`true` or `false`") {
+        Some(|value| value.parse::<bool>().is_ok())
+    } else {
+        None
+    };
+    let pairs = web::Query::<Vec<(String, String)>>::from_query(req.query_string()).map(web::Query::into_inner).unwrap_or_default();
+    let parameter = expects.and_then(|parses| pairs.into_iter().find(|(_, value)| !parses(value)).map(|(key, _)| key));
+    let error = match parameter {
+        Some(parameter) => ApiError::invalid_parameter(&parameter, format!("Invalid value for {}", parameter)),
+        None => ApiError::bad_request("Invalid query parameters"),
+    };
+    error.with_detail(detail).into()
+}
+
+// Work handed to web::block only fails this way if it panicked
+impl From<BlockingError> for ApiError {
+    fn from(e: BlockingError) -> ApiError {
+        ApiError::internal("Failed to compute the response", e)
+    }
+}
+
+// Middleware turning a panic in a handler into a 500 with a message, rather than a
+// connection that closes without a response
+pub async fn catch_panics(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    match AssertUnwindSafe(next.call(req)).catch_unwind().await {
+        Ok(res) => res.map(ServiceResponse::map_into_boxed_body),
+        Err(panic) => {
+            let message = panic.downcast_ref::<&str>().map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown cause".to_string());
+            tracing::error!("Handler panicked: {}", message);
+            Err(ApiError::internal("Internal error", message).into())
+        }
+    }
+}
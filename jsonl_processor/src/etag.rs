@@ -0,0 +1,40 @@
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::HttpResponse;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 64-bit FNV-1a; tags only need to change when the content does, not resist tampering
+fn hash(parts: &[&[u8]]) -> u64 {
+    parts.iter()
+        .flat_map(|part| part.iter().chain(b"\0"))
+        .fold(0xcbf29ce484222325u64, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+// Tag for a body that is fixed once the server has started. Tags are weak because the
+// same tag covers the compressed and uncompressed forms of a body.
+pub fn content_tag(body: &[u8]) -> String {
+    format!("W/\"{:016x}\"", hash(&[body]))
+}
+
+// Tag for a computed response: the same data and the same normalized query (the
+// parameters in sorted order, plus the headers that affect the body) give the same tag.
+pub fn query_tag(loaded_at: SystemTime, query_string: &str, headers: &HeaderMap) -> String {
+    let mut params: Vec<&str> = query_string.split('&').filter(|param| !param.is_empty()).collect();
+    params.sort_unstable();
+    let loaded_at = loaded_at.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64);
+    let accept = headers.get(header::ACCEPT).map_or(&b""[..], |accept| accept.as_bytes());
+    format!("W/\"{:x}-{:016x}\"", loaded_at, hash(&[params.join("&").as_bytes(), accept]))
+}
+
+// True if the request's If-None-Match lists `tag`, compared weakly
+pub fn not_modified(headers: &HeaderMap, tag: &str) -> bool {
+    let tag = tag.trim_start_matches("W/");
+    headers.get_all(header::IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == tag)
+}
+
+pub fn not_modified_response(tag: &str) -> HttpResponse {
+    HttpResponse::NotModified().insert_header((header::ETAG, tag)).finish()
+}
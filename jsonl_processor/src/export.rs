@@ -0,0 +1,331 @@
+use actix_web::web::{self, Bytes};
+use serde_json::{json, Map, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::search::meta_value_string;
+use crate::{AppState, Mutation};
+
+// Approximate size of each chunk of a streamed export
+const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+// Auspice v2 JSON for the subtree rooted at the node at `root_idx`. The tree is built
+// bottom-up over the subtree's pre-order slice so deep trees don't recurse.
+pub fn nextstrain_json(state: &AppState, root_idx: usize) -> Value {
+    let (enter, exit) = state.dfs_intervals[root_idx];
+    let root_x = state.nodes[root_idx].x_dist;
+
+    let mut built: HashMap<i32, Value> = HashMap::new();
+    for &idx in state.dfs_order[enter..=exit].iter().rev() {
+        let node = &state.nodes[idx];
+
+        let mut node_attrs = Map::new();
+        node_attrs.insert("div".to_string(), json!(node.x_dist - root_x));
+        for (key, value) in &node.meta {
+            let value = meta_value_string(value);
+            if value.is_empty() {
+                continue;
+            }
+            let key = key.strip_prefix("meta_").unwrap_or(key);
+            node_attrs.insert(key.to_string(), json!({ "value": value }));
+        }
+        for (key, value) in &node.clades {
+            node_attrs.insert(key.clone(), json!({ "value": value }));
+        }
+
+        let mut mutations: Map<String, Value> = Map::new();
+        for mutation in node.mutations.iter().filter_map(|&id| state.mutation(id)) {
+            let gene = match mutation {
+                Mutation::AA { gene, .. } => gene.as_str(),
+                Mutation::NT { .. } => "nuc",
+            };
+            let label = format!("{}{}{}", mutation.previous_residue(), mutation.residue_pos(), mutation.new_residue());
+            if let Value::Array(labels) = mutations.entry(gene).or_insert_with(|| json!([])) {
+                labels.push(json!(label));
+            }
+        }
+
+        let mut tree_node = json!({
+            "name": auspice_name(&node.name, node.node_id),
+            "node_attrs": node_attrs,
+            "branch_attrs": { "mutations": mutations }
+        });
+        let children: Vec<Value> = state.children.get(&node.node_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|&child| built.remove(&state.nodes[child].node_id))
+            .collect();
+        if !children.is_empty() {
+            tree_node["children"] = json!(children);
+        }
+        built.insert(node.node_id, tree_node);
+    }
+
+    json!({
+        "version": "v2",
+        "meta": nextstrain_meta(state),
+        "tree": built.remove(&state.nodes[root_idx].node_id).unwrap_or(Value::Null)
+    })
+}
+
+// Auspice requires every node to have a unique name; internal nodes are often unnamed
+fn auspice_name(name: &str, node_id: i32) -> String {
+    if name.is_empty() {
+        format!("NODE_{}", node_id)
+    } else {
+        name.to_string()
+    }
+}
+
+fn nextstrain_meta(state: &AppState) -> Value {
+    let genome_end = state.config.gene_details.values().map(|g| g.end).max().unwrap_or(0);
+    let mut annotations = Map::new();
+    annotations.insert("nuc".to_string(), json!({ "start": 1, "end": genome_end, "strand": "+" }));
+    for (gene, detail) in &state.config.gene_details {
+        let strand = if detail.strand < 0 { "-" } else { "+" };
+        annotations.insert(gene.clone(), json!({ "start": detail.start, "end": detail.end, "strand": strand }));
+    }
+
+    let colorings: Vec<Value> = state.metadata_keys.iter()
+        .map(|key| key.strip_prefix("meta_").unwrap_or(key))
+        .map(|key| json!({ "key": key, "title": key, "type": "categorical" }))
+        .collect();
+
+    json!({
+        "title": "Taxonium export",
+        "panels": ["tree"],
+        "genome_annotations": annotations,
+        "colorings": colorings
+    })
+}
+
+enum NewickStep {
+    Open(usize),
+    Comma,
+    Close(usize),
+}
+
+// Writes the subtree beneath a node as Newick, a chunk at a time, so large exports
+// can be streamed without holding the whole string in memory.
+pub struct NewickChunks {
+    state: web::Data<AppState>,
+    root_idx: usize,
+    include_internal_names: bool,
+    stack: Vec<NewickStep>,
+    done: bool,
+}
+
+impl NewickChunks {
+    pub fn new(state: web::Data<AppState>, root_idx: usize, include_internal_names: bool) -> NewickChunks {
+        NewickChunks {
+            state,
+            root_idx,
+            include_internal_names,
+            stack: vec![NewickStep::Open(root_idx)],
+            done: false,
+        }
+    }
+
+    // Name (if any) and branch length written after a node or its closing parenthesis
+    fn write_label(&self, out: &mut String, idx: usize, is_tip: bool) {
+        let node = &self.state.nodes[idx];
+        if is_tip || self.include_internal_names {
+            out.push_str(&newick_name(&node.name));
+        }
+        if idx == self.root_idx {
+            return;
+        }
+        let parent_x = self.state.child_to_parent.get(&node.node_id)
+            .and_then(|&parent_id| self.state.node(parent_id))
+            .map_or(node.x_dist, |parent| parent.x_dist);
+        let _ = write!(out, ":{}", node.x_dist - parent_x);
+    }
+}
+
+impl Iterator for NewickChunks {
+    type Item = Result<Bytes, actix_web::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut out = String::new();
+        while out.len() < EXPORT_CHUNK_SIZE {
+            match self.stack.pop() {
+                Some(NewickStep::Open(idx)) => {
+                    let children = self.state.children.get(&self.state.nodes[idx].node_id).map_or(&[][..], |c| c.as_slice());
+                    if children.is_empty() {
+                        self.write_label(&mut out, idx, true);
+                        continue;
+                    }
+                    out.push('(');
+                    self.stack.push(NewickStep::Close(idx));
+                    for (i, &child) in children.iter().enumerate().rev() {
+                        self.stack.push(NewickStep::Open(child));
+                        if i > 0 {
+                            self.stack.push(NewickStep::Comma);
+                        }
+                    }
+                }
+                Some(NewickStep::Comma) => out.push(','),
+                Some(NewickStep::Close(idx)) => {
+                    out.push(')');
+                    self.write_label(&mut out, idx, false);
+                }
+                None => {
+                    out.push_str(";\n");
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+        Some(Ok(Bytes::from(out)))
+    }
+}
+
+// Quotes names containing characters that are special in Newick
+fn newick_name(name: &str) -> Cow<'_, str> {
+    if name.chars().any(|c| c.is_whitespace() || "()[]':;,".contains(c)) {
+        Cow::Owned(format!("'{}'", name.replace('\'', "''")))
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+// Writes name, node_id and every metadata field for the given nodes as TSV rows,
+// a chunk at a time.
+pub struct TsvChunks {
+    state: web::Data<AppState>,
+    indexes: Vec<usize>,
+    position: usize,
+    header_written: bool,
+}
+
+impl TsvChunks {
+    pub fn new(state: web::Data<AppState>, indexes: Vec<usize>) -> TsvChunks {
+        TsvChunks { state, indexes, position: 0, header_written: false }
+    }
+}
+
+impl Iterator for TsvChunks {
+    type Item = Result<Bytes, actix_web::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut out = String::new();
+        if !self.header_written {
+            out.push_str("name\tnode_id");
+            for key in &self.state.metadata_keys {
+                out.push('\t');
+                out.push_str(&tsv_escape(key.strip_prefix("meta_").unwrap_or(key)));
+            }
+            out.push('\n');
+            self.header_written = true;
+        }
+
+        while out.len() < EXPORT_CHUNK_SIZE && self.position < self.indexes.len() {
+            let node = &self.state.nodes[self.indexes[self.position]];
+            self.position += 1;
+            let _ = write!(out, "{}\t{}", tsv_escape(&node.name), node.node_id);
+            for key in &self.state.metadata_keys {
+                out.push('\t');
+                if let Some(value) = node.meta.get(key) {
+                    out.push_str(&tsv_escape(&meta_value_string(value)));
+                }
+            }
+            out.push('\n');
+        }
+
+        if out.is_empty() {
+            None
+        } else {
+            Some(Ok(Bytes::from(out)))
+        }
+    }
+}
+
+// Backslash-escapes characters that would break the TSV structure
+fn tsv_escape(value: &str) -> Cow<'_, str> {
+    if !value.contains(['\t', '\n', '\r', '\\']) {
+        return Cow::Borrowed(value);
+    }
+    let mut escaped = String::with_capacity(value.len() + 2);
+    for c in value.chars() {
+        match c {
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\\' => escaped.push_str("\\\\"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+// Writes the given nodes as CSV, one row per node, a chunk at a time
+pub struct CsvChunks {
+    state: web::Data<AppState>,
+    indexes: Vec<usize>,
+    position: usize,
+    header_written: bool,
+}
+
+impl CsvChunks {
+    pub fn new(state: web::Data<AppState>, indexes: Vec<usize>) -> CsvChunks {
+        CsvChunks { state, indexes, position: 0, header_written: false }
+    }
+}
+
+impl Iterator for CsvChunks {
+    type Item = Result<Bytes, actix_web::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut out = String::new();
+        if !self.header_written {
+            out.push_str("node_id,parent_id,name,x_dist,y,num_tips");
+            for key in &self.state.metadata_keys {
+                out.push(',');
+                out.push_str(&csv_escape(key.strip_prefix("meta_").unwrap_or(key)));
+            }
+            out.push('\n');
+            self.header_written = true;
+        }
+
+        while out.len() < EXPORT_CHUNK_SIZE && self.position < self.indexes.len() {
+            let node = &self.state.nodes[self.indexes[self.position]];
+            self.position += 1;
+            let _ = write!(
+                out,
+                "{},{},{},{},{},{}",
+                node.node_id,
+                node.parent_id,
+                csv_escape(&node.name),
+                node.x_dist,
+                node.y,
+                node.num_tips
+            );
+            for key in &self.state.metadata_keys {
+                out.push(',');
+                if let Some(value) = node.meta.get(key) {
+                    out.push_str(&csv_escape(&meta_value_string(value)));
+                }
+            }
+            out.push('\n');
+        }
+
+        if out.is_empty() {
+            None
+        } else {
+            Some(Ok(Bytes::from(out)))
+        }
+    }
+}
+
+// Quotes a CSV field when it contains a delimiter, quote or line break (RFC 4180)
+fn csv_escape(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
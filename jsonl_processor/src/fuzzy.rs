@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::iter;
+
+use crate::deadline::Deadline;
+use crate::Node;
+
+// Edit distance is computed over bytes rather than chars: it is still a metric, and
+// for the ASCII sample names we see in practice it is the same thing.
+pub fn levenshtein(a: &[u8], b: &[u8]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, &ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + (ca != cb) as usize;
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+struct BkNode {
+    // Indexes of all nodes sharing this name; the first is used to read the name
+    indexes: Vec<usize>,
+    // (distance to this node's name, child BkNode)
+    children: Vec<(usize, usize)>,
+}
+
+// BK-tree over distinct node names, so a query within distance k only compares
+// against a small fraction of the names.
+pub struct BkTree {
+    nodes: Vec<BkNode>,
+}
+
+impl BkTree {
+    pub fn build(nodes: &[Node]) -> BkTree {
+        let mut by_name: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (idx, node) in nodes.iter().enumerate() {
+            if !node.name.is_empty() {
+                by_name.entry(node.name.as_str()).or_default().push(idx);
+            }
+        }
+
+        // Insert in a deterministic order so the tree shape is reproducible
+        let mut names: Vec<(&str, Vec<usize>)> = by_name.into_iter().collect();
+        names.sort_unstable_by_key(|(_, indexes)| indexes[0]);
+
+        let mut tree = BkTree { nodes: Vec::with_capacity(names.len()) };
+        for (name, indexes) in names {
+            tree.insert(nodes, name.as_bytes(), indexes);
+        }
+        tree
+    }
+
+    fn name<'a>(&self, nodes: &'a [Node], bk_idx: usize) -> &'a [u8] {
+        nodes[self.nodes[bk_idx].indexes[0]].name.as_bytes()
+    }
+
+    fn insert(&mut self, nodes: &[Node], name: &[u8], indexes: Vec<usize>) {
+        let new_idx = self.nodes.len();
+        self.nodes.push(BkNode { indexes, children: Vec::new() });
+        if new_idx == 0 {
+            return;
+        }
+
+        let mut current = 0;
+        loop {
+            let distance = levenshtein(name, self.name(nodes, current));
+            match self.nodes[current].children.iter().find(|(d, _)| *d == distance) {
+                Some(&(_, child)) => current = child,
+                None => {
+                    self.nodes[current].children.push((distance, new_idx));
+                    return;
+                }
+            }
+        }
+    }
+
+    // Returns (node index, distance) for every node whose name is within `max_distance` of
+    // `query`, or those found so far once `deadline` passes
+    pub fn find(&self, nodes: &[Node], query: &str, max_distance: usize, deadline: &Deadline) -> Vec<(usize, usize)> {
+        let mut result = Vec::new();
+        if self.nodes.is_empty() {
+            return result;
+        }
+
+        let mut stack = vec![0];
+        for _ in deadline.watch(iter::repeat(())) {
+            let Some(current) = stack.pop() else { break };
+            let distance = levenshtein(query.as_bytes(), self.name(nodes, current));
+            let node = &self.nodes[current];
+            if distance <= max_distance {
+                result.extend(node.indexes.iter().map(|&idx| (idx, distance)));
+            }
+            stack.extend(node.children.iter()
+                .filter(|(d, _)| d.abs_diff(distance) <= max_distance)
+                .map(|&(_, child)| child));
+        }
+        result
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::BufRead;
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::load_error::LoadError;
+use crate::GeneDetail;
+
+// A feature of a GFF3 file, with GFF's 1-based inclusive coordinates
+struct Feature {
+    seqid: String,
+    kind: String,
+    start: usize,
+    end: usize,
+    strand: i32,
+    attributes: HashMap<String, String>,
+}
+
+// Genes read from the GFF3 file at `path`, by name: one per gene named by its CDS
+// features, with a segment per CDS when there are several, as for spliced genes, or
+// from its gene feature when it has none. Like gene_details elsewhere, starts are
+// 0-based and ends exclusive.
+pub fn read_genes(path: &str) -> Result<Vec<GeneDetail>, LoadError> {
+    let error = |message: String| LoadError::Gff(format!("{}: {}", crate::describe_path(path), message));
+    let mut features = Vec::new();
+    // seqid -> length, from ##sequence-region
+    let mut lengths: HashMap<String, usize> = HashMap::new();
+    for (i, line) in crate::open_reader(Path::new(path))?.lines().enumerate() {
+        let number = i + 1;
+        let line = line.map_err(|e| LoadError::reading(number, e))?;
+        let line = line.trim_end_matches('\r');
+        // Sequences may follow the features
+        if line.starts_with("##FASTA") {
+            break;
+        }
+        if let Some(region) = line.strip_prefix("##sequence-region") {
+            if let [seqid, _, end] = region.split_whitespace().collect::<Vec<_>>()[..] {
+                lengths.insert(seqid.to_string(), end.parse().map_err(|_| error(format!("line {} has an invalid ##sequence-region", number)))?);
+            }
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let columns: Vec<&str> = line.split('\t').collect();
+        let [seqid, _, kind, start, end, _, strand, _, attributes] = columns[..] else {
+            return Err(error(format!("line {} has {} columns rather than 9", number, columns.len())));
+        };
+        let coordinate = |value: &str| value.parse::<usize>().map_err(|_| error(format!("line {} has an invalid coordinate: {}", number, value)));
+        features.push(Feature {
+            seqid: seqid.to_string(),
+            kind: kind.to_string(),
+            start: coordinate(start)?,
+            end: coordinate(end)?,
+            strand: if strand == "-" { -1 } else { 1 },
+            attributes: attributes.split(';')
+                .filter_map(|attribute| attribute.split_once('='))
+                .map(|(key, value)| (key.trim().to_string(), unescape(value.trim())))
+                .collect(),
+        });
+    }
+
+    let by_id: HashMap<&str, &Feature> = features.iter()
+        .filter_map(|feature| Some((feature.attributes.get("ID")?.as_str(), feature)))
+        .collect();
+    let mut cds: BTreeMap<String, Vec<&Feature>> = BTreeMap::new();
+    let mut gene_features: BTreeMap<String, &Feature> = BTreeMap::new();
+    for feature in &features {
+        match feature.kind.as_str() {
+            "CDS" => {
+                if let Some(name) = gene_name(feature, &by_id) {
+                    cds.entry(name).or_default().push(feature);
+                }
+            }
+            "gene" => {
+                if let Some(name) = gene_name(feature, &by_id) {
+                    gene_features.insert(name, feature);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut genes: Vec<GeneDetail> = cds.into_iter()
+        .map(|(name, mut parts)| {
+            parts.sort_by_key(|part| part.start);
+            let segments = match parts.len() {
+                1 => Vec::new(),
+                _ => parts.iter().map(|part| [part.start.saturating_sub(1), part.end]).collect(),
+            };
+            GeneDetail {
+                strand: parts[0].strand,
+                start: parts[0].start.saturating_sub(1),
+                end: parts.iter().map(|part| part.end).max().unwrap_or(0),
+                segments,
+                name,
+            }
+        })
+        .collect();
+    for (name, feature) in gene_features {
+        if !genes.iter().any(|gene| gene.name == name) {
+            genes.push(GeneDetail { name, strand: feature.strand, start: feature.start.saturating_sub(1), end: feature.end, segments: Vec::new() });
+        }
+    }
+    genes.sort_by_key(|gene| gene.start);
+
+    validate(&genes, &features, &lengths, path);
+    info!("Read {} genes from {}", genes.len(), crate::describe_path(path));
+    Ok(genes)
+}
+
+// A feature's gene: its gene or Name attribute, or failing those its parent's, as a
+// CDS's parent is usually its mRNA or gene, or its own ID
+fn gene_name(feature: &Feature, by_id: &HashMap<&str, &Feature>) -> Option<String> {
+    let mut current = feature;
+    // Bounded, in case parents form a cycle
+    for _ in 0..by_id.len() + 1 {
+        if let Some(name) = current.attributes.get("gene").or_else(|| current.attributes.get("Name")) {
+            return Some(name.clone());
+        }
+        match current.attributes.get("Parent").and_then(|parent| by_id.get(parent.split(',').next()?)) {
+            Some(parent) => current = parent,
+            None => break,
+        }
+    }
+    feature.attributes.get("ID").cloned()
+}
+
+// Warns about genes of no length, genes that overlap and genes beyond the end of their
+// sequence when the file gives its length
+fn validate(genes: &[GeneDetail], features: &[Feature], lengths: &HashMap<String, usize>, path: &str) {
+    let source = crate::describe_path(path);
+    for gene in genes.iter().filter(|gene| gene.end <= gene.start) {
+        warn!("Gene {} in {} has no length", gene.name, source);
+    }
+    for (i, a) in genes.iter().enumerate() {
+        for b in genes[i + 1..].iter().take_while(|b| b.start < a.end) {
+            warn!("Genes {} and {} in {} overlap", a.name, b.name, source);
+        }
+    }
+    for feature in features.iter().filter(|feature| matches!(feature.kind.as_str(), "CDS" | "gene")) {
+        if let Some(&length) = lengths.get(&feature.seqid).filter(|&&length| feature.end > length) {
+            let name = feature.attributes.get("ID").map_or("", String::as_str);
+            warn!("Feature {} in {} ends at {}, beyond the end of {} at {}", name, source, feature.end, feature.seqid, length);
+        }
+    }
+}
+
+// GFF3 percent-encodes characters such as ; and = in attribute values
+fn unescape(value: &str) -> String {
+    if !value.contains('%') {
+        return value.to_string();
+    }
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
@@ -0,0 +1,76 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+enum JobStatus {
+    Pending,
+    Complete(Value),
+    Failed(String),
+}
+
+struct Job {
+    status: JobStatus,
+    // When the job finished, used for TTL eviction
+    finished: Option<Instant>,
+}
+
+// Background searches started with POST /search/?async=true and polled via
+// GET /search/status/{id}.
+pub struct SearchJobs {
+    jobs: Mutex<HashMap<u64, Job>>,
+    next_id: AtomicU64,
+    max_running: usize,
+    ttl: Duration,
+}
+
+impl SearchJobs {
+    pub fn new(max_running: usize, ttl: Duration) -> SearchJobs {
+        SearchJobs {
+            jobs: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            max_running,
+            ttl,
+        }
+    }
+
+    // Registers a pending job, or returns None when too many are already running.
+    pub fn start(&self) -> Option<u64> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.evict_expired(&mut jobs);
+        let running = jobs.values().filter(|job| matches!(job.status, JobStatus::Pending)).count();
+        if running >= self.max_running {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        jobs.insert(id, Job { status: JobStatus::Pending, finished: None });
+        Some(id)
+    }
+
+    pub fn finish(&self, id: u64, result: Result<Value, String>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            job.status = match result {
+                Ok(value) => JobStatus::Complete(value),
+                Err(error) => JobStatus::Failed(error),
+            };
+            job.finished = Some(Instant::now());
+        }
+    }
+
+    // Status document for a job, or None if it is unknown or has expired.
+    pub fn status(&self, id: u64) -> Option<Value> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.evict_expired(&mut jobs);
+        jobs.get(&id).map(|job| match &job.status {
+            JobStatus::Pending => json!({ "status": "pending" }),
+            JobStatus::Complete(result) => json!({ "status": "complete", "result": result }),
+            JobStatus::Failed(error) => json!({ "status": "failed", "error": error }),
+        })
+    }
+
+    fn evict_expired(&self, jobs: &mut HashMap<u64, Job>) {
+        jobs.retain(|_, job| job.finished.is_none_or(|finished| finished.elapsed() < self.ttl));
+    }
+}
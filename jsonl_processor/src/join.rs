@@ -0,0 +1,47 @@
+use serde_json::Value;
+use std::collections::BTreeSet;
+use tracing::{info, warn};
+
+use crate::args::KeyFilter;
+use crate::load_error::LoadError;
+use crate::tsv::KeyedTsv;
+use crate::{search, Node};
+
+// Merges the columns of the TSV at `path` into the metadata of the nodes named in its
+// `key_column`, as meta_<prefix><column>. A column that is also in the data file
+// replaces it for the nodes with a row. Fields --include-keys and --exclude-keys drop
+// are skipped, as they are from the data file.
+pub fn join_tsv(nodes: &mut [Node], path: &str, key_column: &str, prefix: &str, key_filter: &KeyFilter) -> Result<(), LoadError> {
+    let tsv = KeyedTsv::open("--metadata-tsv", path, key_column)?;
+    // None for columns that aren't joined
+    let fields: Vec<Option<String>> = tsv.columns.iter().enumerate()
+        .map(|(i, column)| Some(format!("meta_{}{}", prefix, column)).filter(|field| i != tsv.key_index && key_filter.keeps(field)))
+        .collect();
+    let joined: BTreeSet<&String> = fields.iter().flatten().collect();
+    let collisions: BTreeSet<&String> = nodes.iter().flat_map(|n| n.meta.keys()).filter(|field| joined.contains(field)).collect();
+    if !collisions.is_empty() {
+        let collisions: Vec<&str> = collisions.into_iter().map(String::as_str).collect();
+        warn!("{} of {} replace fields already in the data file; --metadata-prefix keeps both", collisions.join(", "), tsv.describe_path());
+    }
+
+    let source = tsv.describe_path().to_string();
+    let matched = tsv.match_rows(&search::build_name_index(nodes), |indexes, values| {
+        for &idx in indexes {
+            let meta = &mut nodes[idx].meta;
+            for (field, value) in fields.iter().zip(values) {
+                if let Some(field) = field {
+                    meta.insert(field.clone(), Value::String(value.to_string()));
+                }
+            }
+        }
+    })?;
+    info!(
+        columns = joined.len(),
+        matched = matched.rows,
+        unmatched = matched.unmatched.len(),
+        "Joined metadata from {}{}",
+        source,
+        matched.examples()
+    );
+    Ok(())
+}
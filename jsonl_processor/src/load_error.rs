@@ -0,0 +1,161 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+
+use crate::validate::TreeProblems;
+
+// Why a data file couldn't be loaded. When no dataset loads, the server exits with the
+// exit_code() of the first one's error.
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    // The compressed stream is corrupt or cut short
+    Decompress { codec: &'static str, source: io::Error },
+    MetadataParse { line: usize, source: serde_json::Error },
+    NodeParse { line: usize, snippet: String, source: serde_json::Error },
+    NotUtf8 { line: usize },
+    // --lenient skipped more than --lenient-max-skipped of the node lines
+    TooManyMalformed { skipped: usize, lines: usize, max_fraction: f64 },
+    EmptyFile,
+    NoRoot,
+    // With no node its own parent, the nodes whose parent isn't in the file
+    AmbiguousRoot { candidates: Vec<i32> },
+    // Some nodes don't lead to the root by following their parents
+    MalformedTree(TreeProblems),
+    // The lines of the first two nodes with the id
+    DuplicateNodeId { id: i32, lines: (usize, usize) },
+    // The --metadata-tsv or --acknowledgements file `flag` names can't be used
+    Tsv { flag: &'static str, message: String },
+    // The --gff file can't be parsed
+    Gff(String),
+    // The --reference file can't be used
+    Reference(String),
+    // The /config/ response couldn't be serialized or compressed
+    Config(String),
+    Panicked,
+}
+
+impl LoadError {
+    // What failed reading line `line`: bytes that aren't UTF-8 are what makes a line
+    // unreadable, rather than the file
+    pub fn reading(line: usize, e: io::Error) -> LoadError {
+        match LoadError::from(e) {
+            LoadError::Io(e) if e.kind() == io::ErrorKind::InvalidData => LoadError::NotUtf8 { line },
+            e => e,
+        }
+    }
+
+    // Malformed lines --lenient may skip
+    pub fn is_malformed_line(&self) -> bool {
+        matches!(self, LoadError::NodeParse { .. } | LoadError::NotUtf8 { .. })
+    }
+
+    // Listed under "Exit status" in --help
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LoadError::Io(_) => 3,
+            LoadError::Decompress { .. } => 4,
+            LoadError::MetadataParse { .. } => 5,
+            LoadError::NodeParse { .. } | LoadError::NotUtf8 { .. } | LoadError::TooManyMalformed { .. } => 6,
+            LoadError::EmptyFile => 7,
+            LoadError::NoRoot | LoadError::AmbiguousRoot { .. } => 8,
+            LoadError::MalformedTree(_) => 13,
+            LoadError::DuplicateNodeId { .. } => 9,
+            LoadError::Tsv { .. } => 10,
+            LoadError::Gff(_) => 11,
+            LoadError::Reference(_) => 12,
+            LoadError::Config(_) | LoadError::Panicked => 1,
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::Decompress { codec, source } => {
+                write!(f, "the {} data is corrupt or cut short ({}); check it with {} -t", codec, source, codec)
+            }
+            LoadError::MetadataParse { line, source } => write!(
+                f,
+                "the metadata on line {} is malformed at column {}: {}; the file must start with a line holding the metadata object",
+                line,
+                source.column(),
+                json_message(source)
+            ),
+            LoadError::NodeParse { line, snippet, source } => {
+                write!(f, "line {}, column {}: {}, in: {}", line, source.column(), json_message(source), snippet)
+            }
+            LoadError::NotUtf8 { line } => write!(f, "line {} isn't valid UTF-8", line),
+            LoadError::TooManyMalformed { skipped, lines, max_fraction } => write!(
+                f,
+                "{} of {} node lines were malformed, more than --lenient-max-skipped {} allows",
+                skipped, lines, max_fraction
+            ),
+            LoadError::EmptyFile => f.write_str("the file is empty; it should hold a line of metadata, then a line per node"),
+            LoadError::NoRoot => f.write_str(
+                "there is no root node; the root is the node whose parent_id is its own node_id, negative or missing, or else the one node whose parent isn't in the file",
+            ),
+            LoadError::AmbiguousRoot { candidates } => {
+                let examples: Vec<String> = candidates.iter().take(5).map(i32::to_string).collect();
+                write!(
+                    f,
+                    "no node is its own parent, and {} nodes have a parent that isn't in the file, so any could be the root, e.g. {}",
+                    candidates.len(),
+                    examples.join(", ")
+                )
+            }
+            LoadError::MalformedTree(problems) => write!(f, "the tree is malformed: {}; --lenient detaches them", problems),
+            LoadError::DuplicateNodeId { id, lines } => {
+                write!(f, "node_id {} is used by the nodes on both line {} and line {}", id, lines.0, lines.1)
+            }
+            LoadError::Tsv { flag, message } => write!(f, "can't use {} {}", flag, message),
+            LoadError::Gff(message) => write!(f, "can't use --gff {}", message),
+            LoadError::Reference(message) => write!(f, "can't use --reference {}", message),
+            LoadError::Config(message) => write!(f, "can't prepare /config/: {}", message),
+            LoadError::Panicked => f.write_str("loading panicked"),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io(source) | LoadError::Decompress { source, .. } => Some(source),
+            LoadError::MetadataParse { source, .. } | LoadError::NodeParse { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// Decompressing tags its errors, so they can be told apart from the file's
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> LoadError {
+        if e.get_ref().is_some_and(|inner| inner.is::<LoadError>()) {
+            return *e.into_inner().unwrap().downcast::<LoadError>().unwrap();
+        }
+        LoadError::Io(e)
+    }
+}
+
+// serde only ever sees the one line, so its " at line 1 column N" is dropped for the
+// file's own line number
+fn json_message(e: &serde_json::Error) -> String {
+    let message = e.to_string();
+    match message.rsplit_once(" at line ") {
+        Some((message, _)) => message.to_string(),
+        None => message,
+    }
+}
+
+// A decompressor whose errors become LoadError::Decompress
+pub struct Decompressing<R> {
+    pub codec: &'static str,
+    pub inner: R,
+}
+
+impl<R: Read> Read for Decompressing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(|source| io::Error::other(LoadError::Decompress { codec: self.codec, source }))
+    }
+}
@@ -0,0 +1,404 @@
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{HttpMessage, HttpRequest};
+use actix_web::middleware::Next;
+use actix_web::web::Bytes;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::io::Write as _;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Instrument, Metadata, Span, Subscriber};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    // One human-readable line per event
+    Pretty,
+    // One JSON object per line
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(name: &str) -> Result<LogFormat, String> {
+        match name {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("Unknown log format: {} (expected pretty or json)", other)),
+        }
+    }
+}
+
+// Which events are logged, in RUST_LOG syntax: a default level and target=level
+// overrides, e.g. "warn,jsonl_processor=debug". The longest matching target wins.
+#[derive(Clone)]
+pub struct Filter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    pub fn parse(directives: &str) -> Result<Filter, String> {
+        let mut filter = Filter { default: LevelFilter::INFO, targets: Vec::new() };
+        for directive in directives.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            let (target, level) = match directive.split_once('=') {
+                Some((target, level)) => (Some(target), level),
+                None => (None, directive),
+            };
+            let level = LevelFilter::from_str(level).map_err(|_| format!("Invalid log level: {}", level))?;
+            match target {
+                Some(target) => filter.targets.push((target.to_string(), level)),
+                None => filter.default = level,
+            }
+        }
+        filter.targets.sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        Ok(filter)
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let level = self.targets.iter()
+            .find(|(target, _)| metadata.target().starts_with(target.as_str()))
+            .map_or(self.default, |&(_, level)| level);
+        *metadata.level() <= level
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.targets.iter().map(|&(_, level)| level).fold(self.default, LevelFilter::max)
+    }
+}
+
+struct SpanData {
+    metadata: &'static Metadata<'static>,
+    fields: Vec<(&'static str, Value)>,
+    parent: Option<u64>,
+    opened: Instant,
+    // Handles to the span still alive; it closes when this reaches zero
+    refs: usize,
+}
+
+thread_local! {
+    // Spans entered on this thread, innermost last
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+// Writes events to stderr prefixed with the spans they happened in. Each span also logs
+// a line when it closes, with how long it was open, so a span around a phase of work
+// reports its timing.
+pub struct Logger {
+    filter: Filter,
+    format: LogFormat,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+}
+
+impl Logger {
+    pub fn new(filter: Filter, format: LogFormat) -> Logger {
+        Logger { filter, format, next_id: AtomicU64::new(1), spans: Mutex::new(HashMap::new()) }
+    }
+
+    fn current(&self) -> Option<u64> {
+        ENTERED.with(|entered| entered.borrow().last().copied())
+    }
+
+    // The span `id` and its ancestors, outermost first, as (name, fields)
+    fn scope(&self, spans: &HashMap<u64, SpanData>, mut id: Option<u64>) -> Vec<(&'static str, Vec<(&'static str, Value)>)> {
+        let mut scope = Vec::new();
+        while let Some(span) = id.and_then(|id| spans.get(&id)) {
+            scope.push((span.metadata.name(), span.fields.clone()));
+            id = span.parent;
+        }
+        scope.reverse();
+        scope
+    }
+
+    fn write(&self, metadata: &Metadata<'_>, scope: &[(&'static str, Vec<(&'static str, Value)>)], fields: Vec<(&'static str, Value)>) {
+        let mut line = String::new();
+        let timestamp = timestamp(SystemTime::now());
+        match self.format {
+            LogFormat::Pretty => {
+                let _ = write!(line, "{} {:>5} ", timestamp, metadata.level());
+                for (i, (name, span_fields)) in scope.iter().enumerate() {
+                    if i > 0 {
+                        line.push(':');
+                    }
+                    line.push_str(name);
+                    if !span_fields.is_empty() {
+                        line.push('{');
+                        write_fields(&mut line, span_fields);
+                        line.push('}');
+                    }
+                }
+                if !scope.is_empty() {
+                    line.push_str(": ");
+                }
+                let (message, fields): (Vec<_>, Vec<_>) = fields.into_iter().partition(|(name, _)| *name == "message");
+                if let Some((_, Value::String(message))) = message.into_iter().next() {
+                    line.push_str(&message);
+                    if !fields.is_empty() {
+                        line.push(' ');
+                    }
+                }
+                write_fields(&mut line, &fields);
+            }
+            LogFormat::Json => {
+                let mut object = Map::new();
+                object.insert("timestamp".to_string(), Value::String(timestamp));
+                object.insert("level".to_string(), Value::String(metadata.level().to_string()));
+                object.insert("target".to_string(), Value::String(metadata.target().to_string()));
+                let spans: Vec<Value> = scope.iter()
+                    .map(|(name, span_fields)| {
+                        let mut span: Map<String, Value> = span_fields.iter().map(|(k, v)| (k.to_string(), v.clone())).collect();
+                        span.insert("name".to_string(), Value::String(name.to_string()));
+                        Value::Object(span)
+                    })
+                    .collect();
+                if !spans.is_empty() {
+                    object.insert("spans".to_string(), Value::Array(spans));
+                }
+                object.extend(fields.into_iter().map(|(k, v)| (k.to_string(), v)));
+                line = Value::Object(object).to_string();
+            }
+        }
+        line.push('\n');
+        let _ = std::io::stderr().lock().write_all(line.as_bytes());
+    }
+}
+
+impl Subscriber for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.filter.max_level())
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let parent = if attrs.is_root() {
+            None
+        } else {
+            attrs.parent().map(Id::into_u64).or_else(|| self.current())
+        };
+        let mut fields = Vec::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+        let span = SpanData { metadata: attrs.metadata(), fields, parent, opened: Instant::now(), refs: 1 };
+        let mut spans = self.spans.lock().unwrap();
+        // A span keeps its parent open until it closes itself
+        if let Some(parent) = parent.and_then(|parent| spans.get_mut(&parent)) {
+            parent.refs += 1;
+        }
+        spans.insert(id, span);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            let mut fields = Vec::new();
+            values.record(&mut FieldVisitor(&mut fields));
+            for (name, value) in fields {
+                match span.fields.iter_mut().find(|(existing, _)| *existing == name) {
+                    Some((_, existing)) => *existing = value,
+                    None => span.fields.push((name, value)),
+                }
+            }
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let parent = if event.is_root() {
+            None
+        } else {
+            event.parent().map(Id::into_u64).or_else(|| self.current())
+        };
+        let scope = self.scope(&self.spans.lock().unwrap(), parent);
+        let mut fields = Vec::new();
+        event.record(&mut FieldVisitor(&mut fields));
+        self.write(event.metadata(), &scope, fields);
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(position) = entered.iter().rposition(|&id| id == span.into_u64()) {
+                entered.remove(position);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            span.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let id = span.into_u64();
+        let Some(data) = spans.get_mut(&id) else {
+            return false;
+        };
+        data.refs -= 1;
+        if data.refs > 0 {
+            return false;
+        }
+        let data = spans.remove(&id).expect("span present");
+        let mut scope = self.scope(&spans, data.parent);
+        drop(spans);
+        scope.push((data.metadata.name(), data.fields));
+        let elapsed = data.opened.elapsed();
+        let fields = match self.format {
+            LogFormat::Pretty => vec![("message", Value::String(format!("done in {:?}", elapsed)))],
+            LogFormat::Json => vec![
+                ("message", Value::String("done".to_string())),
+                ("elapsed_ms", serde_json::json!(elapsed.as_secs_f64() * 1000.0)),
+            ],
+        };
+        self.write(data.metadata, &scope, fields);
+        if let Some(parent) = data.parent {
+            self.try_close(Id::from_u64(parent));
+        }
+        true
+    }
+}
+
+struct FieldVisitor<'a>(&'a mut Vec<(&'static str, Value)>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.push((field.name(), serde_json::json!(value)));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.push((field.name(), Value::from(value)));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.push((field.name(), Value::from(value)));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.push((field.name(), Value::Bool(value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), Value::String(value.to_string())));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push((field.name(), Value::String(format!("{:?}", value))));
+    }
+}
+
+// key=value pairs separated by spaces; strings are written bare
+fn write_fields(line: &mut String, fields: &[(&'static str, Value)]) {
+    for (i, (name, value)) in fields.iter().enumerate() {
+        if i > 0 {
+            line.push(' ');
+        }
+        let _ = match value {
+            Value::String(value) => write!(line, "{}={}", name, value),
+            value => write!(line, "{}={}", name, value),
+        };
+    }
+}
+
+// RFC 3339 in UTC with milliseconds, e.g. 2024-05-01T12:34:56.789Z
+pub fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60, since.subsec_millis()
+    )
+}
+
+// Middleware opening a span for each request, carrying its method, path and query. The
+// span closes once the response body has been sent, logging the status, the size of the
+// body as sent and how long the request took, which serves as the access log.
+pub async fn access_log(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let span = tracing::info_span!(
+        "request",
+        method = %req.method(),
+        path = req.path(),
+        query = req.query_string(),
+        status = tracing::field::Empty,
+        bytes = tracing::field::Empty,
+    );
+    req.extensions_mut().insert(span.clone());
+    match next.call(req).instrument(span.clone()).await {
+        Ok(res) => {
+            span.record("status", res.status().as_u16());
+            Ok(res.map_body(|_, body| BoxBody::new(LoggedBody { inner: BoxBody::new(body), span, bytes: 0 })))
+        }
+        Err(e) => {
+            span.record("status", e.as_response_error().status_code().as_u16());
+            Err(e)
+        }
+    }
+}
+
+// The span access_log opened for a request, for work the request hands to other threads
+pub fn request_span(req: &HttpRequest) -> Span {
+    req.extensions().get::<Span>().cloned().unwrap_or_else(Span::none)
+}
+
+// A response body that counts the bytes passing through it and records the total on
+// the request span when it is done with
+struct LoggedBody {
+    inner: BoxBody,
+    span: Span,
+    bytes: u64,
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.span.record("bytes", self.bytes);
+    }
+}
+
+impl MessageBody for LoggedBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.inner.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        let next = Pin::new(&mut this.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &next {
+            this.bytes += chunk.len() as u64;
+        }
+        next
+    }
+}
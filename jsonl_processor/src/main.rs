@@ -1,18 +1,96 @@
-use actix_web::{web, App, HttpServer, Responder, Result, get, HttpResponse};
+use actix_web::{web, App, HttpRequest, HttpServer, Responder, Result, get, post, HttpResponse};
+use actix_web::http::header;
+use actix_web::middleware::from_fn;
 use actix_cors::Cors;
+use serde::ser::{SerializeMap, Serializer};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use serde_json::Value;
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::error::Error;
 use std::fs::File;
-use std::io::Seek;
-use std::io::{self, BufRead};
-use std::path::Path;
-use std::cmp::Ordering;
-use std::time::Instant;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, debug_span, error, info, info_span, warn, Span};
 use flate2::read::GzDecoder;
 
+mod acknowledgements;
+mod args;
+mod auth;
+mod cache;
+mod colors;
+mod compression;
+mod dataset;
+mod dates;
+mod deadline;
+mod error;
+mod etag;
+mod export;
+mod fuzzy;
+mod gff;
+mod jobs;
+mod join;
+mod load_error;
+mod logging;
+mod msgpack;
+mod offload;
+mod parse;
+mod ratelimit;
+mod search;
+mod sequence;
+mod shutdown;
+mod snapshot;
+#[cfg(unix)]
+mod socket;
+mod spatial;
+mod streaming;
+mod tsv;
+mod validate;
+mod watch;
+mod xz;
+
+use acknowledgements::Acknowledgements;
+use args::{Args, KeyFilter};
+use dataset::{Catalog, Dataset, Snapshot};
+use deadline::Deadline;
+use error::ApiError;
+use offload::HeavyWork;
+use ratelimit::RateLimiter;
+use cache::{CachingChunks, ResponseCache};
+use colors::ColorTables;
+use compression::{Compression, Precompressed};
+use fuzzy::BkTree;
+use load_error::{Decompressing, LoadError};
+use sequence::Reference;
+use jobs::SearchJobs;
+use spatial::SpatialGrid;
+use streaming::{ArrayChunks, Format};
+
+use search::{BooleanMethod, CladeIndex, GenotypeCache, MetaIndex, NumericColumn, PrefixIndex, SearchRequest, SearchSpec, DEFAULT_SEARCH_THRESHOLD};
+
+// Largest density histogram (bins_x * bins_y) that can be requested
+const MAX_DENSITY_BINS: usize = 1 << 20;
+
+// Grid resolution, in cells across the whole tree, used to sample the minimap
+const MINIMAP_RESOLUTION: f64 = 200.0;
+
+// Fraction of the viewport width added on each side when filtering nodes by x
+const VIEWPORT_X_MARGIN: f64 = 0.05;
+
+// The path argument for reading the data from stdin
+const STDIN_PATH: &str = "-";
+
+// How often, in nodes, loading logs its progress
+const LOAD_PROGRESS_INTERVAL: usize = 100000;
+
+// Fewest nodes each thread is given when reducing overplotting; smaller inputs use fewer threads
+const MIN_NODES_PER_THREAD: usize = 20000;
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 struct Metadata {
     version: String,
@@ -45,6 +123,45 @@ enum Mutation {
     },
 }
 
+impl Mutation {
+    fn mutation_id(&self) -> usize {
+        match self {
+            Mutation::AA { mutation_id, .. } | Mutation::NT { mutation_id, .. } => *mutation_id,
+        }
+    }
+
+    fn gene(&self) -> &str {
+        match self {
+            Mutation::AA { gene, .. } | Mutation::NT { gene, .. } => gene,
+        }
+    }
+
+    fn residue_pos(&self) -> usize {
+        match self {
+            Mutation::AA { residue_pos, .. } | Mutation::NT { residue_pos, .. } => *residue_pos,
+        }
+    }
+
+    fn new_residue(&self) -> &str {
+        match self {
+            Mutation::AA { new_residue, .. } | Mutation::NT { new_residue, .. } => new_residue,
+        }
+    }
+
+    fn previous_residue(&self) -> &str {
+        match self {
+            Mutation::AA { previous_residue, .. } | Mutation::NT { previous_residue, .. } => previous_residue,
+        }
+    }
+
+    fn with_previous_residue(mut self, residue: &str) -> Mutation {
+        match &mut self {
+            Mutation::AA { previous_residue, .. } | Mutation::NT { previous_residue, .. } => *previous_residue = residue.to_string(),
+        }
+        self
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 struct Config {
     gene_details: HashMap<String, GeneDetail>,
@@ -65,6 +182,61 @@ struct Config {
     root_mutations: Option<Vec<i32>>,
     #[serde(default)]
     root_id: Option<i32>,
+    // Every tree's root when the file holds a forest (see --forest), in file order;
+    // root_id and root_mutations are the first's
+    #[serde(default)]
+    root_ids: Option<Vec<i32>>,
+    // Root node_id -> the mutations on that root
+    #[serde(default)]
+    root_mutations_by_id: Option<BTreeMap<i32, Vec<i32>>>,
+    #[serde(default)]
+    num_trees: Option<usize>,
+    // Whether nodes carry x_time, so x_type=x_time can be requested
+    #[serde(default)]
+    x_time_available: bool,
+    // The date x_time counts days from, when it was computed with --time-from-key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    time_origin: Option<String>,
+    // Factor applied to the input y coordinates; every y we serve or accept is scaled
+    // unless a request says y_space=raw
+    #[serde(default)]
+    y_scale: Option<f64>,
+    // Bounds of the whole tree, so the camera can be set up without a probe request
+    #[serde(default)]
+    extremes: Option<Extremes>,
+    // The type inferred for each metadata field, so the frontend can pick a widget for it
+    #[serde(default)]
+    metadata_types: Option<BTreeMap<String, MetaType>>,
+    // Metadata fields with array values, which meta_contains searches within
+    #[serde(default)]
+    multi_valued_keys: Option<Vec<String>>,
+    // Whether /acknowledgements/ has attributions to serve
+    #[serde(default)]
+    acknowledgements_available: bool,
+    // Whether /sequence/ has a reference to rebuild sequences from
+    #[serde(default)]
+    reference_available: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MetaType {
+    Number,
+    Bool,
+    Categorical,
+}
+
+// Bounds of the whole tree, computed once at load; x_time bounds only for time trees
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+struct Extremes {
+    min_y: f64,
+    max_y: f64,
+    min_x_dist: f64,
+    max_x_dist: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_x_time: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_x_time: Option<f64>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -73,28 +245,199 @@ struct GeneDetail {
     strand: i32,
     start: usize,
     end: usize,
+    // The [start, end) of each part of a spliced gene, from --gff
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    segments: Vec<[usize; 2]>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 struct Node {
     name: String,
     x_dist: f64,
+    // Laid out from the tree instead when missing; see layout_y
+    #[serde(default)]
     y: f64,
+    // Only present in time trees (e.g. produced by chronumental)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    x_time: Option<f64>,
     mutations: Vec<i32>,
+    // Some exporters give the root -1, or no parent_id at all; it is set to the root's
+    // own node_id as the nodes are read
+    #[serde(default = "no_parent")]
     parent_id: i32,
     node_id: i32,
+    // Counted from the tree instead when missing; see count_tips
+    #[serde(default)]
     num_tips: i32,
-    clades: HashMap<String, String>,
+    // Ordered maps so a node always serializes the same way: clades and meta fields
+    // alphabetically, whatever their order in the data file
+    clades: BTreeMap<String, String>,
     #[serde(flatten)]
-    meta: HashMap<String, Value>,
+    meta: BTreeMap<String, Value>,
+}
+
+fn no_parent() -> i32 {
+    -1
+}
+
+// Which coordinate is used for the horizontal axis
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum XType {
+    Dist,
+    Time,
+}
+
+impl XType {
+    fn parse(x_type: Option<&str>, config: &Config) -> Result<XType, String> {
+        match x_type.unwrap_or("x_dist") {
+            "x_dist" => Ok(XType::Dist),
+            "x_time" if config.x_time_available => Ok(XType::Time),
+            "x_time" => Err("x_type=x_time is not available: this dataset has no x_time values".to_string()),
+            other => Err(format!("Unknown x_type: {}", other)),
+        }
+    }
+
+    // NaN for nodes without a time, so they fall outside every range
+    fn x(self, node: &Node) -> f64 {
+        match self {
+            XType::Dist => node.x_dist,
+            XType::Time => node.x_time.unwrap_or(f64::NAN),
+        }
+    }
 }
 
 struct AppState {
+    // Sorted by y, so a y range is a contiguous slice
     nodes: Vec<Node>,
+    // node_id -> index in nodes
+    node_index: HashMap<i32, usize>,
     child_to_parent: HashMap<i32, i32>,
+    // parent node_id -> indexes of its children
+    children: HashMap<i32, Vec<usize>>,
+    // Pre-order (enter, exit) numbering per node index; descendants of a node
+    // have their enter number within its range
+    dfs_intervals: Vec<(usize, usize)>,
+    // Node indexes in pre-order, so a subtree is the slice dfs_order[enter..=exit]
+    dfs_order: Vec<usize>,
     config: Config,
-    root_mutations: Vec<i32>,
     root_id: i32,
+    // Every tree's root; just root_id unless the file holds a forest
+    root_ids: Vec<i32>,
+    // Every meta field name present in the data, sorted, so the order nodes' meta fields
+    // serialize in
+    metadata_keys: Vec<String>,
+    // mutation_id -> position in config.mutations
+    mutation_lookup: HashMap<i32, usize>,
+    // exact node name -> indexes of nodes with that name
+    name_index: HashMap<String, Vec<usize>>,
+    prefix_index: PrefixIndex,
+    fuzzy_index: Option<BkTree>,
+    clade_index: CladeIndex,
+    meta_index: Option<MetaIndex>,
+    // meta field name -> parsed values for numeric and date fields
+    numeric_columns: HashMap<String, NumericColumn>,
+    acknowledgements: Option<Acknowledgements>,
+    reference: Option<Reference>,
+    genotype_cache: GenotypeCache,
+    color_tables: ColorTables,
+    // Grids over (x_dist, y) and, for time trees, (x_time, y) for spatial lookups
+    spatial_index: SpatialGrid,
+    spatial_time_index: Option<SpatialGrid>,
+    search_jobs: Arc<SearchJobs>,
+    max_search_limit: usize,
+    max_export_tips: usize,
+    max_nodes_returned: usize,
+    heavy_work: HeavyWork,
+    // How long /nodes/ and /search/ may compute for before giving up
+    request_timeout: Option<Duration>,
+    // Threads used to reduce overplotting in large results
+    threads: usize,
+    // Serialized /minimap/ response, computed once at startup
+    minimap: web::Bytes,
+    minimap_etag: String,
+    compression: Compression,
+    // /config/ body, in every allowed encoding
+    config_body: Precompressed,
+    config_etag: String,
+    // When the dataset was loaded; part of the ETag of computed responses
+    loaded_at: SystemTime,
+    // How long loading it and building the indexes took
+    load_time: Duration,
+    tips: usize,
+    nodes_cache: ResponseCache,
+    extremes: Extremes,
+}
+
+impl AppState {
+    // True if the node at `idx` is `ancestor_idx` or lies beneath it
+    fn is_descendant(&self, idx: usize, ancestor_idx: usize) -> bool {
+        let (enter, _) = self.dfs_intervals[idx];
+        let (ancestor_enter, ancestor_exit) = self.dfs_intervals[ancestor_idx];
+        enter >= ancestor_enter && enter <= ancestor_exit
+    }
+
+    fn node(&self, node_id: i32) -> Option<&Node> {
+        self.node_index.get(&node_id).map(|&idx| &self.nodes[idx])
+    }
+
+    // The node followed by its ancestors up to and including the root
+    fn ancestry(&self, node_id: i32) -> Vec<&Node> {
+        let mut result = Vec::new();
+        let mut current = self.node(node_id);
+        while let Some(node) = current {
+            result.push(node);
+            current = self.child_to_parent.get(&node.node_id).and_then(|&parent_id| self.node(parent_id));
+        }
+        result
+    }
+
+    fn mutation(&self, mutation_id: i32) -> Option<&Mutation> {
+        self.mutation_lookup.get(&mutation_id).map(|&idx| &self.config.mutations[idx])
+    }
+
+    fn is_tip(&self, idx: usize) -> bool {
+        self.children.get(&self.nodes[idx].node_id).is_none_or(|c| c.is_empty())
+    }
+
+    // Deepest node that has every one of `indexes` at or beneath it
+    fn mrca(&self, indexes: &[usize]) -> Option<usize> {
+        let enters = indexes.iter().map(|&idx| self.dfs_intervals[idx].0);
+        let (min_enter, max_enter) = enters.fold((usize::MAX, 0), |(lo, hi), enter| (lo.min(enter), hi.max(enter)));
+        if min_enter == usize::MAX || indexes.iter().any(|&idx| self.dfs_intervals[idx].0 == usize::MAX) {
+            return None;
+        }
+
+        // The node entered first is the MRCA or a descendant of it, so climb from
+        // there until the subtree also spans the node entered last
+        let mut idx = self.dfs_order[min_enter];
+        while self.dfs_intervals[idx].1 < max_enter {
+            let parent_id = *self.child_to_parent.get(&self.nodes[idx].node_id)?;
+            idx = *self.node_index.get(&parent_id)?;
+        }
+        Some(idx)
+    }
+
+    // Indexes of the tips at or beneath any of `ancestors`
+    fn tips_beneath(&self, ancestors: &[usize]) -> Vec<usize> {
+        let mut ranges: Vec<(usize, usize)> = ancestors.iter()
+            .map(|&idx| self.dfs_intervals[idx])
+            .filter(|&(enter, _)| enter != usize::MAX)
+            .collect();
+        ranges.sort_unstable();
+
+        let mut result = Vec::new();
+        let mut covered_until = 0;
+        for (enter, exit) in ranges {
+            // Nested ranges have already been collected
+            let start = enter.max(covered_until);
+            if start > exit {
+                continue;
+            }
+            result.extend(self.dfs_order[start..=exit].iter().copied().filter(|&idx| self.is_tip(idx)));
+            covered_until = exit + 1;
+        }
+        result
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -104,277 +447,2886 @@ struct NodesQuery {
     min_x: Option<f64>,
     max_x: Option<f64>,
     x_type: Option<String>,
+    // Comma-separated node fields to include; all of them when omitted
+    fields: Option<String>,
+    // reduce=false returns every leaf in the viewport without thinning
+    #[serde(default = "default_true")]
+    reduce: bool,
+    // Replace the precisions computed from the viewport by get_precision
+    precision_x: Option<f64>,
+    precision_y: Option<f64>,
+    // "tips", "internal" or "all" (default)
+    node_types: Option<String>,
+    // mode=density returns a histogram of tips over the viewport instead of nodes
+    mode: Option<String>,
+    bins_x: Option<usize>,
+    bins_y: Option<usize>,
+    // Break the density histogram down by the values of this metadata key
+    density_key: Option<String>,
+    // delta=true adds a delta_token to the response; passing it back as since= returns
+    // only the nodes that weren't in that earlier response
+    #[serde(default)]
+    delta: bool,
+    since: Option<String>,
+    // Add each node's color_index for this metadata key (see /colors/)
+    include_color_index: Option<String>,
+    // "json" (default) or "csv"; an Accept header of text/csv also selects CSV
+    format: Option<String>,
+    // "scaled" (default) or "raw" when min_y/max_y are in the input file's coordinates
+    y_space: Option<String>,
+    // filter_key/filter_value pairs may be repeated; they are read from the raw query
+    // string by metadata_filters()
 }
 
-#[derive(Debug, Serialize)]
-struct NodesResponse {
-    nodes: Vec<Node>,
+impl NodesQuery {
+    // Checks the numbers that deserialized but make no sense as a viewport
+    fn validate(&self) -> Result<(), ApiError> {
+        validate_range(("min_x", self.min_x), ("max_x", self.max_x))?;
+        validate_range(("min_y", self.min_y), ("max_y", self.max_y))?;
+        for (name, precision) in [("precision_x", self.precision_x), ("precision_y", self.precision_y)] {
+            if precision.is_some_and(|p| !(p.is_finite() && p > 0.0)) {
+                return Err(ApiError::invalid_parameter(name, format!("{} must be a positive number", name)));
+            }
+        }
+        Ok(())
+    }
 }
 
-fn load_data(path: &Path) -> Result<(Metadata, Vec<Node>, HashMap<i32, i32>, Vec<i32>, i32), Box<dyn Error>> {
-    let file = File::open(path)?;
+// Rejects NaN and infinite bounds, and a minimum greater than the maximum
+fn validate_range((min_name, min): (&str, Option<f64>), (max_name, max): (&str, Option<f64>)) -> Result<(), ApiError> {
+    for (name, value) in [(min_name, min), (max_name, max)] {
+        if value.is_some_and(|value| !value.is_finite()) {
+            return Err(ApiError::invalid_parameter(name, format!("{} must be a finite number", name)));
+        }
+    }
+    if let (Some(min), Some(max)) = (min, max) {
+        if min > max {
+            return Err(ApiError::invalid_parameter(min_name, format!("{} must not be greater than {}", min_name, max_name)));
+        }
+    }
+    Ok(())
+}
 
-    let reader: Box<dyn BufRead> = if path.extension().and_then(|s| s.to_str()) == Some("gz") {
-        Box::new(io::BufReader::new(GzDecoder::new(file)))
-    } else {
-        Box::new(io::BufReader::new(file))
-    };
+// Which nodes /nodes/ thins and returns. "all" thins the leaves and then restores
+// their ancestors; "internal" does the same starting from internal nodes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NodeTypes {
+    All,
+    Tips,
+    Internal,
+}
 
-    let mut lines = reader.lines();
+impl NodeTypes {
+    fn parse(node_types: Option<&str>) -> Result<NodeTypes, String> {
+        match node_types.unwrap_or("all") {
+            "all" => Ok(NodeTypes::All),
+            "tips" => Ok(NodeTypes::Tips),
+            "internal" => Ok(NodeTypes::Internal),
+            other => Err(format!("Unknown node_types: {}", other)),
+        }
+    }
 
-    // Read the first line separately as metadata
-    let metadata_line = lines.next().ok_or("Empty file")??;
-    let metadata: Metadata = serde_json::from_str(&metadata_line)?;
-
-    let mut nodes = Vec::new();
-    let mut child_to_parent = HashMap::new();
-    let mut root_mutations = Vec::new();
-    let mut root_id = 0;
-
-    // Process nodes
-    for line in lines {
-        let line = line?;
-        let mut node: Node = serde_json::from_str(&line)?;
-        
-        if node.parent_id == node.node_id {
-            // This is the root node
-            root_mutations = node.mutations.clone();
-            root_id = node.node_id;
-            node.mutations = Vec::new(); // Clear root node mutations
-        } else {
-            child_to_parent.insert(node.node_id, node.parent_id);
+    // Whether the node at `idx` is one of the points that get thinned
+    fn is_candidate(self, data: &AppState, idx: usize) -> bool {
+        match self {
+            NodeTypes::All => data.nodes[idx].num_tips == 1,
+            NodeTypes::Tips => data.is_tip(idx),
+            NodeTypes::Internal => data.nodes[idx].num_tips > 1,
         }
-        
-        nodes.push(node);
     }
 
-    Ok((metadata, nodes, child_to_parent, root_mutations, root_id))
+    fn adds_parents(self) -> bool {
+        self != NodeTypes::Tips
+    }
 }
 
-fn scale_y_coordinates(nodes: &mut Vec<Node>) {
-    let num_nodes = nodes.len();
-    let scale_y = 24e2 / if num_nodes > 10000 { num_nodes as f64 } else { num_nodes as f64 * 0.6666 };
-    
-    for node in nodes.iter_mut() {
-        node.y = (node.y * scale_y * 1e6).round() / 1e6;  // Round to 6 decimal places
+// Repeated filter_key=..&filter_value=.. pairs from a query string, as a search spec
+// matching nodes that satisfy all of them
+fn metadata_filters(query_string: &str) -> Result<Option<SearchSpec>> {
+    let params = web::Query::<Vec<(String, String)>>::from_query(query_string)?.into_inner();
+    let keys: Vec<&String> = params.iter().filter(|(name, _)| name == "filter_key").map(|(_, v)| v).collect();
+    let values: Vec<&String> = params.iter().filter(|(name, _)| name == "filter_value").map(|(_, v)| v).collect();
+    if keys.len() != values.len() {
+        return Err(ApiError::invalid_parameter("filter_value", "Each filter_key needs a matching filter_value").into());
     }
+    if keys.is_empty() {
+        return Ok(None);
+    }
+    let subspecs = keys.into_iter()
+        .zip(values)
+        .map(|(key, value)| SearchSpec::Meta { key: key.clone(), value: Value::String(value.clone()) })
+        .collect();
+    Ok(Some(SearchSpec::Boolean { boolean_method: BooleanMethod::And, subspecs }))
 }
 
-fn calculate_extremes(nodes: &[Node]) -> (f64, f64, f64, f64) {
-    let mut min_y = f64::MAX;
-    let mut max_y = f64::MIN;
-    let mut min_x = f64::MAX;
-    let mut max_x = f64::MIN;
+// A node field that can be requested with fields=
+#[derive(Debug, PartialEq)]
+enum NodeField {
+    Name,
+    XDist,
+    XTime,
+    Y,
+    Mutations,
+    ParentId,
+    NodeId,
+    NumTips,
+    Clades,
+    Meta(String),
+}
 
-    for node in nodes.iter() {
-        min_y = min_y.min(node.y);
-        max_y = max_y.max(node.y);
-        min_x = min_x.min(node.x_dist);
-        max_x = max_x.max(node.x_dist);
+impl NodeField {
+    fn parse_list(fields: &str, metadata_keys: &[String]) -> Result<Vec<NodeField>, String> {
+        fields.split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(|field| match field {
+                "name" => Ok(NodeField::Name),
+                "x_dist" => Ok(NodeField::XDist),
+                "x_time" => Ok(NodeField::XTime),
+                "y" => Ok(NodeField::Y),
+                "mutations" => Ok(NodeField::Mutations),
+                "parent_id" => Ok(NodeField::ParentId),
+                "node_id" => Ok(NodeField::NodeId),
+                "num_tips" => Ok(NodeField::NumTips),
+                "clades" => Ok(NodeField::Clades),
+                other if metadata_keys.iter().any(|k| k == other) => Ok(NodeField::Meta(other.to_string())),
+                other => Err(format!("Unknown field: {}", other)),
+            })
+            // A field listed twice is only sent once
+            .try_fold(Vec::new(), |mut parsed, field| {
+                let field = field?;
+                if !parsed.contains(&field) {
+                    parsed.push(field);
+                }
+                Ok(parsed)
+            })
     }
+}
 
-    (min_y, max_y, min_x, max_x)
+// Just the requested fields of a node, serialized straight from it
+struct SelectedFields<'a> {
+    node: &'a Node,
+    fields: &'a [NodeField],
 }
 
-fn update_config(config: &mut Config, nodes: &[Node], root_mutations: &Vec<i32>, root_id: i32, mutations: Vec<Mutation>) {
-    let (min_y, max_y, min_x, max_x) = calculate_extremes(nodes);
-    config.initial_x = Some((max_x + min_x) / 2.0);
-    config.initial_y = Some((max_y + min_y) / 2.0);
-    config.initial_zoom = Some(config.initial_zoom.unwrap_or(-2.0));
-    config.num_nodes = Some(nodes.len());
-    config.root_mutations = Some(root_mutations.clone());
-    config.root_id = Some(root_id);
-    config.mutations = mutations;
-    config.keys_to_display = Some(vec!["name".to_string(), "num_tips".to_string()]);
+impl Serialize for SelectedFields<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let node = self.node;
+        let mut map = serializer.serialize_map(Some(self.fields.len()))?;
+        for field in self.fields {
+            match field {
+                NodeField::Name => map.serialize_entry("name", &node.name)?,
+                NodeField::XDist => map.serialize_entry("x_dist", &node.x_dist)?,
+                NodeField::XTime => map.serialize_entry("x_time", &node.x_time)?,
+                NodeField::Y => map.serialize_entry("y", &node.y)?,
+                NodeField::Mutations => map.serialize_entry("mutations", &node.mutations)?,
+                NodeField::ParentId => map.serialize_entry("parent_id", &node.parent_id)?,
+                NodeField::NodeId => map.serialize_entry("node_id", &node.node_id)?,
+                NodeField::NumTips => map.serialize_entry("num_tips", &node.num_tips)?,
+                NodeField::Clades => map.serialize_entry("clades", &node.clades)?,
+                NodeField::Meta(key) => map.serialize_entry(key, node.meta.get(key).unwrap_or(&Value::Null))?,
+            }
+        }
+        map.end()
+    }
 }
 
-#[get("/config/")]
-async fn get_config(data: web::Data<AppState>) -> impl Responder {
-    HttpResponse::Ok().json(&data.config)
+#[derive(Debug, Deserialize, Clone)]
+struct SearchQuery {
+    text: Option<String>,
+    method: Option<String>,
+    max_distance: Option<usize>,
+    json: Option<String>,
+    // Above this many hits the response is summarized
+    threshold: Option<usize>,
+    // Requesting a page disables summarizing
+    offset: Option<usize>,
+    limit: Option<usize>,
+    // Only report total_count
+    #[serde(default)]
+    count_only: bool,
+    min_y: Option<f64>,
+    max_y: Option<f64>,
+    min_x: Option<f64>,
+    max_x: Option<f64>,
+    x_type: Option<String>,
+    // "json" (the default) or "msgpack"; without it the Accept header decides
+    format: Option<String>,
+    // POST only: run in the background and return a job id
+    #[serde(rename = "async", default)]
+    run_async: bool,
 }
 
-#[get("/node/{node_id}")]
-async fn get_node(data: web::Data<AppState>, node_id: web::Path<i32>) -> Result<impl Responder> {
-    if let Some(node) = data.nodes.iter().find(|&n| n.node_id == *node_id) {
-        Ok(web::Json(node.clone()))
-    } else {
-        Err(actix_web::error::ErrorNotFound("Node not found"))
+impl SearchQuery {
+    // A full spec can be passed as JSON; otherwise text/method describe a name search.
+    fn request(&self) -> Result<SearchRequest, String> {
+        if let Some(json) = &self.json {
+            let request: SearchRequest = serde_json::from_str(json).map_err(|e| format!("Invalid search spec: {}", e))?;
+            request.spec.validate().map_err(|e| format!("Invalid search spec: {}", e))?;
+            return Ok(request);
+        }
+        let spec = SearchSpec::Name {
+            method: self.method.clone().unwrap_or_else(|| "text_match".to_string()),
+            text: self.text.clone().unwrap_or_default(),
+            max_distance: self.max_distance,
+        };
+        spec.validate()?;
+        Ok(SearchRequest { spec, root_node_id: None })
+    }
+
+    // The same checks as request(), naming the parameter at fault; the viewport only
+    // matters when hits are summarized but is checked regardless
+    fn validate(&self, config: &Config) -> Result<(), ApiError> {
+        validate_range(("min_x", self.min_x), ("max_x", self.max_x))?;
+        validate_range(("min_y", self.min_y), ("max_y", self.max_y))?;
+        XType::parse(self.x_type.as_deref(), config).map_err(|e| ApiError::invalid_parameter("x_type", e))?;
+        let parameter = if self.json.is_some() { "json" } else { "method" };
+        self.request().map(drop).map_err(|e| ApiError::invalid_parameter(parameter, e))
     }
 }
 
-#[get("/")]
-async fn index(_data: web::Data<AppState>) -> String {
-    "Hello world!".to_string()
+// A node from a fuzzy name search, with its edit distance from the query
+#[derive(Debug, Serialize)]
+struct FuzzyHit<'a> {
+    #[serde(flatten)]
+    node: &'a Node,
+    distance: usize,
 }
 
-#[get("/search/")]
-async fn search(_data: web::Data<AppState>) -> impl Responder {
-    HttpResponse::Ok().json(json!({
-        "type": "complete",
-        "data": [],
-        "total_count": 0
-    }))
+// Compact representation of a search hit used when results are summarized
+#[derive(Debug, Serialize)]
+struct SearchHit {
+    node_id: i32,
+    x_dist: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    x_time: Option<f64>,
+    y: f64,
+    num_tips: i32,
 }
 
-#[get("/nodes/")]
-async fn get_nodes(
-    data: web::Data<AppState>,
-    query: web::Query<NodesQuery>,
-) -> impl Responder {
-    let start_time = Instant::now();
+#[derive(Debug, Deserialize)]
+struct NodeDetailsQuery {
+    id: i32,
+}
 
-    let lock_time = start_time.elapsed();
-    println!("Time to acquire locks: {:?}", lock_time);
-    
-    let min_y = query.min_y.unwrap_or_else(|| data.nodes.iter().map(|n| n.y).min_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal)).unwrap_or(0.0));
-    let max_y = query.max_y.unwrap_or_else(|| data.nodes.iter().map(|n| n.y).max_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal)).unwrap_or(0.0));
-    let min_x = query.min_x.unwrap_or_else(|| data.nodes.iter().map(|n| n.x_dist).min_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal)).unwrap_or(0.0));
-    let max_x = query.max_x.unwrap_or_else(|| data.nodes.iter().map(|n| n.x_dist).max_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal)).unwrap_or(0.0));
-    let x_type = query.x_type.as_deref().unwrap_or("x_dist");
+#[derive(Debug, Deserialize)]
+struct SequenceQuery {
+    id: i32,
+    // "nt" (the default) or "aa"
+    #[serde(rename = "type")]
+    sequence_type: Option<String>,
+    // Just this gene, which "aa" needs
+    gene: Option<String>,
+}
 
-    let query_time = start_time.elapsed() - lock_time;
-    println!("Time to process query parameters: {:?}", query_time);
+#[derive(Debug, Deserialize)]
+struct NodeMutationsQuery {
+    id: i32,
+    // Keep only the latest state at each site, relative to the reference
+    #[serde(default)]
+    collapse: bool,
+}
 
-    println!("min_y: {}, max_y: {}, min_x: {}, max_x: {}", min_y, max_y, min_x, max_x);
+#[derive(Debug, Deserialize)]
+struct TipAttsQuery {
+    id: i32,
+    key: String,
+    // Maximum number of distinct values listed before the rest go to "other"
+    limit: Option<usize>,
+}
 
-    let filter_start = Instant::now();
-    let filtered = filter_nodes(&data.nodes, min_y, max_y);
-    let filter_time = filter_start.elapsed();
-    println!("Time to filter nodes: {:?}", filter_time);
+#[derive(Debug, Deserialize)]
+struct ValuesQuery {
+    key: String,
+    #[serde(default)]
+    counts: bool,
+    // "alpha" (default) or "frequency"
+    sort: Option<String>,
+    limit: Option<usize>,
+    contains: Option<String>,
+}
 
-    let reduce_start = Instant::now();
-    let reduced_leaves = reduce_overplotting(
-        filtered.into_iter().filter(|&idx| data.nodes[idx].num_tips == 1).collect(),
-        get_precision(min_x, max_x),
-        get_precision(min_y, max_y),
-        x_type,
-        &data.nodes,
-    );
-    let reduce_time = reduce_start.elapsed();
-    println!("Time to reduce overplotting: {:?}", reduce_time);
+#[derive(Debug, Deserialize)]
+struct MutationsQuery {
+    gene: Option<String>,
+    min_pos: Option<usize>,
+    max_pos: Option<usize>,
+    // "aa" or "nt"
+    #[serde(rename = "type")]
+    mutation_type: Option<String>,
+    // Comma-separated mutation ids to resolve; the other filters still apply
+    ids: Option<String>,
+}
 
-    let parents_start = Instant::now();
-    let result = add_parents(&data.nodes, &data.child_to_parent, reduced_leaves);
-   
-    let parents_time = parents_start.elapsed();
-    println!("Time to add parents: {:?}", parents_time);
+#[derive(Debug, Deserialize)]
+struct NodeIdsQuery {
+    // Comma-separated node ids
+    ids: String,
+}
 
-    let total_time = start_time.elapsed();
-    println!("Total time for /nodes/ endpoint: {:?}", total_time);
-    // return as real nodes not indexes
-    let result: Vec<Node> = result.iter().map(|&idx| data.nodes[idx].clone()).collect();
-    HttpResponse::Ok().json(NodesResponse { nodes: result })
+#[derive(Debug, Deserialize)]
+struct NearestQuery {
+    x: f64,
+    y: f64,
+    x_type: Option<String>,
+    #[serde(default)]
+    tips_only: bool,
+    // Per-axis multipliers (e.g. pixels per unit at the current zoom) applied before
+    // measuring distance; default to the whole tree fitting in the viewport
+    scale_x: Option<f64>,
+    scale_y: Option<f64>,
 }
 
-fn filter_nodes(nodes: &[Node], min_y: f64, max_y: f64) -> Vec<usize> {
-    nodes.iter()
-        .enumerate()
-        .filter(|(_, n)| n.y >= min_y && n.y <= max_y)
-        .map(|(idx, _)| idx)
-        .collect()
+#[derive(Debug, Deserialize)]
+struct MrcaQuery {
+    // Comma-separated node ids
+    ids: String,
 }
 
-fn get_precision(min: f64, max: f64) -> f64 {
-    2000.0 / (max - min)
+#[derive(Debug, Deserialize)]
+struct PathQuery {
+    from: i32,
+    to: i32,
 }
 
-fn reduce_overplotting(nodes: Vec<usize>, precision_x: f64, precision_y: f64, x_type: &str, all_nodes: &[Node]) -> Vec<usize> {
-    println!("Precision: {}, {}", precision_x, precision_y);
-    println!("Before: {}", nodes.len());
-    let precision_x = precision_x / 5.0;
-    let mut included_points = HashMap::new();
-    let result: Vec<usize> = nodes.into_iter().filter(|&idx| {
-        let node = &all_nodes[idx];
-        let x = if x_type == "x_dist" { node.x_dist } else { node.x_dist };
-        let rounded_x = (x * precision_x).round() as i64;
-        let rounded_y = (node.y * precision_y).round() as i64;
-        included_points
-            .entry(rounded_x)
-            .or_insert_with(HashSet::new)
-            .insert(rounded_y)
-    }).collect();
-    println!("After: {}", result.len());
-    result
+#[derive(Debug, Deserialize)]
+struct NewickQuery {
+    // Defaults to the root of the tree, or of a forest's first tree
+    root: Option<i32>,
+    #[serde(default = "default_true")]
+    include_internal_names: bool,
+    // Refuse subtrees with more tips than this (never more than --max-export-tips)
+    max_tips: Option<usize>,
 }
 
-fn add_parents(all_nodes: &[Node], child_to_parent: &HashMap<i32, i32>, filtered: Vec<usize>) -> Vec<usize> {
-    let start = Instant::now();
-    
-    let mut selected_node_ids: HashSet<i32> = filtered.iter().map(|&idx| all_nodes[idx].node_id).collect();
-    let starting_size = selected_node_ids.len();
+#[derive(Debug, Deserialize)]
+struct MetadataExportQuery {
+    // Export this subtree, or scope the search to it
+    root: Option<i32>,
+    // Search spec selecting the nodes to export
+    json: Option<String>,
+    #[serde(default)]
+    include_internal: bool,
+}
 
-    let setup_time = start.elapsed();
-   
-    let processing_start = Instant::now();
-    let mut to_process: Vec<i32> = selected_node_ids.iter().cloned().collect();
+fn default_true() -> bool {
+    true
+}
 
-    while let Some(node_id) = to_process.pop() {
-        if let Some(&parent_id) = child_to_parent.get(&node_id) {
-            if !selected_node_ids.contains(&parent_id) {
-                selected_node_ids.insert(parent_id);
-                to_process.push(parent_id);
-            }
-        }
-    }
-    let processing_time = processing_start.elapsed();
-   
-    let result_start = Instant::now();
-    let result: Vec<usize> = all_nodes.iter()
-        .enumerate()
-        .filter(|(_, node)| selected_node_ids.contains(&node.node_id))
-        .map(|(idx, _)| idx)
-        .collect();
-    let result_time = result_start.elapsed();
-   
-    println!("Went from {} to {} nodes.", starting_size, result.len());
-    
-    let total_time = start.elapsed();
-    
-    result
+#[derive(Debug, Deserialize)]
+struct ViewportCountsQuery {
+    min_y: Option<f64>,
+    max_y: Option<f64>,
+    min_x: Option<f64>,
+    max_x: Option<f64>,
+    x_type: Option<String>,
+    key: String,
+    // Maximum number of distinct values listed before the rest go to "other"
+    limit: Option<usize>,
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        println!("Usage: {} <path_to_jsonl_file>", args[0]);
-        std::process::exit(1);
-    }
+#[derive(Debug, Deserialize)]
+struct ColorsQuery {
+    key: String,
+    // Also return a suggested hex colour per value
+    #[serde(default)]
+    hex: bool,
+}
 
-    let path = Path::new(&args[1]);
-    
-    let (mut metadata, mut nodes, child_to_parent, root_mutations, root_id) = load_data(path).expect("Failed to load data");
+#[derive(Debug, Deserialize)]
+struct AutocompleteQuery {
+    prefix: String,
+    limit: Option<usize>,
+    #[serde(default)]
+    case_insensitive: bool,
+}
 
-    scale_y_coordinates(&mut nodes);
-    update_config(&mut metadata.config, &nodes, &root_mutations, root_id, metadata.mutations.clone());
-    let app_state = web::Data::new(AppState {
-        nodes,
-        child_to_parent,
-        config: metadata.config,
-        root_mutations,
-        root_id,
-    });
+// A node with the index of its value for the include_color_index key
+#[derive(Debug, Serialize)]
+struct ColoredNode<T> {
+    #[serde(flatten)]
+    node: T,
+    color_index: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct NodesResponse<T = Node> {
+    nodes: Vec<T>,
+    // Requested ids that don't exist, for lookups by id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    not_found: Option<Vec<i32>>,
+    // Set when the result was thinned to --max-nodes-returned
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_count: Option<usize>,
+    // For delta requests: node ids from the previous response that are no longer shown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    removed: Option<Vec<i32>>,
+    // Pass back as since= on the next request to receive only the difference
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delta_token: Option<String>,
+}
 
-    println!("Starting server at http://localhost:8080");
+type LoadedData = (Metadata, Vec<Node>, HashMap<i32, i32>, Vec<i32>);
+
+// The data file's contents, or stdin's for STDIN_PATH, decompressed if gzip, zstd or xz
+fn open_reader(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    if path == Path::new(STDIN_PATH) {
+        return decompress(io::stdin().lock(), None);
+    }
+    if path.to_str().is_some_and(|path| path.starts_with("http://") || path.starts_with("https://")) {
+        let message = "loading from a URL isn't supported; download the file first, or pipe it in with a path of -";
+        return Err(io::Error::new(io::ErrorKind::Unsupported, message));
+    }
+    let extension = path.extension().and_then(|s| s.to_str());
+    decompress(io::BufReader::new(File::open(path)?), extension)
+}
 
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allow_any_method()
-            .allow_any_header()
-            .max_age(3600);
+// The first bytes decide the format, so a misnamed file still loads; the extension only
+// when they match none. Legacy .lzma files have no magic, so go by the extension alone.
+fn decompress(mut reader: impl BufRead + 'static, extension: Option<&str>) -> io::Result<Box<dyn BufRead>> {
+    const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+    const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+    const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
 
-        App::new()
-            .wrap(cors)
-            .app_data(app_state.clone())
-            .service(index)
-            .service(get_node)
-            .service(get_nodes)
-            .service(get_config)
-            .service(search)
+    let start = reader.fill_buf()?;
+    let codec = if start.starts_with(GZIP_MAGIC) {
+        Some("gzip")
+    } else if start.starts_with(ZSTD_MAGIC) {
+        Some("zstd")
+    } else if start.starts_with(XZ_MAGIC) {
+        Some("xz")
+    } else {
+        match extension {
+            Some("gz") => Some("gzip"),
+            Some("zst") => Some("zstd"),
+            Some("xz") => Some("xz"),
+            Some("lzma") => Some("lzma"),
+            _ => None,
+        }
+    };
+    Ok(match codec {
+        Some("gzip") => Box::new(io::BufReader::new(Decompressing { codec: "gzip", inner: GzDecoder::new(reader) })),
+        Some("zstd") => Box::new(io::BufReader::new(Decompressing { codec: "zstd", inner: zstd::Decoder::with_buffer(reader)? })),
+        Some("xz") => Box::new(io::BufReader::new(Decompressing { codec: "xz", inner: xz::XzDecoder::new(reader) })),
+        Some("lzma") => Box::new(io::BufReader::new(Decompressing { codec: "lzma", inner: xz::LzmaDecoder::new(reader) })),
+        _ => Box::new(reader),
     })
-    .bind(("127.0.0.1", 8080))?
-    .disable_signals()
-    .run()
-    .await
-}
\ No newline at end of file
+}
+
+// How a data path is referred to in messages
+fn describe_path(path: &str) -> &str {
+    if path == STDIN_PATH { "stdin" } else { path }
+}
+
+// The nodes read so far, with what is gathered from them on the way
+#[derive(Default)]
+struct NodesRead {
+    // Metadata fields not kept are dropped from each node as it is read
+    key_filter: KeyFilter,
+    // Whether nodes that don't lead to the root are detached rather than failing the load
+    lenient: bool,
+    // Whether every node that is its own parent roots a tree, rather than just the first
+    forest: bool,
+    nodes: Vec<Node>,
+    child_to_parent: HashMap<i32, i32>,
+    // The nodes that are their own parent, in file order
+    root_ids: Vec<i32>,
+}
+
+impl NodesRead {
+    fn new(args: &Args) -> NodesRead {
+        NodesRead { key_filter: args.key_filter.clone(), lenient: args.lenient, forest: args.forest, ..NodesRead::default() }
+    }
+
+    // `progress` counts the nodes read so far, for reporting while the server waits
+    fn push(&mut self, mut node: Node, progress: &AtomicUsize) {
+        if !self.key_filter.is_empty() {
+            node.meta.retain(|key, _| self.key_filter.keeps(key));
+        }
+        if node.parent_id < 0 {
+            node.parent_id = node.node_id;
+        }
+        if node.parent_id == node.node_id {
+            // A root; its mutations stay on the node and are also reported in the config
+            self.root_ids.push(node.node_id);
+        } else {
+            self.child_to_parent.insert(node.node_id, node.parent_id);
+        }
+
+        self.nodes.push(node);
+        progress.store(self.nodes.len(), Ordering::Relaxed);
+        if self.nodes.len().is_multiple_of(LOAD_PROGRESS_INTERVAL) {
+            info!(nodes = self.nodes.len(), "Loading nodes");
+        }
+    }
+
+    // Checks that every node leads to a root, detaching those that don't with --lenient.
+    // Without --forest only the first root is one; the others are reported.
+    fn finish(mut self, metadata: Metadata) -> Result<LoadedData, LoadError> {
+        if self.root_ids.is_empty() {
+            self.infer_roots()?;
+        }
+        if !self.forest {
+            self.root_ids.truncate(1);
+        }
+        let ids: Vec<i32> = self.nodes.iter().map(|n| n.node_id).collect();
+        let (detached, problems) = validate::check_tree(&ids, &self.child_to_parent, &self.root_ids);
+        if !problems.is_empty() {
+            if !self.lenient {
+                return Err(LoadError::MalformedTree(problems));
+            }
+            warn!("The tree is malformed: {}; detaching them", problems);
+            self.nodes.retain(|n| !detached.contains(&n.node_id));
+            self.child_to_parent.retain(|id, _| !detached.contains(id));
+        }
+        Ok((metadata, self.nodes, self.child_to_parent, self.root_ids))
+    }
+
+    // With no node its own parent, the root is the one node whose parent isn't in the
+    // file, which becomes its own parent; with --forest, every such node roots a tree
+    fn infer_roots(&mut self) -> Result<(), LoadError> {
+        let ids: HashSet<i32> = self.nodes.iter().map(|n| n.node_id).collect();
+        let candidates: Vec<usize> = (0..self.nodes.len()).filter(|&idx| !ids.contains(&self.nodes[idx].parent_id)).collect();
+        match candidates.len() {
+            0 => return Err(LoadError::NoRoot),
+            1 => {}
+            _ if self.forest => {}
+            _ => return Err(LoadError::AmbiguousRoot { candidates: candidates.iter().map(|&idx| self.nodes[idx].node_id).collect() }),
+        }
+        for idx in candidates {
+            let root = &mut self.nodes[idx];
+            info!("No node is its own parent; node {}, whose parent {} isn't in the file, is a root", root.node_id, root.parent_id);
+            root.parent_id = root.node_id;
+            self.child_to_parent.remove(&root.node_id);
+            self.root_ids.push(root.node_id);
+        }
+        Ok(())
+    }
+}
+
+// Parses on --threads threads, keeping the nodes in file order. With --lenient,
+// malformed node lines are skipped, up to --lenient-max-skipped of them.
+fn load_data(args: &Args, path: &Path, progress: &AtomicUsize) -> Result<LoadedData, LoadError> {
+    let mut lines = open_reader(path)?.lines();
+
+    // Read the first line separately as metadata
+    let metadata_line = lines.next().ok_or(LoadError::EmptyFile)?.map_err(|e| LoadError::reading(1, e))?;
+    let metadata = parse::metadata(1, &metadata_line)?;
+
+    let mut read = NodesRead::new(args);
+    // The line each node_id was first seen on
+    let mut lines_by_id: HashMap<i32, usize> = HashMap::new();
+    let mut duplicates = 0;
+    let skipped = parse::nodes(lines, 2, thread_count(args), args.lenient, |line, node| {
+        if let Some(&first) = lines_by_id.get(&node.node_id) {
+            // --lenient keeps the first node with the id
+            if !args.lenient {
+                return Err(LoadError::DuplicateNodeId { id: node.node_id, lines: (first, line) });
+            }
+            warn!("Skipping the node on line {}: node_id {} is already used by the node on line {}", line, node.node_id, first);
+            duplicates += 1;
+            return Ok(());
+        }
+        lines_by_id.insert(node.node_id, line);
+        read.push(node, progress);
+        Ok(())
+    })?;
+    let source = describe_path(&path.to_string_lossy()).to_string();
+    if skipped > 0 {
+        let lines = read.nodes.len() + skipped;
+        let fraction = skipped as f64 / lines as f64;
+        warn!(skipped, nodes = read.nodes.len(), "Skipped {:.2}% of the node lines in {} as malformed", fraction * 100.0, source);
+        if fraction > args.lenient_max_skipped {
+            return Err(LoadError::TooManyMalformed { skipped, lines, max_fraction: args.lenient_max_skipped });
+        }
+    }
+    if duplicates > 0 {
+        warn!("Skipped {} nodes in {} whose node_id an earlier node has", duplicates, source);
+    }
+    info!(nodes = read.nodes.len(), "Loaded nodes from {}", source);
+    read.finish(metadata)
+}
+
+// --threads, or one per CPU
+fn thread_count(args: &Args) -> usize {
+    args.threads.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get())).max(1)
+}
+
+// Where --snapshot keeps the snapshot of the data file at `path`, if anywhere
+fn snapshot_path(args: &Args, path: &str) -> Option<PathBuf> {
+    if !args.snapshot || path == STDIN_PATH {
+        return None;
+    }
+    Some(args.snapshot_path.as_ref().map_or_else(|| PathBuf::from(format!("{}.snapshot", path)), PathBuf::from))
+}
+
+// load_data, or with --snapshot, the snapshot made from the data file as it is now.
+// Without a usable snapshot the file is parsed and a new snapshot saved.
+fn load_nodes(args: &Args, path: &str, progress: &AtomicUsize) -> Result<LoadedData, LoadError> {
+    let (Some(snapshot_path), Some(source)) = (snapshot_path(args, path), watch::file_signature(Path::new(path))) else {
+        return load_data(args, Path::new(path), progress);
+    };
+    let start = Instant::now();
+    let mut read = NodesRead::new(args);
+    match snapshot::read(&snapshot_path, source, args, |node| read.push(node, progress)) {
+        Ok(metadata) => {
+            info!(nodes = read.nodes.len(), "Loaded nodes from snapshot {} in {:?}", snapshot_path.display(), start.elapsed());
+            return read.finish(metadata);
+        }
+        Err(snapshot::Unusable::Missing) => info!("No snapshot at {} yet", snapshot_path.display()),
+        Err(snapshot::Unusable::Stale) => info!("Snapshot {} is out of date", snapshot_path.display()),
+        Err(snapshot::Unusable::Corrupt(e)) => warn!("Ignoring unreadable snapshot {}: {}", snapshot_path.display(), e),
+    }
+    // Whatever was read before the snapshot turned out unusable
+    drop(read);
+
+    let loaded = load_data(args, Path::new(path), progress)?;
+    let start = Instant::now();
+    match snapshot::write(&snapshot_path, source, args, &loaded.0, &loaded.1) {
+        Ok(()) => info!("Saved snapshot {} in {:?}", snapshot_path.display(), start.elapsed()),
+        Err(e) => warn!("Failed to save snapshot {}: {}", snapshot_path.display(), e),
+    }
+    Ok(loaded)
+}
+
+fn build_children(nodes: &[Node], child_to_parent: &HashMap<i32, i32>) -> HashMap<i32, Vec<usize>> {
+    let mut children: HashMap<i32, Vec<usize>> = HashMap::new();
+    for (idx, node) in nodes.iter().enumerate() {
+        if let Some(&parent_id) = child_to_parent.get(&node.node_id) {
+            children.entry(parent_id).or_default().push(idx);
+        }
+    }
+    children
+}
+
+// Whether num_tips as the input gave it can't be used: some converters write 0 for every
+// internal node, and a file without the field has 0 for every node
+fn num_tips_missing(nodes: &[Node], children: &HashMap<i32, Vec<usize>>) -> bool {
+    let (internal, tips): (Vec<&Node>, Vec<&Node>) = nodes.iter().partition(|n| children.contains_key(&n.node_id));
+    [internal, tips].iter().any(|group| !group.is_empty() && group.iter().all(|n| n.num_tips <= 0))
+}
+
+// Indexes of the nodes with `root_ids`, in the same order
+fn root_indexes(nodes: &[Node], root_ids: &[i32]) -> Vec<usize> {
+    let roots: HashMap<i32, usize> = nodes.iter()
+        .enumerate()
+        .filter(|(_, n)| n.parent_id == n.node_id)
+        .map(|(idx, n)| (n.node_id, idx))
+        .collect();
+    root_ids.iter().filter_map(|id| roots.get(id).copied()).collect()
+}
+
+// Sets every node's num_tips to the number of tips beneath it, 1 for tips themselves
+fn count_tips(nodes: &mut [Node], children: &HashMap<i32, Vec<usize>>, roots: &[usize]) {
+    let mut order = Vec::with_capacity(nodes.len());
+    let mut stack = roots.to_vec();
+    while let Some(idx) = stack.pop() {
+        order.push(idx);
+        stack.extend(children.get(&nodes[idx].node_id).into_iter().flatten());
+    }
+    // Children come before their parents in reverse pre-order
+    for &idx in order.iter().rev() {
+        nodes[idx].num_tips = match children.get(&nodes[idx].node_id) {
+            Some(node_children) => node_children.iter().map(|&child| nodes[child].num_tips).sum(),
+            None => 1,
+        };
+    }
+}
+
+// Returns the (enter, exit) interval of every node and the node indexes in pre-order,
+// one tree after another in the order of `roots`.
+fn compute_dfs_intervals(nodes: &[Node], children: &HashMap<i32, Vec<usize>>, roots: &[usize]) -> (Vec<(usize, usize)>, Vec<usize>) {
+    // Nodes not reachable from a root are never descendants of anything
+    let mut intervals = vec![(usize::MAX, usize::MAX); nodes.len()];
+    let mut order = Vec::with_capacity(nodes.len());
+
+    let mut counter = 0;
+    let mut stack: Vec<(usize, bool)> = roots.iter().rev().map(|&root| (root, false)).collect();
+    while let Some((idx, visited)) = stack.pop() {
+        if visited {
+            intervals[idx].1 = counter - 1;
+            continue;
+        }
+        intervals[idx].0 = counter;
+        order.push(idx);
+        counter += 1;
+        stack.push((idx, true));
+        if let Some(node_children) = children.get(&nodes[idx].node_id) {
+            stack.extend(node_children.iter().map(|&child| (child, false)));
+        }
+    }
+    (intervals, order)
+}
+
+// Whether the input's y can't be used, as in bare trees where every node has the same
+// y or none
+fn y_missing(nodes: &[Node]) -> bool {
+    nodes.len() > 1 && nodes.iter().all(|n| n.y == nodes[0].y)
+}
+
+// Lays the tree out afresh: tips take consecutive y in a pre-order traversal that visits
+// smaller subtrees first, ties in file order, and internal nodes sit at the mean y of
+// their children. The trees of a forest are stacked in the order of `roots`.
+fn layout_y(nodes: &mut [Node], child_to_parent: &HashMap<i32, i32>, roots: &[usize]) {
+    let mut children = build_children(nodes, child_to_parent);
+    let pre_order = |children: &HashMap<i32, Vec<usize>>| {
+        let mut order = Vec::with_capacity(nodes.len());
+        let mut stack: Vec<usize> = roots.iter().rev().copied().collect();
+        while let Some(idx) = stack.pop() {
+            order.push(idx);
+            // Reversed, so the first child is visited first
+            stack.extend(children.get(&nodes[idx].node_id).into_iter().flatten().rev());
+        }
+        order
+    };
+    let mut tips = vec![1usize; nodes.len()];
+    for &idx in pre_order(&children).iter().rev() {
+        if let Some(node_children) = children.get(&nodes[idx].node_id) {
+            tips[idx] = node_children.iter().map(|&child| tips[child]).sum();
+        }
+    }
+    for node_children in children.values_mut() {
+        node_children.sort_by_key(|&child| (tips[child], child));
+    }
+
+    let order = pre_order(&children);
+    let mut next_y = 0.0;
+    for &idx in &order {
+        if !children.contains_key(&nodes[idx].node_id) {
+            nodes[idx].y = next_y;
+            next_y += 1.0;
+        }
+    }
+    for &idx in order.iter().rev() {
+        if let Some(node_children) = children.get(&nodes[idx].node_id) {
+            nodes[idx].y = node_children.iter().map(|&child| nodes[child].y).sum::<f64>() / node_children.len() as f64;
+        }
+    }
+}
+
+// Rescales y for display and returns the factor applied
+fn scale_y_coordinates(nodes: &mut [Node]) -> f64 {
+    let num_nodes = nodes.len();
+    let scale_y = 24e2 / if num_nodes > 10000 { num_nodes as f64 } else { num_nodes as f64 * 0.6666 };
+    
+    for node in nodes.iter_mut() {
+        node.y = (node.y * scale_y * 1e6).round() / 1e6;  // Round to 6 decimal places
+    }
+    scale_y
+}
+
+impl Extremes {
+    fn compute(nodes: &[Node]) -> Extremes {
+        fn bounds(values: impl Iterator<Item = f64>) -> Option<(f64, f64)> {
+            values.filter(|v| !v.is_nan()).fold(None, |bounds, v| match bounds {
+                Some((min, max)) => Some((v.min(min), v.max(max))),
+                None => Some((v, v)),
+            })
+        }
+        let (min_y, max_y) = bounds(nodes.iter().map(|n| n.y)).unwrap_or((0.0, 0.0));
+        let (min_x_dist, max_x_dist) = bounds(nodes.iter().map(|n| n.x_dist)).unwrap_or((0.0, 0.0));
+        let x_time = bounds(nodes.iter().filter_map(|n| n.x_time));
+        Extremes {
+            min_y,
+            max_y,
+            min_x_dist,
+            max_x_dist,
+            min_x_time: x_time.map(|(min, _)| min),
+            max_x_time: x_time.map(|(_, max)| max),
+        }
+    }
+
+    // (min_y, max_y, min_x, max_x) with x in the given type
+    fn bounds(&self, x_type: XType) -> (f64, f64, f64, f64) {
+        let (min_x, max_x) = match x_type {
+            XType::Dist => (self.min_x_dist, self.max_x_dist),
+            XType::Time => (self.min_x_time.unwrap_or(0.0), self.max_x_time.unwrap_or(0.0)),
+        };
+        (self.min_y, self.max_y, min_x, max_x)
+    }
+}
+
+// Infers each metadata field's type from its values, and converts numbers and booleans
+// given as strings to JSON ones so they're served as such, where that keeps the text
+// exactly: "1.10" stays a string in a number field, as does "007". A field is only typed
+// a number or bool if every value is one; empty values don't count either way and are
+// left as they are.
+fn type_metadata(nodes: &mut [Node]) -> BTreeMap<String, MetaType> {
+    fn value_type(value: &Value) -> Option<MetaType> {
+        match value {
+            Value::Null => None,
+            Value::String(s) if s.is_empty() => None,
+            Value::Number(_) => Some(MetaType::Number),
+            Value::Bool(_) => Some(MetaType::Bool),
+            Value::String(s) if s == "true" || s == "false" => Some(MetaType::Bool),
+            Value::String(s) if serde_json::from_str::<serde_json::Number>(s).is_ok() => Some(MetaType::Number),
+            _ => Some(MetaType::Categorical),
+        }
+    }
+
+    // None while only empty values have been seen
+    let mut types: BTreeMap<String, Option<MetaType>> = BTreeMap::new();
+    for node in nodes.iter() {
+        for (key, value) in &node.meta {
+            let seen = types.entry(key.clone()).or_default();
+            *seen = match (*seen, value_type(value)) {
+                (seen, None) => seen,
+                (None, this) => this,
+                (Some(seen), Some(this)) if seen == this => Some(seen),
+                _ => Some(MetaType::Categorical),
+            };
+        }
+    }
+    let types: BTreeMap<String, MetaType> = types.into_iter()
+        .map(|(key, seen)| (key, seen.unwrap_or(MetaType::Categorical)))
+        .collect();
+
+    for node in nodes.iter_mut() {
+        for (key, value) in node.meta.iter_mut() {
+            let Value::String(s) = value else {
+                continue;
+            };
+            match types[key] {
+                MetaType::Number => {
+                    if let Some(number) = json_number(s) {
+                        *value = Value::Number(number);
+                    }
+                }
+                MetaType::Bool if !s.is_empty() => *value = Value::Bool(s == "true"),
+                _ => {}
+            }
+        }
+    }
+    types
+}
+
+fn multi_valued_keys(nodes: &[Node]) -> Vec<String> {
+    nodes.iter()
+        .flat_map(|n| n.meta.iter().filter(|(_, value)| value.is_array()).map(|(key, _)| key))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .cloned()
+        .collect()
+}
+
+// A string holding a number exactly as it is written back out, so converting it loses
+// nothing; not a zero-padded identifier like "007", nor "1.10", "1e5", " 7", "-0" or an
+// integer too long to be held exactly
+fn json_number(s: &str) -> Option<serde_json::Number> {
+    serde_json::from_str::<serde_json::Number>(s).ok().filter(|number| number.to_string() == s)
+}
+
+fn update_config(config: &mut Config, nodes: &[Node], extremes: Extremes, roots: &[usize], mutations: Vec<Mutation>) {
+    let (min_y, max_y, min_x, max_x) = extremes.bounds(XType::Dist);
+    config.extremes = Some(extremes);
+    config.x_time_available = nodes.iter().any(|n| n.x_time.is_some());
+    config.initial_x = Some((max_x + min_x) / 2.0);
+    config.initial_y = Some((max_y + min_y) / 2.0);
+    config.initial_zoom = Some(config.initial_zoom.unwrap_or(-2.0));
+    config.num_nodes = Some(nodes.len());
+    config.root_mutations = roots.first().map(|&root| nodes[root].mutations.clone());
+    config.root_id = roots.first().map(|&root| nodes[root].node_id);
+    config.root_ids = Some(roots.iter().map(|&root| nodes[root].node_id).collect());
+    config.root_mutations_by_id = Some(roots.iter().map(|&root| (nodes[root].node_id, nodes[root].mutations.clone())).collect());
+    config.num_trees = Some(roots.len());
+    config.mutations = mutations;
+    config.keys_to_display = Some(vec!["name".to_string(), "num_tips".to_string()]);
+}
+
+// The config never changes once loaded, so its body is serialized and compressed at startup
+#[get("/config/")]
+async fn get_config(req: HttpRequest, Snapshot(data): Snapshot) -> impl Responder {
+    if etag::not_modified(req.headers(), &data.config_etag) {
+        return etag::not_modified_response(&data.config_etag);
+    }
+    let (encoding, body) = data.config_body.select(&data.compression, req.headers());
+    let mut response = HttpResponse::Ok();
+    response.content_type("application/json").insert_header((header::ETAG, data.config_etag.as_str()));
+    if let Some(encoding) = encoding {
+        response.insert_header((header::CONTENT_ENCODING, encoding));
+    }
+    response.body(body)
+}
+
+#[get("/node/{node_id}")]
+async fn get_node(Snapshot(data): Snapshot, node_id: web::Path<i32>) -> Result<impl Responder> {
+    if let Some(node) = data.node(*node_id) {
+        Ok(web::Json(node.clone()))
+    } else {
+        Err(ApiError::not_found("Node not found").into())
+    }
+}
+
+// Everything we know about one node, with its mutations resolved from the dictionary
+#[get("/node_details/")]
+async fn get_node_details(Snapshot(data): Snapshot, query: web::Query<NodeDetailsQuery>) -> Result<HttpResponse, ApiError> {
+    let Some(node) = data.node(query.id) else {
+        return Err(ApiError::not_found(format!("Node {} not found", query.id)));
+    };
+
+    let mutations: Vec<&Mutation> = node.mutations.iter().filter_map(|&id| data.mutation(id)).collect();
+    let mut details = serde_json::to_value(node).map_err(|e| ApiError::internal("Failed to serialize the node", e))?;
+    if let Value::Object(fields) = &mut details {
+        fields.insert("mutations".to_string(), json!(mutations));
+    }
+    Ok(HttpResponse::Ok().json(details))
+}
+
+// The full set of mutations from the root to a node, split into AA and NT
+#[get("/node_mutations/")]
+async fn get_node_mutations(Snapshot(data): Snapshot, query: web::Query<NodeMutationsQuery>) -> Result<HttpResponse, ApiError> {
+    if data.node(query.id).is_none() {
+        return Err(ApiError::not_found(format!("Node {} not found", query.id)));
+    }
+
+    let mut mutations: Vec<Mutation> = data.ancestry(query.id)
+        .into_iter()
+        .rev()
+        .flat_map(|node| &node.mutations)
+        .filter_map(|&id| data.mutation(id).cloned())
+        .collect();
+
+    if query.collapse {
+        mutations = collapse_mutations(mutations);
+    }
+
+    let (aa, nt): (Vec<Mutation>, Vec<Mutation>) = mutations.into_iter().partition(|m| matches!(m, Mutation::AA { .. }));
+    Ok(HttpResponse::Ok().json(json!({
+        "node_id": query.id,
+        "aa_mutations": aa,
+        "nt_mutations": nt
+    })))
+}
+
+// Collapses root-to-node mutations so each site appears once, going from the residue
+// the first mutation replaced to the residue the last one introduced. Sites that end
+// up back where they started are dropped.
+fn collapse_mutations(mutations: Vec<Mutation>) -> Vec<Mutation> {
+    let mut sites: HashMap<(String, usize), Mutation> = HashMap::new();
+    for mutation in mutations {
+        let site = (mutation.gene().to_string(), mutation.residue_pos());
+        let collapsed = match sites.remove(&site) {
+            Some(first) => mutation.with_previous_residue(first.previous_residue()),
+            None => mutation,
+        };
+        sites.insert(site, collapsed);
+    }
+
+    let mut result: Vec<Mutation> = sites.into_values()
+        .filter(|m| m.previous_residue() != m.new_residue())
+        .collect();
+    result.sort_by(|a, b| (a.gene(), a.residue_pos()).cmp(&(b.gene(), b.residue_pos())));
+    result
+}
+
+// The attribution for one node from --acknowledgements, null if it has none
+#[get("/acknowledgements/")]
+async fn get_acknowledgements(Snapshot(data): Snapshot, query: web::Query<NodeDetailsQuery>) -> Result<HttpResponse, ApiError> {
+    let Some(acknowledgements) = &data.acknowledgements else {
+        return Err(ApiError::not_found("No acknowledgements are loaded (start the server with --acknowledgements)"));
+    };
+    let Some(&idx) = data.node_index.get(&query.id) else {
+        return Err(ApiError::not_found(format!("Node {} not found", query.id)));
+    };
+    Ok(HttpResponse::Ok().json(json!({ "node_id": query.id, "acknowledgement": acknowledgements.for_node(idx) })))
+}
+
+// The distinct attributions of the tips beneath a node, each with how many it covers
+#[get("/acknowledgements/subtree/")]
+async fn get_subtree_acknowledgements(Snapshot(data): Snapshot, query: web::Query<NodeDetailsQuery>) -> Result<HttpResponse, ApiError> {
+    let Some(acknowledgements) = &data.acknowledgements else {
+        return Err(ApiError::not_found("No acknowledgements are loaded (start the server with --acknowledgements)"));
+    };
+    let Some(&idx) = data.node_index.get(&query.id) else {
+        return Err(ApiError::not_found(format!("Node {} not found", query.id)));
+    };
+    let tips = data.tips_beneath(&[idx]);
+    Ok(HttpResponse::Ok().json(json!({
+        "node_id": query.id,
+        "tips": tips.len(),
+        "acknowledgements": acknowledgements.for_nodes(tips.into_iter()),
+    })))
+}
+
+// A node's sequence as FASTA, rebuilt by applying the mutations from the root down to it
+// to the --reference: the genome, or with a gene its coding sequence or protein
+#[get("/sequence/")]
+async fn get_sequence(Snapshot(data): Snapshot, query: web::Query<SequenceQuery>) -> Result<HttpResponse> {
+    let Some(reference) = &data.reference else {
+        return Err(ApiError::not_found("No reference is loaded (start the server with --reference)").into());
+    };
+    let Some(node) = data.node(query.id) else {
+        return Err(ApiError::not_found(format!("Node {} not found", query.id)).into());
+    };
+    let is_aa = match query.sequence_type.as_deref() {
+        None | Some("nt") => false,
+        Some("aa") => true,
+        Some(other) => return Err(ApiError::invalid_parameter("type", format!("Unknown sequence type: {}", other)).into()),
+    };
+    let gene = match &query.gene {
+        Some(name) => Some(data.config.gene_details.get(name).ok_or_else(|| ApiError::not_found(format!("Unknown gene: {}", name)))?),
+        None => None,
+    };
+
+    let path: Vec<&Mutation> = data.ancestry(query.id)
+        .into_iter()
+        .rev()
+        .flat_map(|node| &node.mutations)
+        .filter_map(|&id| data.mutation(id))
+        .collect();
+    let sequence = match gene {
+        None if is_aa => return Err(ApiError::invalid_parameter("gene", "type=aa needs a gene").into()),
+        None => reference.genome(&path),
+        Some(gene) if is_aa => reference.protein(gene, &path),
+        Some(gene) => reference.coding(gene, &path),
+    };
+    let mut body = format!(">{}", if node.name.is_empty() { format!("node_{}", node.node_id) } else { node.name.clone() });
+    if let Some(gene) = gene {
+        body.push(' ');
+        body.push_str(&gene.name);
+    }
+    body.push('\n');
+    body.push_str(&String::from_utf8_lossy(&sequence));
+    body.push('\n');
+    Ok(HttpResponse::Ok().content_type("text/x-fasta").body(body))
+}
+
+// Distribution of a metadata field among the tips beneath a node
+#[get("/tip_atts/")]
+async fn get_tip_atts(Snapshot(data): Snapshot, query: web::Query<TipAttsQuery>) -> Result<HttpResponse, ApiError> {
+    let Some(&idx) = data.node_index.get(&query.id) else {
+        return Err(ApiError::not_found(format!("Node {} not found", query.id)));
+    };
+
+    let field = search::meta_field_name(&query.key);
+    let tips = data.tips_beneath(&[idx]);
+    let mut counts: HashMap<Option<String>, usize> = HashMap::new();
+    for &tip in &tips {
+        let value = data.nodes[tip].meta.get(field.as_ref()).map(|v| search::meta_value_string(v).into_owned());
+        *counts.entry(value).or_default() += 1;
+    }
+
+    let mut counts: Vec<(Option<String>, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let limit = query.limit.unwrap_or(20);
+    let other: usize = counts.iter().skip(limit).map(|(_, count)| count).sum();
+    let values: Vec<Value> = counts.into_iter()
+        .take(limit)
+        .map(|(value, count)| json!({ "value": value, "count": count }))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "node_id": query.id,
+        "key": query.key,
+        "total_tips": tips.len(),
+        "values": values,
+        "other": other
+    })))
+}
+
+// Subset of the mutation dictionary, filtered by gene, position and type or resolved from ids
+#[get("/mutations/")]
+async fn get_mutations(Snapshot(data): Snapshot, query: web::Query<MutationsQuery>) -> Result<HttpResponse> {
+    let is_aa = match query.mutation_type.as_deref() {
+        None => None,
+        Some("aa") => Some(true),
+        Some("nt") => Some(false),
+        Some(other) => return Err(ApiError::bad_request(format!("Unknown mutation type: {}", other)).into()),
+    };
+    let matches = |m: &Mutation| {
+        query.gene.as_ref().is_none_or(|gene| m.gene() == gene)
+            && query.min_pos.is_none_or(|min| m.residue_pos() >= min)
+            && query.max_pos.is_none_or(|max| m.residue_pos() <= max)
+            && is_aa.is_none_or(|is_aa| matches!(m, Mutation::AA { .. }) == is_aa)
+    };
+
+    let Some(ids) = &query.ids else {
+        let mutations: Vec<&Mutation> = data.config.mutations.iter().filter(|m| matches(m)).collect();
+        return Ok(HttpResponse::Ok().json(json!({ "mutations": mutations })));
+    };
+
+    let mut mutations = Vec::new();
+    let mut not_found = Vec::new();
+    for id in parse_ids(ids)? {
+        match data.mutation(id) {
+            Some(mutation) if matches(mutation) => mutations.push(mutation),
+            Some(_) => {}
+            None => not_found.push(id),
+        }
+    }
+    Ok(HttpResponse::Ok().json(json!({ "mutations": mutations, "not_found": not_found })))
+}
+
+// Index -> value table for the color_index values /nodes/ emits
+#[get("/colors/")]
+async fn get_colors(Snapshot(data): Snapshot, query: web::Query<ColorsQuery>) -> Result<HttpResponse, ApiError> {
+    let field = search::meta_field_name(&query.key);
+    if !data.metadata_keys.iter().any(|k| k == field.as_ref()) {
+        return Err(ApiError::not_found(format!("Unknown metadata key: {}", query.key)));
+    }
+
+    let table = colors::color_table(&data, &field);
+    let mut response = json!({ "key": query.key, "values": table.values });
+    if query.hex {
+        response["colors"] = table.values.iter().map(|value| json!(colors::value_color(value))).collect();
+    }
+    Ok(HttpResponse::Ok().json(response))
+}
+
+// Distinct values of a metadata field, for populating dropdowns
+#[get("/values/")]
+async fn get_values(Snapshot(data): Snapshot, query: web::Query<ValuesQuery>) -> Result<HttpResponse> {
+    let field = search::meta_field_name(&query.key);
+    if !data.metadata_keys.iter().any(|k| k == field.as_ref()) {
+        return Err(ApiError::not_found(format!("Unknown metadata key: {}", query.key)).into());
+    }
+
+    let mut values: Vec<(String, usize)> = search::value_counts(&data, &field)
+        .into_iter()
+        .filter(|(value, _)| query.contains.as_ref().is_none_or(|c| value.contains(c.as_str())))
+        .collect();
+    match query.sort.as_deref().unwrap_or("alpha") {
+        "alpha" => values.sort(),
+        "frequency" => values.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))),
+        other => return Err(ApiError::bad_request(format!("Unknown sort: {}", other)).into()),
+    }
+
+    let total_values = values.len();
+    values.truncate(query.limit.unwrap_or(usize::MAX));
+    let values: Vec<Value> = if query.counts {
+        values.into_iter().map(|(value, count)| json!({ "value": value, "count": count })).collect()
+    } else {
+        values.into_iter().map(|(value, _)| json!(value)).collect()
+    };
+
+    Ok(HttpResponse::Ok().json(json!({
+        "key": query.key,
+        "total_values": total_values,
+        "values": values
+    })))
+}
+
+// Distribution of a metadata field among the tips in a viewport, for dynamic legends
+#[get("/viewport_counts/")]
+async fn get_viewport_counts(Snapshot(data): Snapshot, query: web::Query<ViewportCountsQuery>) -> Result<HttpResponse> {
+    let start_time = Instant::now();
+    let x_type = XType::parse(query.x_type.as_deref(), &data.config).map_err(ApiError::bad_request)?;
+    let field = search::meta_field_name(&query.key);
+    if !data.metadata_keys.iter().any(|k| k == field.as_ref()) {
+        return Err(ApiError::not_found(format!("Unknown metadata key: {}", query.key)).into());
+    }
+
+    let (default_min_y, default_max_y, default_min_x, default_max_x) = data.extremes.bounds(x_type);
+    let filtered = filter_nodes(
+        &data,
+        query.min_y.unwrap_or(default_min_y),
+        query.max_y.unwrap_or(default_max_y),
+        query.min_x.unwrap_or(default_min_x),
+        query.max_x.unwrap_or(default_max_x),
+        x_type,
+        &Deadline::none(),
+    );
+
+    let mut counts: HashMap<Option<Cow<str>>, usize> = HashMap::new();
+    let mut total_tips = 0;
+    for idx in filtered.into_iter().filter(|&idx| data.is_tip(idx)) {
+        let value = data.nodes[idx].meta.get(field.as_ref()).map(search::meta_value_string);
+        *counts.entry(value).or_default() += 1;
+        total_tips += 1;
+    }
+
+    let mut counts: Vec<(Option<Cow<str>>, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let limit = query.limit.unwrap_or(20);
+    let other: usize = counts.iter().skip(limit).map(|(_, count)| count).sum();
+    let values: Vec<Value> = counts.into_iter()
+        .take(limit)
+        .map(|(value, count)| json!({ "value": value, "count": count }))
+        .collect();
+
+    debug!("Viewport counts for {} over {} tips in {:?}", query.key, total_tips, start_time.elapsed());
+    Ok(HttpResponse::Ok().json(json!({
+        "key": query.key,
+        "total_tips": total_tips,
+        "values": values,
+        "other": other
+    })))
+}
+
+// Parses a comma-separated list of node or mutation ids
+fn parse_ids(ids: &str) -> Result<Vec<i32>> {
+    ids.split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse().map_err(|_| ApiError::bad_request(format!("Invalid id: {}", id)).into()))
+        .collect()
+}
+
+// The inverse of parse_ids, for naming ids in errors
+fn join_ids(ids: &[i32]) -> String {
+    ids.iter().map(i32::to_string).collect::<Vec<_>>().join(",")
+}
+
+fn nodes_by_ids(data: &AppState, ids: &[i32]) -> NodesResponse {
+    let mut nodes = Vec::with_capacity(ids.len());
+    let mut not_found = Vec::new();
+    for &id in ids {
+        match data.node(id) {
+            Some(node) => nodes.push(node.clone()),
+            None => not_found.push(id),
+        }
+    }
+    NodesResponse { nodes, not_found: Some(not_found), truncated: false, original_count: None, removed: None, delta_token: None }
+}
+
+// Current representation of specific nodes, e.g. ones remembered from earlier searches
+#[get("/nodes/ids/", wrap = "from_fn(ratelimit::limit)")]
+async fn get_nodes_by_ids(Snapshot(data): Snapshot, query: web::Query<NodeIdsQuery>) -> Result<HttpResponse> {
+    let ids = parse_ids(&query.ids)?;
+    Ok(HttpResponse::Ok().json(nodes_by_ids(&data, &ids)))
+}
+
+// As GET /nodes/ids/, for lists too long for a query string: a JSON array, or ids
+// separated by commas or whitespace
+#[post("/nodes/ids/", wrap = "from_fn(ratelimit::limit)")]
+async fn post_nodes_by_ids(Snapshot(data): Snapshot, body: String) -> Result<HttpResponse> {
+    let ids: Vec<i32> = if body.trim_start().starts_with('[') {
+        serde_json::from_str(&body)
+            .map_err(|e| ApiError::bad_request(format!("Invalid id list: {}", e)))?
+    } else {
+        parse_ids(&body.split_whitespace().collect::<Vec<_>>().join(","))?
+    };
+    Ok(HttpResponse::Ok().json(nodes_by_ids(&data, &ids)))
+}
+
+// The node closest to a point, for click handling
+#[get("/nearest/")]
+async fn get_nearest(Snapshot(data): Snapshot, query: web::Query<NearestQuery>) -> Result<HttpResponse> {
+    let x_type = XType::parse(query.x_type.as_deref(), &data.config).map_err(ApiError::bad_request)?;
+    let (scale_x, scale_y) = match (query.scale_x, query.scale_y) {
+        (Some(scale_x), Some(scale_y)) => (scale_x, scale_y),
+        (scale_x, scale_y) => {
+            let (min_y, max_y, min_x, max_x) = data.extremes.bounds(x_type);
+            (scale_x.unwrap_or_else(|| get_precision(min_x, max_x)), scale_y.unwrap_or_else(|| get_precision(min_y, max_y)))
+        }
+    };
+    if !(scale_x.is_finite() && scale_x > 0.0 && scale_y.is_finite() && scale_y > 0.0) {
+        return Err(ApiError::bad_request("scale_x and scale_y must be positive").into());
+    }
+
+    let spatial_index = match x_type {
+        XType::Dist => &data.spatial_index,
+        XType::Time => data.spatial_time_index.as_ref().unwrap_or(&data.spatial_index),
+    };
+    let nearest = spatial_index.nearest(
+        &data.nodes,
+        |n| (x_type.x(n), n.y),
+        (query.x, query.y),
+        (scale_x, scale_y),
+        |idx| !query.tips_only || data.is_tip(idx),
+    );
+    let Some((idx, distance)) = nearest else {
+        return Err(ApiError::not_found("No nodes found").into());
+    };
+    Ok(HttpResponse::Ok().json(json!({ "node": data.nodes[idx], "distance": distance })))
+}
+
+// Most recent common ancestor of a set of nodes; unknown ids are reported and ignored
+#[get("/mrca/")]
+async fn get_mrca(Snapshot(data): Snapshot, query: web::Query<MrcaQuery>) -> Result<HttpResponse> {
+    let ids = parse_ids(&query.ids)?;
+    let (found, not_found): (Vec<i32>, Vec<i32>) = ids.into_iter().partition(|id| data.node_index.contains_key(id));
+    let indexes: Vec<usize> = found.iter().map(|id| data.node_index[id]).collect();
+
+    let Some(mrca) = data.mrca(&indexes) else {
+        let error = ApiError::not_found("No common ancestor found");
+        return Err(match not_found.is_empty() {
+            true => error,
+            false => error.with_detail(format!("Unknown node ids: {}", join_ids(&not_found))),
+        }.into());
+    };
+
+    let node = &data.nodes[mrca];
+    Ok(HttpResponse::Ok().json(json!({
+        "node_id": node.node_id,
+        "x_dist": node.x_dist,
+        "y": node.y,
+        "num_tips": node.num_tips,
+        "not_found": not_found
+    })))
+}
+
+// Route between two nodes through their MRCA. Each edge carries the mutations on the
+// child's branch: reverted when climbing towards the MRCA, gained when descending.
+#[get("/path/")]
+async fn get_path(Snapshot(data): Snapshot, query: web::Query<PathQuery>) -> Result<HttpResponse, ApiError> {
+    let not_found: Vec<i32> = [query.from, query.to].into_iter().filter(|id| !data.node_index.contains_key(id)).collect();
+    if !not_found.is_empty() {
+        return Err(ApiError::not_found("Node not found").with_detail(format!("Unknown node ids: {}", join_ids(&not_found))));
+    }
+    let Some(mrca) = data.mrca(&[data.node_index[&query.from], data.node_index[&query.to]]) else {
+        return Err(ApiError::not_found("No common ancestor found"));
+    };
+    let mrca_id = data.nodes[mrca].node_id;
+
+    let leg = |node_id: i32| -> Vec<&Node> {
+        let ancestry = data.ancestry(node_id);
+        let end = ancestry.iter().position(|n| n.node_id == mrca_id).unwrap_or(ancestry.len() - 1);
+        ancestry[..=end].to_vec()
+    };
+    let up = leg(query.from);
+    let down: Vec<&Node> = leg(query.to).into_iter().rev().collect();
+
+    let mut edges = Vec::new();
+    let mut mutation_distance = 0;
+    let mut edge = |child: &Node, from: i32, to: i32, direction: &str| {
+        let mutations: Vec<&Mutation> = child.mutations.iter().filter_map(|&id| data.mutation(id)).collect();
+        mutation_distance += mutations.len();
+        edges.push(json!({ "from": from, "to": to, "direction": direction, "mutations": mutations }));
+    };
+    for pair in up.windows(2) {
+        edge(pair[0], pair[0].node_id, pair[1].node_id, "up");
+    }
+    for pair in down.windows(2) {
+        edge(pair[1], pair[0].node_id, pair[1].node_id, "down");
+    }
+
+    let path: Vec<i32> = up.iter().chain(down.iter().skip(1)).map(|n| n.node_id).collect();
+    Ok(HttpResponse::Ok().json(json!({
+        "from": query.from,
+        "to": query.to,
+        "mrca": mrca_id,
+        "path": path,
+        "edges": edges,
+        "mutation_distance": mutation_distance
+    })))
+}
+
+// The subtree beneath a node as Auspice v2 JSON, for opening in Nextstrain
+#[get("/nextstrain_json/{node_id}", wrap = "from_fn(ratelimit::limit)")]
+async fn get_nextstrain_json(Snapshot(data): Snapshot, node_id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let Some(&idx) = data.node_index.get(&node_id).filter(|&&idx| data.dfs_intervals[idx].0 != usize::MAX) else {
+        return Err(ApiError::not_found(format!("Node {} not found", node_id)));
+    };
+    let num_tips = data.nodes[idx].num_tips.max(0) as usize;
+    if num_tips > data.max_export_tips {
+        return Err(ApiError::payload_too_large(format!("Subtree has {} tips; at most {} can be exported", num_tips, data.max_export_tips)));
+    }
+    Ok(HttpResponse::Ok().json(export::nextstrain_json(&data, idx)))
+}
+
+// The subtree beneath a node as Newick, streamed in chunks
+#[get("/newick/", wrap = "from_fn(ratelimit::limit)")]
+async fn get_newick(Snapshot(data): Snapshot, query: web::Query<NewickQuery>) -> Result<HttpResponse, ApiError> {
+    let root = query.root.unwrap_or(data.root_id);
+    let Some(&idx) = data.node_index.get(&root).filter(|&&idx| data.dfs_intervals[idx].0 != usize::MAX) else {
+        return Err(ApiError::not_found(format!("Node {} not found", root)));
+    };
+    let max_tips = query.max_tips.unwrap_or(usize::MAX).min(data.max_export_tips);
+    let num_tips = data.nodes[idx].num_tips.max(0) as usize;
+    if num_tips > max_tips {
+        return Err(ApiError::payload_too_large(format!("Subtree has {} tips; at most {} can be exported", num_tips, max_tips)));
+    }
+
+    let chunks = export::NewickChunks::new(data.clone(), idx, query.include_internal_names);
+    Ok(HttpResponse::Ok()
+        .content_type("text/x-newick")
+        .streaming(futures_util::stream::iter(chunks)))
+}
+
+// Metadata for a subtree or a search's results as a TSV download
+#[get("/export/metadata.tsv", wrap = "from_fn(ratelimit::limit)")]
+async fn get_metadata_tsv(Snapshot(data): Snapshot, query: web::Query<MetadataExportQuery>) -> Result<HttpResponse> {
+    let root_idx = match query.root {
+        Some(root) => match data.node_index.get(&root).filter(|&&idx| data.dfs_intervals[idx].0 != usize::MAX) {
+            Some(&idx) => Some(idx),
+            None => return Err(ApiError::not_found(format!("Node {} not found", root)).into()),
+        },
+        None => None,
+    };
+
+    let mut indexes = match &query.json {
+        Some(json) => {
+            let mut request: SearchRequest = serde_json::from_str(json)
+                .map_err(|e| ApiError::invalid_parameter("json", format!("Invalid search spec: {}", e)))?;
+            request.spec.validate().map_err(|e| ApiError::invalid_parameter("json", format!("Invalid search spec: {}", e)))?;
+            request.root_node_id = request.root_node_id.or(query.root);
+            let (deadline, _cancel_on_drop) = Deadline::new(data.request_timeout);
+            search::run_search_request(&data, &request, &deadline)?
+        }
+        // Every tree, without a root
+        None => match root_idx {
+            Some(idx) => {
+                let (enter, exit) = data.dfs_intervals[idx];
+                data.dfs_order[enter..=exit].to_vec()
+            }
+            None => data.dfs_order.clone(),
+        },
+    };
+    if !query.include_internal {
+        indexes.retain(|&idx| data.is_tip(idx));
+    }
+
+    let chunks = export::TsvChunks::new(data.clone(), indexes);
+    Ok(HttpResponse::Ok()
+        .content_type("text/tab-separated-values; charset=utf-8")
+        .insert_header((header::CONTENT_DISPOSITION, "attachment; filename=\"metadata.tsv\""))
+        .streaming(futures_util::stream::iter(chunks)))
+}
+
+// Low-resolution sketch of the entire tree, independent of the viewport
+#[get("/minimap/")]
+async fn get_minimap(req: HttpRequest, Snapshot(data): Snapshot) -> impl Responder {
+    if etag::not_modified(req.headers(), &data.minimap_etag) {
+        return etag::not_modified_response(&data.minimap_etag);
+    }
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header((header::ETAG, data.minimap_etag.as_str()))
+        .body(data.minimap.clone())
+}
+
+// A summary of the dataset and server, as JSON or, for browsers, as an HTML page
+#[get("/")]
+async fn index(req: HttpRequest, Snapshot(data): Snapshot, dataset: web::Data<Dataset>, catalog: web::Data<Catalog>) -> HttpResponse {
+    let status: serde_json::Map<String, Value> = [
+        ("name", json!(dataset.name)),
+        ("path", json!(dataset.path)),
+        ("nodes", json!(data.nodes.len())),
+        ("tips", json!(data.tips)),
+        ("metadata_keys", json!(data.metadata_keys)),
+        ("loaded_at", json!(logging::timestamp(data.loaded_at))),
+        ("load_seconds", json!(data.load_time.as_secs_f64())),
+        ("version", json!(env!("CARGO_PKG_VERSION"))),
+        ("uptime_seconds", json!(catalog.started.elapsed().as_secs())),
+    ].into_iter().map(|(key, value)| (key.to_string(), value)).collect();
+    let accepts_html = req.headers().get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    if !accepts_html {
+        return HttpResponse::Ok().json(status);
+    }
+
+    let mut rows = String::new();
+    for (key, value) in &status {
+        let value = match value {
+            Value::String(s) => s.clone(),
+            Value::Array(items) => items.iter().filter_map(Value::as_str).collect::<Vec<_>>().join(", "),
+            other => other.to_string(),
+        };
+        rows.push_str(&format!("<tr><th>{}</th><td>{}</td></tr>\n", key, escape_html(&value)));
+    }
+    let title = format!("jsonl_processor {}", env!("CARGO_PKG_VERSION"));
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title></head>\n<body><h1>{title}</h1>\n<table>\n{rows}</table></body></html>\n"
+        ))
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// Search hits, kept as node indexes until they are serialized
+enum SearchHits {
+    // Full nodes, with their edit distance for fuzzy name searches
+    Full { matches: Vec<usize>, fuzzy_query: Option<String> },
+    // Compact hits of a summarized result
+    Summary(Vec<usize>),
+}
+
+impl SearchHits {
+    fn len(&self) -> usize {
+        match self {
+            SearchHits::Full { matches, .. } | SearchHits::Summary(matches) => matches.len(),
+        }
+    }
+
+    fn write_hit(&self, data: &AppState, i: usize, format: Format, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        match self {
+            SearchHits::Full { matches, fuzzy_query } => {
+                let node = &data.nodes[matches[i]];
+                match fuzzy_query {
+                    Some(query) => format.write(out, &FuzzyHit { node, distance: fuzzy::levenshtein(query.as_bytes(), node.name.as_bytes()) }),
+                    None => format.write(out, node),
+                }
+            }
+            SearchHits::Summary(matches) => {
+                let node = &data.nodes[matches[i]];
+                let hit = SearchHit { node_id: node.node_id, x_dist: node.x_dist, x_time: node.x_time, y: node.y, num_tips: node.num_tips };
+                format.write(out, &hit)
+            }
+        }
+    }
+}
+
+// A search response: the hits go in "data" alongside the other fields
+struct SearchResult {
+    fields: Value,
+    hits: Option<SearchHits>,
+}
+
+impl SearchResult {
+    // The whole response as one document, for background jobs
+    fn into_value(self, data: &AppState) -> Value {
+        let mut document = self.fields;
+        if let Some(hits) = &self.hits {
+            let items: Vec<Value> = (0..hits.len())
+                .map(|i| {
+                    let mut out = Vec::new();
+                    hits.write_hit(data, i, Format::Json, &mut out).ok();
+                    serde_json::from_slice(&out).unwrap_or(Value::Null)
+                })
+                .collect();
+            document["data"] = json!(items);
+        }
+        document
+    }
+}
+
+fn run_search_query(data: &AppState, query: &SearchQuery, deadline: &Deadline) -> Result<SearchResult, ApiError> {
+    let start_time = Instant::now();
+
+    let request = query.request().map_err(ApiError::bad_request)?;
+
+    if query.count_only {
+        let total_count = search::count_search_request(data, &request, deadline)?;
+        debug!("Count for {:?} found {} nodes in {:?}", request, total_count, start_time.elapsed());
+        return Ok(SearchResult { fields: json!({ "total_count": total_count }), hits: None });
+    }
+
+    let matches = search::run_search_request(data, &request, deadline)?;
+    let total_count = matches.len();
+    let threshold = query.threshold.unwrap_or(DEFAULT_SEARCH_THRESHOLD);
+    let fuzzy_query = search::fuzzy_query(&request.spec).map(str::to_string);
+
+    debug!("Search for {:?} matched {} nodes in {:?}", request, total_count, start_time.elapsed());
+
+    if query.offset.is_some() || query.limit.is_some() {
+        let offset = query.offset.unwrap_or(0);
+        let limit = query.limit.unwrap_or(data.max_search_limit).min(data.max_search_limit);
+        let page = matches.get(offset..).unwrap_or_default();
+        let page = page[..limit.min(page.len())].to_vec();
+        return Ok(SearchResult {
+            fields: json!({
+                "type": "complete",
+                "data": [],
+                "total_count": total_count,
+                "summarized": false,
+                "offset": offset,
+                "limit": limit
+            }),
+            hits: Some(SearchHits::Full { matches: page, fuzzy_query }),
+        });
+    }
+
+    if total_count <= threshold {
+        return Ok(SearchResult {
+            fields: json!({
+                "type": "complete",
+                "data": [],
+                "total_count": total_count,
+                "summarized": false
+            }),
+            hits: Some(SearchHits::Full { matches, fuzzy_query }),
+        });
+    }
+
+    // Too many hits to send individually: thin them at the current viewport precision
+    let x_type = XType::parse(query.x_type.as_deref(), &data.config).map_err(ApiError::bad_request)?;
+    let (default_min_y, default_max_y, default_min_x, default_max_x) = data.extremes.bounds(x_type);
+    let min_y = query.min_y.unwrap_or(default_min_y);
+    let max_y = query.max_y.unwrap_or(default_max_y);
+    let min_x = query.min_x.unwrap_or(default_min_x);
+    let max_x = query.max_x.unwrap_or(default_max_x);
+
+    let reduced = reduce_overplotting(
+        matches,
+        get_precision(min_x, max_x),
+        get_precision(min_y, max_y),
+        x_type,
+        &data.nodes,
+        data.threads,
+    );
+
+    Ok(SearchResult {
+        fields: json!({
+            "type": "filtered",
+            "data": [],
+            "total_count": total_count,
+            "summarized": true
+        }),
+        hits: Some(SearchHits::Summary(reduced)),
+    })
+}
+
+#[get("/search/", wrap = "from_fn(ratelimit::limit)")]
+async fn get_search(Snapshot(data): Snapshot, query: web::Query<SearchQuery>, req: HttpRequest) -> Result<impl Responder> {
+    query.validate(&data.config)?;
+    let format = Format::from_request(query.format.as_deref(), req.headers()).map_err(|e| ApiError::invalid_parameter("format", e))?;
+    let permit = data.heavy_work.try_acquire()?;
+    let search_data = data.clone();
+    let span = logging::request_span(&req);
+    let (deadline, _cancel_on_drop) = Deadline::new(data.request_timeout);
+    let result = web::block(move || span.in_scope(|| run_search_query(&search_data, &query, &deadline))).await.map_err(ApiError::from)??;
+    let Some(hits) = result.hits else {
+        let body = format.to_vec(&result.fields).map_err(|e| ApiError::internal("Failed to serialize search results", e))?;
+        return Ok(HttpResponse::Ok().content_type(format.content_type()).body(body));
+    };
+
+    let hits_data = data.clone();
+    let chunks = ArrayChunks::new(format, &result.fields, "data", hits.len(), move |i, out| hits.write_hit(&hits_data, i, format, out));
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .streaming(offload::stream(chunks, permit, logging::request_span(&req))))
+}
+
+// Starts a background search; the spec comes from the query string or, failing that, the body.
+fn start_search_job(data: web::Data<AppState>, mut query: SearchQuery, body: String) -> Result<HttpResponse> {
+    if query.json.is_none() && !body.trim().is_empty() {
+        query.json = Some(body);
+    }
+    // Reject malformed specs up front rather than in the job
+    query.validate(&data.config)?;
+
+    let Some(job_id) = data.search_jobs.start() else {
+        return Err(ApiError::too_many_requests("Too many search jobs running, try again later").into());
+    };
+    actix_web::rt::task::spawn_blocking(move || {
+        let result = run_search_query(&data, &query, &Deadline::none())
+            .map(|result| result.into_value(&data))
+            .map_err(|e| e.to_string());
+        data.search_jobs.finish(job_id, result);
+    });
+    Ok(HttpResponse::Accepted().json(json!({ "job_id": job_id.to_string(), "status": "pending" })))
+}
+
+#[get("/search/status/{job_id}")]
+async fn get_search_status(Snapshot(data): Snapshot, job_id: web::Path<u64>) -> Result<HttpResponse, ApiError> {
+    match data.search_jobs.status(*job_id) {
+        Some(status) => Ok(HttpResponse::Ok().json(status)),
+        None => Err(ApiError::not_found("Unknown or expired search job")),
+    }
+}
+
+// Looks up a list of exact names, sent either as a JSON array or one name per line.
+// With async=true the request instead starts a background search.
+#[post("/search/", wrap = "from_fn(ratelimit::limit)")]
+async fn post_search(Snapshot(data): Snapshot, query: web::Query<SearchQuery>, body: String) -> Result<HttpResponse> {
+    if query.run_async {
+        return start_search_job(data, query.into_inner(), body);
+    }
+
+    let start_time = Instant::now();
+
+    let names: Vec<String> = if body.trim_start().starts_with('[') {
+        serde_json::from_str(&body)
+            .map_err(|e| ApiError::bad_request(format!("Invalid name list: {}", e)))?
+    } else {
+        body.lines().map(|l| l.trim().to_string()).filter(|l| !l.is_empty()).collect()
+    };
+
+    let (matches, not_found) = search::search_by_names(&data.name_index, &names);
+    let result: Vec<Node> = matches.iter().map(|&idx| data.nodes[idx].clone()).collect();
+
+    debug!("Lookup of {} names matched {} nodes in {:?}", names.len(), result.len(), start_time.elapsed());
+
+    Ok(HttpResponse::Ok().json(json!({
+        "type": "complete",
+        "data": result,
+        "total_count": result.len(),
+        "not_found": not_found
+    })))
+}
+
+#[get("/autocomplete/")]
+async fn get_autocomplete(Snapshot(data): Snapshot, query: web::Query<AutocompleteQuery>) -> Result<impl Responder> {
+    if query.case_insensitive && !data.prefix_index.supports_case_insensitive() {
+        return Err(ApiError::bad_request(
+            "Case-insensitive autocomplete is not enabled (start the server with --autocomplete-case-insensitive)",
+        ).into());
+    }
+    let limit = query.limit.unwrap_or(20).min(1000);
+    let names = data.prefix_index.complete(&data.nodes, &query.prefix, limit, query.case_insensitive);
+    Ok(HttpResponse::Ok().json(json!({ "prefix": query.prefix, "names": names })))
+}
+
+// The nodes in a viewport, ordered by node_id. Each node's meta fields come in
+// metadata_keys order, i.e. alphabetically, unless fields= lists them in its own.
+#[get("/nodes/", wrap = "from_fn(ratelimit::limit)")]
+async fn get_nodes(
+    Snapshot(data): Snapshot,
+    query: web::Query<NodesQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    let start_time = Instant::now();
+
+    // Identical requests against the same load of the data give identical responses
+    let tag = etag::query_tag(data.loaded_at, req.query_string(), req.headers());
+    if etag::not_modified(req.headers(), &tag) {
+        return Ok(etag::not_modified_response(&tag));
+    }
+
+    query.validate()?;
+    let y_factor = match query.y_space.as_deref().unwrap_or("scaled") {
+        "scaled" => 1.0,
+        "raw" => data.config.y_scale.unwrap_or(1.0),
+        other => return Err(ApiError::invalid_parameter("y_space", format!("Unknown y_space: {}", other)).into()),
+    };
+    let x_type = XType::parse(query.x_type.as_deref(), &data.config).map_err(|e| ApiError::invalid_parameter("x_type", e))?;
+    let (default_min_y, default_max_y, default_min_x, default_max_x) = data.extremes.bounds(x_type);
+    let min_y = query.min_y.map_or(default_min_y, |y| y * y_factor);
+    let max_y = query.max_y.map_or(default_max_y, |y| y * y_factor);
+    let selection = NodeSelection {
+        x_type,
+        node_types: NodeTypes::parse(query.node_types.as_deref()).map_err(|e| ApiError::invalid_parameter("node_types", e))?,
+        filter: metadata_filters(req.query_string())?,
+        reduce: query.reduce,
+        precision_x: query.precision_x,
+        precision_y: query.precision_y,
+    };
+    let fields = query.fields.as_deref()
+        .map(|fields| NodeField::parse_list(fields, &data.metadata_keys))
+        .transpose()
+        .map_err(|e| ApiError::invalid_parameter("fields", e))?;
+    let previous = query.since.as_deref().map(parse_delta_token).transpose()?;
+    let accepts_csv = req.headers().get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/csv"));
+    let csv = query.format.as_deref().map_or(accepts_csv, |format| format == "csv");
+    let format = match csv {
+        true => Format::Json,
+        false => Format::from_request(query.format.as_deref(), req.headers()).map_err(|e| ApiError::invalid_parameter("format", e))?,
+    };
+    let color_field = query.include_color_index.as_deref().map(search::meta_field_name);
+    if let Some(field) = &color_field {
+        if !data.metadata_keys.iter().any(|k| k == field.as_ref()) {
+            let key = query.include_color_index.as_deref().unwrap_or_default();
+            return Err(ApiError::invalid_parameter("include_color_index", format!("Unknown metadata key: {}", key)).into());
+        }
+    }
+    let color_table = color_field.as_ref().map(|field| colors::color_table(&data, field));
+    let min_x = query.min_x.unwrap_or(default_min_x);
+    let max_x = query.max_x.unwrap_or(default_max_x);
+    // With the cache on, nearby viewports snap to the same bounds so they can share a response
+    let (min_x, max_x, min_y, max_y) = if data.nodes_cache.enabled() {
+        let (min_x, max_x) = quantize_range(min_x, max_x);
+        let (min_y, max_y) = quantize_range(min_y, max_y);
+        (min_x, max_x, min_y, max_y)
+    } else {
+        (min_x, max_x, min_y, max_y)
+    };
+    let viewport = (min_x, max_x, min_y, max_y);
+
+    debug!(min_x, max_x, min_y, max_y, "Viewport");
+
+    if let Some(mode) = query.mode.as_deref().filter(|&mode| mode != "nodes") {
+        if mode != "density" {
+            return Err(ApiError::invalid_parameter("mode", format!("Unknown mode: {}", mode)).into());
+        }
+        let _permit = data.heavy_work.try_acquire()?;
+        let query = query.into_inner();
+        let density_data = data.clone();
+        let span = logging::request_span(&req);
+        let (deadline, _cancel_on_drop) = Deadline::new(data.request_timeout);
+        let body = web::block(move || {
+            let _entered = span.enter();
+            let filtered = filter_nodes(&density_data, min_y, max_y, min_x, max_x, x_type, &deadline);
+            deadline.check()?;
+            density(&density_data, &query, filtered, viewport, x_type).map_err(ApiError::bad_request)
+        }).await.map_err(ApiError::from)??;
+        let mut response = HttpResponse::Ok().json(body);
+        if let Ok(tag) = header::HeaderValue::from_str(&tag) {
+            response.headers_mut().insert(header::ETAG, tag);
+        }
+        return Ok(response);
+    }
+
+    let cache_key = data.nodes_cache.enabled().then(|| nodes_cache_key(viewport, req.query_string(), req.headers()));
+    if let Some((content_type, body)) = cache_key.as_deref().and_then(|key| data.nodes_cache.get(key)) {
+        debug!("Served /nodes/ from cache in {:?} ({} bytes)", start_time.elapsed(), body.len());
+        return Ok(HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header((header::ETAG, tag))
+            .insert_header(("cache", "HIT"))
+            .body(body));
+    }
+
+    let permit = data.heavy_work.try_acquire()?;
+    let selection_data = data.clone();
+    let span = logging::request_span(&req);
+    let (deadline, _cancel_on_drop) = Deadline::new(data.request_timeout);
+    let (result, original_count, removed) = web::block(move || -> Result<_, ApiError> {
+        let _entered = span.enter();
+        let data = selection_data;
+        let (mut result, original_count) = select_nodes(&data, &selection, viewport, &deadline)?;
+
+        // With a previous viewport, send only what the client doesn't already have
+        let mut removed = None;
+        if let Some(previous) = previous {
+            let delta = debug_span!("delta", new_nodes = tracing::field::Empty).entered();
+            let (previous_result, _) = select_nodes(&data, &selection, previous, &deadline)?;
+            let current: HashSet<usize> = result.iter().copied().collect();
+            let previous_set: HashSet<usize> = previous_result.iter().copied().collect();
+            removed = Some(previous_result.iter()
+                .filter(|idx| !current.contains(idx))
+                .map(|&idx| data.nodes[idx].node_id)
+                .collect::<Vec<i32>>());
+            result.retain(|idx| !previous_set.contains(idx));
+            delta.record("new_nodes", result.len());
+        }
+        Ok((result, original_count, removed))
+    }).await.map_err(ApiError::from)??;
+    let truncated = original_count.is_some();
+    let delta_token = (query.delta || previous.is_some()).then(|| format!("{},{},{},{}", min_x, max_x, min_y, max_y));
+
+    let (content_type, chunks): (&'static str, Box<dyn Iterator<Item = Result<web::Bytes, actix_web::Error>> + Send>) = if csv {
+        ("text/csv; charset=utf-8", Box::new(export::CsvChunks::new(data.clone(), result)))
+    } else {
+        // return as real nodes not indexes, serialized as the body streams
+        let frame = NodesResponse::<()> { nodes: Vec::new(), not_found: None, truncated, original_count, removed, delta_token };
+        let color = color_table.zip(color_field.map(Cow::into_owned));
+        let nodes_data = data.clone();
+        (format.content_type(), Box::new(ArrayChunks::new(format, &frame, "nodes", result.len(), move |i, out| {
+            let node = &nodes_data.nodes[result[i]];
+            let color_index = color.as_ref().map(|(table, field)| table.index(node, field));
+            match (&fields, color_index) {
+                (Some(fields), Some(color_index)) => format.write(out, &ColoredNode { node: SelectedFields { node, fields }, color_index }),
+                (Some(fields), None) => format.write(out, &SelectedFields { node, fields }),
+                (None, Some(color_index)) => format.write(out, &ColoredNode { node, color_index }),
+                (None, None) => format.write(out, node),
+            }
+        })))
+    };
+
+    let mut response = HttpResponse::Ok();
+    response.content_type(content_type).insert_header((header::ETAG, tag));
+    Ok(match cache_key {
+        Some(key) => response
+            .insert_header(("cache", "MISS"))
+            .streaming(offload::stream(CachingChunks::new(chunks, data.clone(), key, content_type), permit, logging::request_span(&req))),
+        None => response.streaming(offload::stream(chunks, permit, logging::request_span(&req))),
+    })
+}
+
+// Widens a range outward to multiples of a power of two around 1/64 of its span, so
+// ranges that differ by a small pan or zoom come out the same
+fn quantize_range(min: f64, max: f64) -> (f64, f64) {
+    if !(min.is_finite() && max.is_finite() && max > min) {
+        return (min, max);
+    }
+    let step = 2f64.powi(((max - min) / 64.0).log2().floor() as i32);
+    ((min / step).floor() * step, (max / step).ceil() * step)
+}
+
+// Cache key for a /nodes/ response: the snapped viewport, every other parameter in
+// sorted order, and the Accept header, which can pick the format
+fn nodes_cache_key(viewport: Viewport, query_string: &str, headers: &header::HeaderMap) -> String {
+    let mut params: Vec<&str> = query_string.split('&')
+        .filter(|param| !param.is_empty())
+        .filter(|param| !matches!(param.split('=').next(), Some("min_x" | "max_x" | "min_y" | "max_y")))
+        .collect();
+    params.sort_unstable();
+    let accept = headers.get(header::ACCEPT).and_then(|accept| accept.to_str().ok()).unwrap_or_default();
+    format!("{:?}|{}|{}", viewport, params.join("&"), accept)
+}
+
+// Everything other than the viewport bounds that decides which nodes /nodes/ returns
+struct NodeSelection {
+    x_type: XType,
+    node_types: NodeTypes,
+    filter: Option<SearchSpec>,
+    reduce: bool,
+    precision_x: Option<f64>,
+    precision_y: Option<f64>,
+}
+
+// Viewport bounds as (min_x, max_x, min_y, max_y)
+type Viewport = (f64, f64, f64, f64);
+
+// The /nodes/ pipeline: filter to the viewport, thin, restore ancestors and cap. Returns
+// node indexes ordered by node_id, plus the pre-cap count if the result was truncated.
+fn select_nodes(
+    data: &AppState,
+    selection: &NodeSelection,
+    (min_x, max_x, min_y, max_y): Viewport,
+    deadline: &Deadline,
+) -> Result<(Vec<usize>, Option<usize>), ApiError> {
+    let span = debug_span!("filter", in_viewport = tracing::field::Empty).entered();
+    let filtered = filter_nodes(data, min_y, max_y, min_x, max_x, selection.x_type, deadline);
+    span.record("in_viewport", filtered.len());
+    drop(span);
+    deadline.check()?;
+
+    let node_types = selection.node_types;
+    let mut candidates: Vec<usize> = filtered.into_iter().filter(|&idx| node_types.is_candidate(data, idx)).collect();
+    if let Some(filter) = &selection.filter {
+        // Filter before thinning so the points kept are matching ones
+        let mut matching = vec![false; data.nodes.len()];
+        for idx in search::run_search(data, filter, deadline)? {
+            matching[idx] = true;
+        }
+        candidates.retain(|&idx| matching[idx]);
+        debug!("Metadata filter kept {} nodes", candidates.len());
+    }
+    let reduced = if selection.reduce {
+        reduce_overplotting(
+            candidates,
+            selection.precision_x.unwrap_or_else(|| get_precision(min_x, max_x)),
+            selection.precision_y.unwrap_or_else(|| get_precision(min_y, max_y)),
+            selection.x_type,
+            &data.nodes,
+            data.threads,
+        )
+    } else {
+        candidates
+    };
+    deadline.check()?;
+
+    let result = if node_types.adds_parents() {
+        add_parents(&data.nodes, &data.node_index, &data.child_to_parent, reduced)
+    } else {
+        reduced
+    };
+
+    deadline.check()?;
+
+    let original_count = result.len();
+    let truncated = original_count > data.max_nodes_returned;
+    let mut result = if truncated {
+        let _span = debug_span!("truncate", from = original_count, to = data.max_nodes_returned).entered();
+        truncate_nodes(&data.nodes, &data.node_index, &data.child_to_parent, result, data.max_nodes_returned)
+    } else {
+        result
+    };
+    // Responses are ordered by node_id so identical requests give identical bodies
+    result.sort_unstable_by_key(|&idx| data.nodes[idx].node_id);
+    Ok((result, truncated.then_some(original_count)))
+}
+
+// A delta token is the previous response's viewport, "min_x,max_x,min_y,max_y"
+fn parse_delta_token(token: &str) -> Result<Viewport> {
+    let invalid = || ApiError::invalid_parameter("since", format!("Invalid delta token: {}", token));
+    let bounds: Vec<f64> = token.split(',').map(|v| v.trim().parse()).collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    match bounds[..] {
+        [min_x, max_x, min_y, max_y] if bounds.iter().all(|v| v.is_finite()) && min_x <= max_x && min_y <= max_y => {
+            Ok((min_x, max_x, min_y, max_y))
+        }
+        _ => Err(invalid().into()),
+    }
+}
+
+// Indexes of the nodes in the viewport, in index order. Nodes are sorted by y, so the
+// y range is a slice; when the viewport is a narrow x slice of it, the spatial grid
+// finds the nodes with far fewer visits.
+fn filter_nodes(data: &AppState, min_y: f64, max_y: f64, min_x: f64, max_x: f64, x_type: XType, deadline: &Deadline) -> Vec<usize> {
+    let nodes = &data.nodes;
+    // Widen the x range slightly so nodes just off-screen are already loaded when panning
+    let margin = (max_x - min_x) * VIEWPORT_X_MARGIN;
+    let (min_x, max_x) = (min_x - margin, max_x + margin);
+    let in_x_range = |&idx: &usize| {
+        let x = x_type.x(&nodes[idx]);
+        x >= min_x && x <= max_x
+    };
+
+    let start = nodes.partition_point(|n| n.y < min_y);
+    let end = start + nodes[start..].partition_point(|n| n.y <= max_y);
+    let grid = match x_type {
+        XType::Dist => Some(&data.spatial_index),
+        XType::Time => data.spatial_time_index.as_ref(),
+    };
+    if let Some(grid) = grid.filter(|grid| grid.candidate_count(min_x, max_x, min_y, max_y) < (end - start) / 4) {
+        let mut found: Vec<usize> = deadline.watch(grid.candidates(min_x, max_x, min_y, max_y))
+            .filter(|idx| (start..end).contains(idx) && in_x_range(idx))
+            .collect();
+        found.sort_unstable();
+        return found;
+    }
+    deadline.watch(start..end).filter(in_x_range).collect()
+}
+
+fn get_precision(min: f64, max: f64) -> f64 {
+    2000.0 / (max - min)
+}
+
+// Keeps the first node in each cell of a grid at the given precision, in input order.
+// Cells are computed in parallel chunks, then split between threads by hash so each
+// thread can find the first node in its own cells independently.
+fn reduce_overplotting(nodes: Vec<usize>, precision_x: f64, precision_y: f64, x_type: XType, all_nodes: &[Node], threads: usize) -> Vec<usize> {
+    let threads = threads.clamp(1, nodes.len() / MIN_NODES_PER_THREAD + 1);
+    let span = debug_span!("reduce", precision_x, precision_y, threads, before = nodes.len(), after = tracing::field::Empty).entered();
+    let precision_x = precision_x / 5.0;
+    let cell = |idx: usize| {
+        let node = &all_nodes[idx];
+        let x = x_type.x(node);
+        if x.is_nan() {
+            return None;
+        }
+        Some(((x * precision_x).round() as i64, (node.y * precision_y).round() as i64))
+    };
+
+    let result: Vec<usize> = if threads == 1 {
+        let mut included_points = HashSet::new();
+        nodes.into_iter().filter(|&idx| cell(idx).is_some_and(|c| included_points.insert(c))).collect()
+    } else {
+        let mut cells = vec![None; nodes.len()];
+        let chunk_size = nodes.len().div_ceil(threads);
+        thread::scope(|scope| {
+            for (chunk, out) in nodes.chunks(chunk_size).zip(cells.chunks_mut(chunk_size)) {
+                scope.spawn(move || {
+                    for (&idx, c) in chunk.iter().zip(out) {
+                        *c = cell(idx);
+                    }
+                });
+            }
+        });
+        let cells = &cells;
+        let mut kept: Vec<usize> = thread::scope(|scope| {
+            let shards: Vec<_> = (0..threads).map(|shard| scope.spawn(move || {
+                let mut included_points = HashSet::new();
+                cells.iter().enumerate()
+                    .filter(|(_, c)| c.is_some_and(|c| cell_shard(c, threads) == shard && included_points.insert(c)))
+                    .map(|(position, _)| position)
+                    .collect::<Vec<usize>>()
+            })).collect();
+            shards.into_iter().flat_map(|shard| shard.join().expect("reduce_overplotting thread panicked")).collect()
+        });
+        kept.sort_unstable();
+        kept.into_iter().map(|position| nodes[position]).collect()
+    };
+    span.record("after", result.len());
+    result
+}
+
+fn cell_shard((x, y): (i64, i64), shards: usize) -> usize {
+    let mixed = (x as u64).wrapping_mul(0x9e3779b97f4a7c15) ^ (y as u64).wrapping_mul(0xc2b2ae3d27d4eb4f);
+    ((mixed >> 32) % shards as u64) as usize
+}
+
+// Adds every ancestor of the given nodes; the result is in index order
+fn add_parents(all_nodes: &[Node], node_index: &HashMap<i32, usize>, child_to_parent: &HashMap<i32, i32>, filtered: Vec<usize>) -> Vec<usize> {
+    let span = debug_span!("parents", before = filtered.len(), after = tracing::field::Empty).entered();
+    let mut selected_node_ids: HashSet<i32> = filtered.iter().map(|&idx| all_nodes[idx].node_id).collect();
+
+    let mut to_process: Vec<i32> = selected_node_ids.iter().cloned().collect();
+
+    while let Some(node_id) = to_process.pop() {
+        if let Some(&parent_id) = child_to_parent.get(&node_id) {
+            if !selected_node_ids.contains(&parent_id) {
+                selected_node_ids.insert(parent_id);
+                to_process.push(parent_id);
+            }
+        }
+    }
+
+    let mut result: Vec<usize> = selected_node_ids.iter().filter_map(|node_id| node_index.get(node_id).copied()).collect();
+    result.sort_unstable();
+
+    span.record("after", result.len());
+
+    result
+}
+
+// Tip counts binned over the viewport, optionally split by a metadata field. Counts
+// are row-major: bin (x, y) is at y * bins_x + x.
+fn density(
+    data: &AppState,
+    query: &NodesQuery,
+    filtered: Vec<usize>,
+    (min_x, max_x, min_y, max_y): (f64, f64, f64, f64),
+    x_type: XType,
+) -> Result<Value, String> {
+    let start_time = Instant::now();
+    let bins_x = query.bins_x.unwrap_or(200);
+    let bins_y = query.bins_y.unwrap_or(400);
+    if bins_x == 0 || bins_y == 0 || bins_x.saturating_mul(bins_y) > MAX_DENSITY_BINS {
+        return Err(format!("bins_x * bins_y must be between 1 and {}", MAX_DENSITY_BINS));
+    }
+    if !(max_x > min_x && max_y > min_y) {
+        return Err("The viewport must have a positive width and height".to_string());
+    }
+    let field = query.density_key.as_deref().map(search::meta_field_name);
+
+    let bin = |node: &Node| -> Option<usize> {
+        let x = (x_type.x(node) - min_x) / (max_x - min_x);
+        let y = (node.y - min_y) / (max_y - min_y);
+        // Points exactly on the upper bound belong to the last bin
+        let to_bin = |f: f64, bins: usize| (0.0..=1.0).contains(&f).then(|| ((f * bins as f64) as usize).min(bins - 1));
+        Some(to_bin(y, bins_y)? * bins_x + to_bin(x, bins_x)?)
+    };
+
+    let mut total = vec![0u32; bins_x * bins_y];
+    let mut by_value: HashMap<Option<Cow<str>>, Vec<u32>> = HashMap::new();
+    let mut total_tips = 0;
+    for idx in filtered.into_iter().filter(|&idx| data.is_tip(idx)) {
+        let node = &data.nodes[idx];
+        let Some(bin) = bin(node) else { continue };
+        total[bin] += 1;
+        total_tips += 1;
+        if let Some(field) = &field {
+            let value = node.meta.get(field.as_ref()).map(search::meta_value_string);
+            by_value.entry(value).or_insert_with(|| vec![0; bins_x * bins_y])[bin] += 1;
+        }
+    }
+
+    let mut response = json!({
+        "mode": "density",
+        "bins_x": bins_x,
+        "bins_y": bins_y,
+        "min_x": min_x,
+        "max_x": max_x,
+        "min_y": min_y,
+        "max_y": max_y,
+        "total_tips": total_tips,
+        "counts": total
+    });
+    if field.is_some() {
+        let mut by_value: Vec<(Option<Cow<str>>, Vec<u32>)> = by_value.into_iter().collect();
+        by_value.sort_by(|a, b| a.0.cmp(&b.0));
+        response["values"] = by_value.into_iter()
+            .map(|(value, counts)| json!({ "value": value, "counts": counts }))
+            .collect();
+    }
+    debug!("Density of {} tips over {}x{} bins in {:?}", total_tips, bins_x, bins_y, start_time.elapsed());
+    Ok(response)
+}
+
+// A coarse, fixed sample of the whole tree for the minimap, serialized up front
+fn build_minimap(nodes: &[Node], extremes: Extremes, node_index: &HashMap<i32, usize>, child_to_parent: &HashMap<i32, i32>, threads: usize) -> web::Bytes {
+    let (min_y, max_y, min_x, max_x) = extremes.bounds(XType::Dist);
+    let leaves: Vec<usize> = (0..nodes.len()).filter(|&idx| nodes[idx].num_tips == 1).collect();
+    let reduced = reduce_overplotting(
+        leaves,
+        MINIMAP_RESOLUTION / (max_x - min_x),
+        MINIMAP_RESOLUTION / (max_y - min_y),
+        XType::Dist,
+        nodes,
+        threads,
+    );
+    let fields = [NodeField::NodeId, NodeField::ParentId, NodeField::XDist, NodeField::Y, NodeField::NumTips];
+    let sample: Vec<SelectedFields> = add_parents(nodes, node_index, child_to_parent, reduced)
+        .into_iter()
+        .map(|idx| SelectedFields { node: &nodes[idx], fields: &fields })
+        .collect();
+
+    let minimap = json!({
+        "nodes": sample,
+        "extremes": { "min_x": min_x, "max_x": max_x, "min_y": min_y, "max_y": max_y }
+    });
+    web::Bytes::from(serde_json::to_vec(&minimap).unwrap_or_default())
+}
+
+// Thins a node set to at most `cap` nodes, keeping those with the most tips beneath
+// them together with their ancestors so the skeleton stays connected. A node whose
+// ancestors don't all fit is passed over for smaller ones that still might.
+fn truncate_nodes(
+    nodes: &[Node],
+    node_index: &HashMap<i32, usize>,
+    child_to_parent: &HashMap<i32, i32>,
+    indexes: Vec<usize>,
+    cap: usize,
+) -> Vec<usize> {
+    let mut by_size = indexes;
+    by_size.sort_unstable_by(|&a, &b| {
+        nodes[b].num_tips.cmp(&nodes[a].num_tips).then(nodes[a].node_id.cmp(&nodes[b].node_id))
+    });
+
+    let mut included: HashSet<usize> = HashSet::with_capacity(cap);
+    let mut chain = Vec::new();
+    for idx in by_size {
+        if included.len() == cap {
+            break;
+        }
+        chain.clear();
+        let mut current = Some(idx);
+        while let Some(node_idx) = current.filter(|node_idx| !included.contains(node_idx)) {
+            chain.push(node_idx);
+            current = child_to_parent.get(&nodes[node_idx].node_id)
+                .and_then(|parent_id| node_index.get(parent_id))
+                .copied()
+                .filter(|&parent_idx| parent_idx != node_idx);
+        }
+        if included.len() + chain.len() > cap {
+            continue;
+        }
+        included.extend(chain.iter().copied());
+    }
+
+    let mut result: Vec<usize> = included.into_iter().collect();
+    result.sort_unstable();
+    result
+}
+
+// Re-reads the data file and swaps it in once it is fully built. Requests keep being
+// served from the old dataset meanwhile, and any already running finish against it.
+#[post("/admin/reload/")]
+async fn post_reload(dataset: web::Data<Dataset>, req: HttpRequest) -> Result<HttpResponse> {
+    dataset.check_admin_token(&req)?;
+    if dataset.path == STDIN_PATH {
+        return Err(ApiError::bad_request("A dataset read from stdin can't be reloaded").into());
+    }
+    let Some(_reloading) = dataset.try_begin_reload() else {
+        return Err(ApiError::conflict("A reload is already in progress").into());
+    };
+
+    let reload_dataset = dataset.clone();
+    let span = logging::request_span(&req);
+    let report = web::block(move || span.in_scope(|| reload_dataset.reload()))
+        .await
+        .map_err(ApiError::from)?
+        .map_err(|e| ApiError::internal("Failed to reload the dataset", e))?;
+    Ok(HttpResponse::Ok().json(json!({
+        "old_nodes": report.old_nodes,
+        "new_nodes": report.new_nodes,
+        "load_seconds": report.elapsed.as_secs_f64(),
+    })))
+}
+
+#[get("/status/")]
+async fn get_status(dataset: web::Data<Dataset>) -> impl Responder {
+    HttpResponse::Ok().json(dataset.status())
+}
+
+// Liveness: answers as soon as the server is listening, whether or not anything has loaded
+#[get("/health")]
+async fn get_health(catalog: web::Data<Catalog>) -> impl Responder {
+    HttpResponse::Ok().json(json!({ "status": "ok", "uptime_seconds": catalog.started.elapsed().as_secs() }))
+}
+
+// Readiness: 503 until every dataset has finished loading and at least one succeeded
+#[get("/ready")]
+async fn get_ready(catalog: web::Data<Catalog>) -> impl Responder {
+    let mut body = catalog.listing();
+    let ready = catalog.ready();
+    body["ready"] = json!(ready);
+    match ready {
+        true => HttpResponse::Ok().json(body),
+        false => HttpResponse::ServiceUnavailable().json(body),
+    }
+}
+
+#[get("/datasets/")]
+async fn get_datasets(catalog: web::Data<Catalog>) -> impl Responder {
+    HttpResponse::Ok().json(catalog.listing())
+}
+
+// Loads a data file and builds everything served from it. A reload passes the state it
+// replaces, whose background search jobs carry over.
+fn build_state(args: &Args, path: &str, heavy_work: HeavyWork, previous: Option<&AppState>, progress: &AtomicUsize) -> Result<AppState, LoadError> {
+    let loaded_at = SystemTime::now();
+    let start_load = Instant::now();
+
+    let (mut metadata, mut nodes, child_to_parent, root_ids) = load_nodes(args, path, progress)?;
+    if root_ids.len() > 1 {
+        info!("Loaded a forest of {} trees", root_ids.len());
+    }
+    // Joined after loading, so a changed TSV doesn't make the snapshot stale
+    if let Some(tsv) = &args.metadata_tsv {
+        join::join_tsv(&mut nodes, tsv, &args.metadata_key, &args.metadata_prefix, &args.key_filter)?;
+    }
+
+    if args.relayout || y_missing(&nodes) {
+        let roots = root_indexes(&nodes, &root_ids);
+        layout_y(&mut nodes, &child_to_parent, &roots);
+        info!("Laid out y from the tree{}", if args.relayout { "" } else { ", as the data file has no usable y" });
+    }
+    metadata.config.y_scale = Some(scale_y_coordinates(&mut nodes));
+    // y is fixed from here on; everything indexed by position is built after the sort
+    nodes.sort_by(|a, b| a.y.total_cmp(&b.y));
+    let metadata_types = type_metadata(&mut nodes);
+    let children = build_children(&nodes, &child_to_parent);
+    let roots = root_indexes(&nodes, &root_ids);
+    if args.recompute_num_tips || num_tips_missing(&nodes, &children) {
+        count_tips(&mut nodes, &children, &roots);
+        info!(
+            "Recomputed num_tips from the tree{}",
+            if args.recompute_num_tips { "" } else { ", as the data file's are missing or zero" }
+        );
+    }
+    if let Some(key) = &args.time_from_key {
+        match nodes.iter().any(|n| n.x_time.is_some()) {
+            true => warn!("The data file has its own x_time, so --time-from-key is ignored"),
+            false => metadata.config.time_origin = dates::assign_times(&mut nodes, &children, &roots, key),
+        }
+    }
+    let extremes = Extremes::compute(&nodes);
+    update_config(&mut metadata.config, &nodes, extremes, &roots, metadata.mutations.clone());
+    // Genes in the GFF replace those of the same name from the metadata line
+    if let Some(gff) = &args.gff {
+        for gene in gff::read_genes(gff)? {
+            metadata.config.gene_details.insert(gene.name.clone(), gene);
+        }
+    }
+    let reference = args.reference.as_deref().map(Reference::load).transpose()?;
+    if let Some(reference) = &reference {
+        reference.check_genes(&metadata.config.gene_details);
+    }
+    metadata.config.reference_available = reference.is_some();
+    if let Some(include) = &args.key_filter.include {
+        let missing = include.iter()
+            .filter(|key| !args.key_filter.exclude.contains(key) && !metadata_types.keys().any(|field| args::names_field(key, field)));
+        for key in missing {
+            warn!("--include-keys names {}, but no node has it", key);
+        }
+    }
+    // Fields were chosen to be shown, so the frontend displays them all
+    if !args.key_filter.is_empty() {
+        let keys = metadata.config.keys_to_display.get_or_insert_with(Vec::new);
+        keys.extend(metadata_types.keys().cloned());
+    }
+    let node_index = nodes.iter().enumerate().map(|(idx, n)| (n.node_id, idx)).collect();
+    let metadata_keys: Vec<String> = metadata_types.keys().cloned().collect();
+    metadata.config.metadata_types = Some(metadata_types);
+    metadata.config.multi_valued_keys = Some(multi_valued_keys(&nodes));
+    let mutation_lookup = metadata.config.mutations.iter()
+        .enumerate()
+        .map(|(idx, m)| (m.mutation_id() as i32, idx))
+        .collect();
+    let (dfs_intervals, dfs_order) = compute_dfs_intervals(&nodes, &children, &roots);
+    let name_index = search::build_name_index(&nodes);
+    let acknowledgements = args.acknowledgements.as_ref()
+        .map(|path| Acknowledgements::load(path, &args.metadata_key, &name_index, nodes.len()))
+        .transpose()?;
+    metadata.config.acknowledgements_available = acknowledgements.is_some();
+    let prefix_index = PrefixIndex::build(&nodes, args.autocomplete_case_insensitive);
+    let fuzzy_index = args.fuzzy_index.then(|| {
+        let start = Instant::now();
+        let tree = BkTree::build(&nodes);
+        info!("Built fuzzy name index in {:?}", start.elapsed());
+        tree
+    });
+    let clade_index = search::build_clade_index(&nodes);
+    let meta_index = args.meta_index.then(|| {
+        let start = Instant::now();
+        let meta_index = MetaIndex::build(&nodes);
+        info!(
+            "Built metadata index with {} values (~{:.1} MB) in {:?}",
+            meta_index.num_values(),
+            meta_index.memory_bytes() as f64 / 1e6,
+            start.elapsed()
+        );
+        meta_index
+    });
+    let numeric_columns = search::build_numeric_columns(&nodes);
+    info!("Detected {} numeric metadata fields", numeric_columns.len());
+    let start = Instant::now();
+    let spatial_index = SpatialGrid::build(&nodes, |n| (n.x_dist, n.y));
+    let spatial_time_index = metadata.config.x_time_available.then(|| {
+        SpatialGrid::build(&nodes, |n| (XType::Time.x(n), n.y))
+    });
+    info!(
+        "Built spatial index (~{:.1} MB) in {:?}",
+        (spatial_index.memory_bytes() + spatial_time_index.as_ref().map_or(0, SpatialGrid::memory_bytes)) as f64 / 1e6,
+        start.elapsed()
+    );
+    let threads = thread_count(args);
+    let start = Instant::now();
+    let minimap = build_minimap(&nodes, extremes, &node_index, &child_to_parent, threads);
+    info!("Built minimap ({} bytes) in {:?}", minimap.len(), start.elapsed());
+    let minimap_etag = etag::content_tag(&minimap);
+    let compression = Compression::new(args.compression.clone(), args.compression_level);
+    let config_json = serde_json::to_vec(&metadata.config).map_err(|e| LoadError::Config(e.to_string()))?;
+    let config_etag = etag::content_tag(&config_json);
+    let config_body = compression.precompress(web::Bytes::from(config_json)).map_err(|e| LoadError::Config(e.to_string()))?;
+    let tips = nodes.iter().filter(|n| n.num_tips == 1).count();
+    Ok(AppState {
+        nodes,
+        node_index,
+        child_to_parent,
+        children,
+        dfs_intervals,
+        dfs_order,
+        config: metadata.config,
+        root_id: root_ids[0],
+        root_ids,
+        metadata_keys,
+        mutation_lookup,
+        name_index,
+        prefix_index,
+        fuzzy_index,
+        clade_index,
+        meta_index,
+        numeric_columns,
+        acknowledgements,
+        reference,
+        genotype_cache: GenotypeCache::default(),
+        color_tables: ColorTables::default(),
+        spatial_index,
+        spatial_time_index,
+        search_jobs: previous.map_or_else(
+            || Arc::new(SearchJobs::new(args.max_search_jobs, Duration::from_secs(args.search_job_ttl))),
+            |previous| previous.search_jobs.clone(),
+        ),
+        max_search_limit: args.max_search_limit,
+        max_export_tips: args.max_export_tips,
+        max_nodes_returned: args.max_nodes_returned,
+        heavy_work,
+        request_timeout: (args.request_timeout > 0).then(|| Duration::from_secs(args.request_timeout)),
+        threads,
+        minimap,
+        minimap_etag,
+        compression,
+        config_body,
+        config_etag,
+        loaded_at,
+        load_time: start_load.elapsed(),
+        tips,
+        nodes_cache: ResponseCache::new(args.nodes_cache_mb * 1024 * 1024),
+        extremes,
+    })
+}
+
+// Registers every dataset as loading and loads them all in the background, each on
+// its own thread, starting to watch them for --watch once loaded. One that fails is
+// logged and listed with its error, without holding up the others; if they all fail,
+// the server exits with the status for the first one's error.
+fn load_datasets(args: &Arc<Args>) -> Catalog {
+    let heavy_work = HeavyWork::new(args.max_concurrent_queries);
+    let datasets: Vec<web::Data<Dataset>> = args.path.iter().map(|path| (None, path.clone()))
+        .chain(args.datasets.iter().map(|(name, path)| (Some(name.clone()), path.clone())))
+        .map(|(name, path)| web::Data::new(Dataset::new(name, path, args.clone(), heavy_work.clone())))
+        .collect();
+
+    let loading = datasets.clone();
+    thread::spawn(move || {
+        let loaded = thread::scope(|scope| {
+            let handles: Vec<_> = loading.iter().map(|dataset| scope.spawn(move || {
+                // Only named datasets are labelled, so a single dataset logs as it always has
+                let span = dataset.name.as_deref().map_or_else(Span::none, |name| info_span!("load", dataset = name));
+                let _entered = span.enter();
+                // Taken before loading, so a change made while the file is read still triggers a reload
+                let loaded_file = watch::file_signature(Path::new(&dataset.path));
+                let result = {
+                    let _loading = dataset.try_begin_reload();
+                    dataset.load()
+                };
+                if dataset.args.watch {
+                    watch::spawn(dataset.clone(), loaded_file);
+                }
+                result
+            })).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap_or(Err(LoadError::Panicked))).collect::<Vec<_>>()
+        });
+        if loaded.iter().all(Result::is_err) {
+            error!("No dataset could be loaded");
+            std::process::exit(loaded.first().and_then(|result| result.as_ref().err()).map_or(1, LoadError::exit_code));
+        }
+    });
+    Catalog { datasets, started: Instant::now() }
+}
+
+// GET and POST from --cors-origin, or from anywhere when none was given
+fn cors_policy(args: &Args) -> Cors {
+    let cors = Cors::default()
+        .allowed_methods(["GET", "POST", "OPTIONS"])
+        .allow_any_header()
+        .max_age(3600);
+    if args.cors_origins.is_empty() {
+        return cors.allow_any_origin();
+    }
+    let cors = args.cors_origins.iter().fold(cors, |cors, origin| cors.allowed_origin(origin));
+    match args.cors_allow_credentials {
+        true => cors.supports_credentials(),
+        false => cors,
+    }
+}
+
+// The routes served for each dataset, at the root or under its name
+fn dataset_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(index)
+        .service(get_node)
+        .service(get_node_details)
+        .service(get_node_mutations)
+        .service(get_tip_atts)
+        .service(get_acknowledgements)
+        .service(get_subtree_acknowledgements)
+        .service(get_sequence)
+        .service(get_values)
+        .service(get_colors)
+        .service(get_viewport_counts)
+        .service(get_mutations)
+        .service(get_nearest)
+        .service(get_mrca)
+        .service(get_path)
+        .service(get_nextstrain_json)
+        .service(get_newick)
+        .service(get_metadata_tsv)
+        .service(get_nodes)
+        .service(get_minimap)
+        .service(get_nodes_by_ids)
+        .service(post_nodes_by_ids)
+        .service(get_config)
+        .service(get_search)
+        .service(post_search)
+        .service(get_search_status)
+        .service(get_autocomplete)
+        .service(post_reload)
+        .service(get_status);
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let args = Args::parse().unwrap_or_else(|message| {
+        println!("{}", message);
+        std::process::exit(1);
+    });
+    tracing::subscriber::set_global_default(logging::Logger::new(args.log_filter.clone(), args.log_format))
+        .expect("Failed to install logger");
+
+    let args = Arc::new(args);
+    let catalog = web::Data::new(load_datasets(&args));
+    let datasets = catalog.datasets.clone();
+    let compression = web::Data::new(Compression::new(args.compression.clone(), args.compression_level));
+    let rate_limiter = args.rate_limit.map(|rate| web::Data::new(RateLimiter::new(rate, args.rate_limit_burst.unwrap_or(rate.max(1.0)), args.trust_proxy)));
+
+    let app_args = args.clone();
+    let mut server = HttpServer::new(move || {
+        let cors = cors_policy(&app_args);
+
+        let mut app = App::new()
+            .wrap(from_fn(error::catch_panics))
+            .wrap(from_fn(auth::require_token))
+            .wrap(cors)
+            .wrap(from_fn(compression::compress))
+            .wrap(from_fn(logging::access_log))
+            .app_data(web::Data::from(app_args.clone()))
+            .app_data(compression.clone())
+            .app_data(catalog.clone())
+            .app_data(web::QueryConfig::default().error_handler(error::query_error))
+            .app_data(web::PathConfig::default().error_handler(|e, _| ApiError::bad_request("Invalid path").with_detail(e).into()))
+            .service(get_datasets)
+            .service(get_health)
+            .service(get_ready);
+        if let Some(rate_limiter) = &rate_limiter {
+            app = app.app_data(rate_limiter.clone());
+        }
+        // Named datasets get their routes under /<name>/, the unnamed one at the root
+        for dataset in &datasets {
+            app = match &dataset.name {
+                Some(name) => app.service(web::scope(&format!("/{}", name)).app_data(dataset.clone()).configure(dataset_routes)),
+                None => app.app_data(dataset.clone()).configure(dataset_routes),
+            };
+        }
+        app
+    })
+    .shutdown_timeout(args.shutdown_timeout)
+    // Signals are handled by shutdown::on_signal, so SIGINT is graceful too
+    .disable_signals();
+    for host in &args.hosts {
+        let url = match host.contains(':') {
+            true => format!("http://[{}]:{}", host, args.port),
+            false => format!("http://{}:{}", host, args.port),
+        };
+        server = server.bind((host.as_str(), args.port)).unwrap_or_else(|e| {
+            error!("Failed to listen on {}: {}", url, e);
+            std::process::exit(1);
+        });
+        info!("Starting server at {}", url);
+    }
+    #[cfg(unix)]
+    if let Some(path) = &args.unix_socket {
+        let listener = socket::bind(Path::new(path), args.unix_socket_mode, args.unix_socket_owner)
+            .and_then(|listener| server.listen_uds(listener));
+        server = listener.unwrap_or_else(|e| {
+            error!("Failed to listen on {}: {}", path, e);
+            std::process::exit(1);
+        });
+        info!("Starting server at unix:{}", path);
+    }
+    let server = server.run();
+
+    if args.signal_handlers {
+        actix_web::rt::spawn(shutdown::on_signal(server.handle(), Duration::from_secs(args.shutdown_timeout)));
+    }
+    server.await?;
+    info!("Server stopped");
+    std::io::stderr().flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A node line with metadata values of every shape, including strings that look
+    // like other JSON values
+    const NODE_LINE: &str = r#"{"name":"tip_1","x_dist":1.5,"y":2.0,"mutations":[3,4],"parent_id":0,"node_id":1,"num_tips":1,"clades":{"pango":"B.1"},"meta_text":"plain","meta_null_string":"null","meta_quoted":"\"quoted\"","meta_missing":null,"meta_int":-7,"meta_big":18446744073709551615,"meta_min":-9223372036854775808,"meta_float":0.1,"meta_nested":{"a":[1,"two",null],"b":{"c":"say \"hi\""}}}"#;
+
+    #[test]
+    fn node_line_serializes_back_unchanged() {
+        let node: Node = serde_json::from_str(NODE_LINE).unwrap();
+        let line: Value = serde_json::from_str(NODE_LINE).unwrap();
+        assert_eq!(serde_json::to_value(&node).unwrap(), line);
+        assert_eq!(node.meta["meta_null_string"], json!("null"));
+        assert_eq!(node.meta["meta_quoted"], json!("\"quoted\""));
+        assert_eq!(node.meta["meta_missing"], Value::Null);
+    }
+
+    #[test]
+    fn node_round_trips_through_msgpack() {
+        let node: Node = serde_json::from_str(NODE_LINE).unwrap();
+        let mut encoded = Vec::new();
+        msgpack::to_writer(&mut encoded, &node).unwrap();
+        let decoded: Node = msgpack::from_slice(&encoded).unwrap();
+        assert_eq!(serde_json::to_value(&decoded).unwrap(), serde_json::to_value(&node).unwrap());
+    }
+
+    fn node(node_id: i32, parent_id: i32, num_tips: i32) -> Node {
+        Node {
+            name: String::new(),
+            x_dist: 0.0,
+            y: 0.0,
+            x_time: None,
+            mutations: Vec::new(),
+            parent_id,
+            node_id,
+            num_tips,
+            clades: BTreeMap::new(),
+            meta: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn truncate_nodes_keeps_the_biggest_clades_with_their_ancestors() {
+        //        0 (6)
+        //       /     \
+        //     1 (4)   2 (2)
+        //    /  \      |
+        //  3 (3) 4    5 (2)
+        //  / | \       |
+        // 6  7  8      9
+        let nodes = vec![
+            node(0, 0, 6),
+            node(1, 0, 4),
+            node(2, 0, 2),
+            node(3, 1, 3),
+            node(4, 1, 1),
+            node(5, 2, 2),
+            node(6, 3, 1),
+            node(7, 3, 1),
+            node(8, 3, 1),
+            node(9, 5, 1),
+        ];
+        let node_index: HashMap<i32, usize> = nodes.iter().enumerate().map(|(idx, n)| (n.node_id, idx)).collect();
+        let child_to_parent: HashMap<i32, i32> = nodes.iter().filter(|n| n.parent_id != n.node_id).map(|n| (n.node_id, n.parent_id)).collect();
+        let truncate = |indexes: Vec<usize>, cap| truncate_nodes(&nodes, &node_index, &child_to_parent, indexes, cap);
+
+        assert_eq!(truncate((0..10).collect(), 4), vec![0, 1, 2, 3]);
+        assert_eq!(truncate((0..10).collect(), 10), (0..10).collect::<Vec<_>>());
+        // Node 5 would bring its parent 2 as well, which doesn't fit beside 0, 1 and 3,
+        // but the smaller tip 4 still does
+        assert_eq!(truncate(vec![0, 1, 3, 4, 5], 4), vec![0, 1, 3, 4]);
+        // Tips bring all their ancestors; 9's don't fit beside 6's
+        assert_eq!(truncate(vec![6, 9], 5), vec![0, 1, 3, 6]);
+        assert_eq!(truncate(vec![6, 9], 3), Vec::<usize>::new());
+    }
+
+    fn typed(values: &[Value]) -> (MetaType, Vec<Value>) {
+        let mut nodes: Vec<Node> = values.iter()
+            .enumerate()
+            .map(|(i, value)| Node { meta: BTreeMap::from([("meta_x".to_string(), value.clone())]), ..node(i as i32, 0, 1) })
+            .collect();
+        let types = type_metadata(&mut nodes);
+        (types["meta_x"], nodes.into_iter().map(|node| node.meta["meta_x"].clone()).collect())
+    }
+
+    #[test]
+    fn numeric_strings_only_become_numbers_when_written_the_same() {
+        let kept = ["0.950", "1.10", "1e5", " 7", "-0", "12345678901234567890123", "007"];
+        for s in kept {
+            assert!(json_number(s).is_none(), "{}", s);
+        }
+        for s in ["0.95", "7", "-3", "1.5e-7", "18446744073709551615"] {
+            assert_eq!(json_number(s).map(|number| number.to_string()).as_deref(), Some(s));
+        }
+
+        let (kind, values) = typed(&[json!("0.950"), json!("0.95"), json!("12345678901234567890123"), json!(""), json!(3)]);
+        assert_eq!(kind, MetaType::Number);
+        assert_eq!(values, [json!("0.950"), json!(0.95), json!("12345678901234567890123"), json!(""), json!(3)]);
+        // Searches match on the text as given, converted or not
+        for (value, given) in values.iter().zip(["0.950", "0.95", "12345678901234567890123", "", "3"]) {
+            assert_eq!(search::meta_value_string(value), given);
+        }
+    }
+
+    #[test]
+    fn metadata_types_need_every_value() {
+        assert_eq!(typed(&[json!("true"), json!("false"), Value::Null]), (MetaType::Bool, vec![json!(true), json!(false), Value::Null]));
+        assert_eq!(typed(&[json!("1"), json!("a")]), (MetaType::Categorical, vec![json!("1"), json!("a")]));
+        assert_eq!(typed(&[json!("true"), json!("1")]).0, MetaType::Categorical);
+        assert_eq!(typed(&[json!(""), Value::Null]).0, MetaType::Categorical);
+    }
+
+    const METADATA_LINE: &str = r#"{"version":"1","mutations":[],"total_nodes":3,"config":{"gene_details":{},"num_tips":2}}"#;
+
+    // A data file of the metadata line and these nodes, one per line
+    fn data_file(name: &str, nodes: &[Node]) -> PathBuf {
+        let mut contents = format!("{}\n", METADATA_LINE);
+        for node in nodes {
+            contents.push_str(&serde_json::to_string(node).unwrap());
+            contents.push('\n');
+        }
+        fixture(name, contents.as_bytes())
+    }
+
+    fn args(path: &Path, flags: &[&str]) -> Args {
+        let flags = flags.iter().map(|flag| flag.to_string());
+        Args::parse_from(flags.chain([path.display().to_string()])).unwrap()
+    }
+
+    #[test]
+    fn duplicate_node_ids_are_reported_with_their_lines() {
+        let named = |name: &str, node: Node| Node { name: name.to_string(), ..node };
+        let path = data_file("duplicate.jsonl", &[
+            node(0, 0, 2),
+            named("first", node(1, 0, 1)),
+            node(2, 0, 1),
+            named("second", node(1, 2, 1)),
+        ]);
+
+        match load_data(&args(&path, &[]), &path, &AtomicUsize::new(0)) {
+            Err(LoadError::DuplicateNodeId { id: 1, lines: (3, 5) }) => {}
+            other => panic!("expected node_id 1 on lines 3 and 5, got {:?}", other.map(|(_, nodes, ..)| nodes.len())),
+        }
+
+        let (_, nodes, child_to_parent, _) = load_data(&args(&path, &["--lenient"]), &path, &AtomicUsize::new(0)).unwrap();
+        assert_eq!(nodes.len(), 3);
+        let kept: Vec<&Node> = nodes.iter().filter(|node| node.node_id == 1).collect();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].name, "first");
+        assert_eq!(child_to_parent[&1], 0);
+    }
+
+    // The state served for a data file
+    pub(crate) fn state(path: &Path, flags: &[&str]) -> AppState {
+        build_state(&args(path, flags), path.to_str().unwrap(), HeavyWork::new(1), None, &AtomicUsize::new(0)).unwrap()
+    }
+
+    #[test]
+    fn nodes_serialize_meta_fields_in_metadata_keys_order() {
+        let lines = [
+            METADATA_LINE,
+            r#"{"name":"","x_dist":0,"mutations":[],"node_id":0,"parent_id":0,"clades":{},"meta_zone":"b","meta_age":3}"#,
+            r#"{"name":"a","x_dist":1,"mutations":[],"node_id":1,"parent_id":0,"clades":{},"meta_zone":"a","meta_lineage":"X","meta_age":1}"#,
+            r#"{"name":"b","x_dist":1,"mutations":[],"node_id":2,"parent_id":0,"clades":{},"meta_country":"UK"}"#,
+        ];
+        let data = state(&fixture("key_order.jsonl", lines.join("\n").as_bytes()), &[]);
+        assert_eq!(data.metadata_keys, ["meta_age", "meta_country", "meta_lineage", "meta_zone"]);
+        for node in &data.nodes {
+            let json = serde_json::to_string(node).unwrap();
+            let positions: Vec<usize> = data.metadata_keys.iter()
+                .filter_map(|key| json.find(&format!("\"{}\":", key)))
+                .collect();
+            assert_eq!(positions.len(), node.meta.len(), "{}", json);
+            assert!(positions.is_sorted(), "{}", json);
+        }
+    }
+
+    const CONTENTS: &str = "{\"config\":{}}\n{\"node_id\":1}\n";
+
+    // Writes `bytes` to a file of this name in a directory of this process's own
+    pub(crate) fn fixture(name: &str, bytes: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("jsonl_processor_tests_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn gzipped(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read_all(path: &Path) -> io::Result<String> {
+        let mut contents = String::new();
+        open_reader(path)?.read_to_string(&mut contents)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn open_reader_decompresses_by_content() {
+        let zstd = zstd::encode_all(CONTENTS.as_bytes(), 0).unwrap();
+        let files = [
+            fixture("plain.jsonl", CONTENTS.as_bytes()),
+            fixture("tree.jsonl.gz", &gzipped(CONTENTS.as_bytes())),
+            fixture("tree.jsonl.zst", &zstd),
+            // Misnamed: the first bytes win over the extension
+            fixture("gzip.jsonl.zst", &gzipped(CONTENTS.as_bytes())),
+            fixture("zstd.jsonl", &zstd),
+        ];
+        for path in files {
+            assert_eq!(read_all(&path).unwrap(), CONTENTS, "{}", path.display());
+        }
+    }
+
+    // CONTENTS compressed by xz, and by xz --format=lzma
+    const CONTENTS_XZ: &[u8] = &[
+        0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00, 0x00, 0x04, 0xe6, 0xd6, 0xb4, 0x46, 0x04, 0xc0, 0x20, 0x1c,
+        0x21, 0x01, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x15, 0x52, 0x85, 0x37,
+        0x01, 0x00, 0x1b, 0x7b, 0x22, 0x63, 0x6f, 0x6e, 0x66, 0x69, 0x67, 0x22, 0x3a, 0x7b, 0x7d, 0x7d,
+        0x0a, 0x7b, 0x22, 0x6e, 0x6f, 0x64, 0x65, 0x5f, 0x69, 0x64, 0x22, 0x3a, 0x31, 0x7d, 0x0a, 0x00,
+        0x82, 0x6b, 0xc2, 0xf2, 0x04, 0x03, 0x04, 0x68, 0x00, 0x01, 0x3c, 0x1c, 0x9b, 0x90, 0x74, 0x47,
+        0x1f, 0xb6, 0xf3, 0x7d, 0x01, 0x00, 0x00, 0x00, 0x00, 0x04, 0x59, 0x5a,
+    ];
+    const CONTENTS_LZMA: &[u8] = &[
+        0x5d, 0x00, 0x00, 0x80, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x3d, 0x88,
+        0x88, 0x67, 0x34, 0x05, 0x9b, 0xb2, 0xcc, 0x2d, 0x61, 0xe9, 0x7c, 0x90, 0x1d, 0x27, 0x1a, 0x39,
+        0xba, 0x7a, 0x17, 0x33, 0x3b, 0x0e, 0x22, 0xd1, 0x1b, 0xb8, 0x9f, 0x0f, 0xff, 0xff, 0xf7, 0x5b,
+        0xf8, 0x00,
+    ];
+
+    #[test]
+    fn open_reader_decompresses_xz() {
+        let files = [
+            fixture("tree.jsonl.xz", CONTENTS_XZ),
+            fixture("tree.jsonl.lzma", CONTENTS_LZMA),
+            // Misnamed: xz has magic, so is found by content
+            fixture("xz.jsonl", CONTENTS_XZ),
+        ];
+        for path in files {
+            assert_eq!(read_all(&path).unwrap(), CONTENTS, "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn open_reader_reports_corrupt_xz() {
+        let error = read_all(&fixture("cut.jsonl.xz", &CONTENTS_XZ[..CONTENTS_XZ.len() / 2])).unwrap_err();
+        assert!(matches!(LoadError::from(error), LoadError::Decompress { codec: "xz", .. }));
+    }
+
+    #[test]
+    fn open_reader_reports_corrupt_gzip() {
+        let mut gzip = gzipped(CONTENTS.as_bytes());
+        gzip.truncate(gzip.len() / 2);
+        let error = read_all(&fixture("cut.jsonl.gz", &gzip)).unwrap_err();
+        assert!(matches!(LoadError::from(error), LoadError::Decompress { codec: "gzip", .. }));
+    }
+
+    #[test]
+    fn metadata_values_round_trip_through_msgpack() {
+        let values = [
+            json!("null"),
+            json!("\"quoted\""),
+            json!(""),
+            Value::Null,
+            json!(i64::MIN),
+            json!(i64::MAX),
+            json!(u64::MAX),
+            json!(-1),
+            json!(0.1),
+            json!(f64::MAX),
+            json!(f64::MIN_POSITIVE),
+            json!({ "a": { "b": [1, "two", null, { "c": "\"" }] } }),
+        ];
+        for value in values {
+            let mut encoded = Vec::new();
+            msgpack::to_writer(&mut encoded, &value).unwrap();
+            assert_eq!(msgpack::from_slice::<Value>(&encoded).unwrap(), value);
+        }
+    }
+}
@@ -0,0 +1,781 @@
+use serde::de;
+use serde::ser::{self, Serialize};
+use std::fmt;
+
+// MessagePack encoding of anything serializable, laid out the way serde_json would lay
+// it out: structs become maps keyed by field name and enums use the externally tagged
+// form, so a client decoding with a generic MessagePack library sees the same structure
+// as the JSON.
+pub fn to_writer<T: Serialize + ?Sized>(out: &mut Vec<u8>, value: &T) -> Result<(), Error> {
+    value.serialize(&mut Serializer { out })
+}
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Error {
+        Error(message.to_string())
+    }
+}
+
+struct Serializer<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl<'a> Serializer<'a> {
+    fn write_uint(&mut self, value: u64) {
+        match value {
+            0..=0x7f => self.out.push(value as u8),
+            0x80..=0xff => self.out.extend_from_slice(&[0xcc, value as u8]),
+            0x100..=0xffff => {
+                self.out.push(0xcd);
+                self.out.extend_from_slice(&(value as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                self.out.push(0xce);
+                self.out.extend_from_slice(&(value as u32).to_be_bytes());
+            }
+            _ => {
+                self.out.push(0xcf);
+                self.out.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+    }
+
+    fn write_int(&mut self, value: i64) {
+        if value >= 0 {
+            return self.write_uint(value as u64);
+        }
+        if value >= -32 {
+            self.out.push(value as i8 as u8);
+        } else if value >= i8::MIN as i64 {
+            self.out.extend_from_slice(&[0xd0, value as i8 as u8]);
+        } else if value >= i16::MIN as i64 {
+            self.out.push(0xd1);
+            self.out.extend_from_slice(&(value as i16).to_be_bytes());
+        } else if value >= i32::MIN as i64 {
+            self.out.push(0xd2);
+            self.out.extend_from_slice(&(value as i32).to_be_bytes());
+        } else {
+            self.out.push(0xd3);
+            self.out.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    // Header for a str, bin, array or map of `len` elements, using the smallest form
+    fn write_header(&mut self, kind: Kind, len: usize) -> Result<(), Error> {
+        let (fix, fix_max, short, long) = match kind {
+            Kind::Str => (Some(0xa0), 31, Some(0xd9), [0xda, 0xdb]),
+            Kind::Bin => (None, 0, Some(0xc4), [0xc5, 0xc6]),
+            Kind::Array => (Some(0x90), 15, None, [0xdc, 0xdd]),
+            Kind::Map => (Some(0x80), 15, None, [0xde, 0xdf]),
+        };
+        match (fix, short) {
+            (Some(fix), _) if len <= fix_max => self.out.push(fix | len as u8),
+            (_, Some(short)) if len <= 0xff => self.out.extend_from_slice(&[short, len as u8]),
+            _ if len <= 0xffff => {
+                self.out.push(long[0]);
+                self.out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            _ => {
+                let len = u32::try_from(len).map_err(|_| Error(format!("Too many elements for MessagePack: {}", len)))?;
+                self.out.push(long[1]);
+                self.out.extend_from_slice(&len.to_be_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn write_str(&mut self, value: &str) -> Result<(), Error> {
+        self.write_header(Kind::Str, value.len())?;
+        self.out.extend_from_slice(value.as_bytes());
+        Ok(())
+    }
+
+    // Opens an array or map; without a known length the header is filled in at the end
+    fn compound<'s>(&'s mut self, kind: Kind, len: Option<usize>) -> Result<Compound<'s, 'a>, Error> {
+        let start = self.out.len();
+        if let Some(len) = len {
+            self.write_header(kind, len)?;
+        }
+        Ok(Compound { ser: self, kind, start, len, count: 0 })
+    }
+
+    // Enum variants carrying data are written as a one-entry map, {variant: data}
+    fn variant_key(&mut self, variant: &str) -> Result<(), Error> {
+        self.write_header(Kind::Map, 1)?;
+        self.write_str(variant)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Str,
+    Bin,
+    Array,
+    Map,
+}
+
+struct Compound<'s, 'a> {
+    ser: &'s mut Serializer<'a>,
+    kind: Kind,
+    // Where this array or map begins in the output
+    start: usize,
+    // The length given up front, if any; otherwise the header is written at the end
+    len: Option<usize>,
+    count: usize,
+}
+
+impl Compound<'_, '_> {
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        match self.len {
+            Some(len) if len != self.count => Err(Error(format!("Expected {} elements but got {}", len, self.count))),
+            Some(_) => Ok(()),
+            None => {
+                let elements = self.ser.out.split_off(self.start);
+                self.ser.write_header(self.kind, self.count)?;
+                self.ser.out.extend_from_slice(&elements);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<'s, 'a> ser::Serializer for &'s mut Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'s, 'a>;
+    type SerializeTuple = Compound<'s, 'a>;
+    type SerializeTupleStruct = Compound<'s, 'a>;
+    type SerializeTupleVariant = Compound<'s, 'a>;
+    type SerializeMap = Compound<'s, 'a>;
+    type SerializeStruct = Compound<'s, 'a>;
+    type SerializeStructVariant = Compound<'s, 'a>;
+
+    fn serialize_bool(self, value: bool) -> Result<(), Error> {
+        self.out.push(if value { 0xc3 } else { 0xc2 });
+        Ok(())
+    }
+
+    fn serialize_i8(self, value: i8) -> Result<(), Error> {
+        self.serialize_i64(value as i64)
+    }
+
+    fn serialize_i16(self, value: i16) -> Result<(), Error> {
+        self.serialize_i64(value as i64)
+    }
+
+    fn serialize_i32(self, value: i32) -> Result<(), Error> {
+        self.serialize_i64(value as i64)
+    }
+
+    fn serialize_i64(self, value: i64) -> Result<(), Error> {
+        self.write_int(value);
+        Ok(())
+    }
+
+    fn serialize_u8(self, value: u8) -> Result<(), Error> {
+        self.serialize_u64(value as u64)
+    }
+
+    fn serialize_u16(self, value: u16) -> Result<(), Error> {
+        self.serialize_u64(value as u64)
+    }
+
+    fn serialize_u32(self, value: u32) -> Result<(), Error> {
+        self.serialize_u64(value as u64)
+    }
+
+    fn serialize_u64(self, value: u64) -> Result<(), Error> {
+        self.write_uint(value);
+        Ok(())
+    }
+
+    fn serialize_f32(self, value: f32) -> Result<(), Error> {
+        self.out.push(0xca);
+        self.out.extend_from_slice(&value.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_f64(self, value: f64) -> Result<(), Error> {
+        self.out.push(0xcb);
+        self.out.extend_from_slice(&value.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, value: char) -> Result<(), Error> {
+        self.write_str(value.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, value: &str) -> Result<(), Error> {
+        self.write_str(value)
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> Result<(), Error> {
+        self.write_header(Kind::Bin, value.len())?;
+        self.out.extend_from_slice(value);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.out.push(0xc0);
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<(), Error> {
+        self.write_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.variant_key(variant)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Compound<'s, 'a>, Error> {
+        self.compound(Kind::Array, len)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'s, 'a>, Error> {
+        self.compound(Kind::Array, Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Compound<'s, 'a>, Error> {
+        self.compound(Kind::Array, Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'s, 'a>, Error> {
+        self.variant_key(variant)?;
+        self.compound(Kind::Array, Some(len))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Compound<'s, 'a>, Error> {
+        self.compound(Kind::Map, len)
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Compound<'s, 'a>, Error> {
+        self.compound(Kind::Map, Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'s, 'a>, Error> {
+        self.variant_key(variant)?;
+        self.compound(Kind::Map, Some(len))
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.count += 1;
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.count += 1;
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.count += 1;
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.count += 1;
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeMap for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.count += 1;
+        self.element(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.count += 1;
+        self.ser.write_str(key)?;
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.count += 1;
+        self.ser.write_str(key)?;
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+// Decodes what to_writer encodes. Strings are borrowed from `input` where the target
+// type allows it.
+pub fn from_slice<'de, T: de::Deserialize<'de>>(input: &'de [u8]) -> Result<T, Error> {
+    let mut deserializer = Deserializer { input };
+    let value = T::deserialize(&mut deserializer)?;
+    match deserializer.input.is_empty() {
+        true => Ok(value),
+        false => Err(Error(format!("{} bytes left over after the value", deserializer.input.len()))),
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Error {
+        Error(message.to_string())
+    }
+}
+
+struct Deserializer<'de> {
+    input: &'de [u8],
+}
+
+impl<'de> Deserializer<'de> {
+    fn take(&mut self, len: usize) -> Result<&'de [u8], Error> {
+        if self.input.len() < len {
+            return Err(Error("Unexpected end of MessagePack input".to_string()));
+        }
+        let (taken, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn peek(&self) -> Result<u8, Error> {
+        self.input.first().copied().ok_or_else(|| Error("Unexpected end of MessagePack input".to_string()))
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+
+    // The length following a str, bin, array or map marker of `width` bytes
+    fn len(&mut self, width: usize) -> Result<usize, Error> {
+        Ok(match width {
+            1 => self.byte()? as usize,
+            2 => u16::from_be_bytes(self.array()?) as usize,
+            _ => u32::from_be_bytes(self.array()?) as usize,
+        })
+    }
+
+    fn str(&mut self, len: usize) -> Result<&'de str, Error> {
+        std::str::from_utf8(self.take(len)?).map_err(|e| Error(format!("Invalid UTF-8 in MessagePack string: {}", e)))
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let marker = self.byte()?;
+        match marker {
+            0x00..=0x7f => visitor.visit_u64(marker as u64),
+            0x80..=0x8f => visitor.visit_map(Elements { de: self, left: (marker & 0x0f) as usize }),
+            0x90..=0x9f => visitor.visit_seq(Elements { de: self, left: (marker & 0x0f) as usize }),
+            0xa0..=0xbf => visitor.visit_borrowed_str(self.str((marker & 0x1f) as usize)?),
+            0xc0 => visitor.visit_unit(),
+            0xc2 => visitor.visit_bool(false),
+            0xc3 => visitor.visit_bool(true),
+            0xc4..=0xc6 => {
+                let len = self.len(1 << (marker - 0xc4))?;
+                visitor.visit_borrowed_bytes(self.take(len)?)
+            }
+            0xca => visitor.visit_f32(f32::from_be_bytes(self.array()?)),
+            0xcb => visitor.visit_f64(f64::from_be_bytes(self.array()?)),
+            0xcc => visitor.visit_u64(self.byte()? as u64),
+            0xcd => visitor.visit_u64(u16::from_be_bytes(self.array()?) as u64),
+            0xce => visitor.visit_u64(u32::from_be_bytes(self.array()?) as u64),
+            0xcf => visitor.visit_u64(u64::from_be_bytes(self.array()?)),
+            0xd0 => visitor.visit_i64(self.byte()? as i8 as i64),
+            0xd1 => visitor.visit_i64(i16::from_be_bytes(self.array()?) as i64),
+            0xd2 => visitor.visit_i64(i32::from_be_bytes(self.array()?) as i64),
+            0xd3 => visitor.visit_i64(i64::from_be_bytes(self.array()?)),
+            0xd9..=0xdb => {
+                let len = self.len(1 << (marker - 0xd9))?;
+                visitor.visit_borrowed_str(self.str(len)?)
+            }
+            0xdc | 0xdd => {
+                let left = self.len(2 << (marker - 0xdc))?;
+                visitor.visit_seq(Elements { de: self, left })
+            }
+            0xde | 0xdf => {
+                let left = self.len(2 << (marker - 0xde))?;
+                visitor.visit_map(Elements { de: self, left })
+            }
+            0xe0..=0xff => visitor.visit_i64(marker as i8 as i64),
+            _ => Err(Error(format!("Unsupported MessagePack marker 0x{:02x}", marker))),
+        }
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.peek()? == 0xc0 {
+            self.byte()?;
+            return visitor.visit_none();
+        }
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // A unit variant is its name; one carrying data is {variant: data}
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.peek()? {
+            0x81 => {
+                self.byte()?;
+                visitor.visit_enum(Variant { de: self })
+            }
+            _ => visitor.visit_enum(de::value::StrDeserializer::<Error>::new(de::Deserialize::deserialize(&mut *self)?)),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+// The remaining elements of an array, or entries of a map
+struct Elements<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    left: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Elements<'_, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+struct Variant<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'de> de::EnumAccess<'de> for Variant<'_, 'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let variant = seed.deserialize(&mut *self.de)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Variant<'_, 'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        de::Deserialize::deserialize(self.de)
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(self.de, visitor)
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(self.de, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Vec<u8> {
+        let mut out = Vec::new();
+        to_writer(&mut out, value).unwrap();
+        out
+    }
+
+    fn round_trip(value: &Value) -> Vec<u8> {
+        let encoded = encode(value);
+        assert_eq!(&from_slice::<Value>(&encoded).unwrap(), value);
+        encoded
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Segment(i32, i32),
+        Rect { width: u32, height: u32 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Drawing {
+        name: String,
+        shapes: Vec<Shape>,
+        scale: Option<f64>,
+        layer: Option<u8>,
+    }
+
+    #[test]
+    fn every_value_kind_round_trips() {
+        round_trip(&json!(null));
+        round_trip(&json!(true));
+        round_trip(&json!(false));
+        round_trip(&json!(0));
+        round_trip(&json!(1.5));
+        round_trip(&json!(-0.25));
+        round_trip(&json!(""));
+        round_trip(&json!("héllo"));
+        round_trip(&json!([]));
+        round_trip(&json!({}));
+        round_trip(&json!({ "a": [1, -2, 3.5, "four", null, { "b": false }] }));
+    }
+
+    #[test]
+    fn structs_and_enums_round_trip() {
+        let drawing = Drawing {
+            name: "d".to_string(),
+            shapes: vec![Shape::Empty, Shape::Circle(2.0), Shape::Segment(-1, 1), Shape::Rect { width: 3, height: 4 }],
+            scale: None,
+            layer: Some(2),
+        };
+        assert_eq!(from_slice::<Drawing>(&encode(&drawing)).unwrap(), drawing);
+        // Laid out as serde_json lays it out
+        assert_eq!(from_slice::<Value>(&encode(&drawing)).unwrap(), serde_json::to_value(&drawing).unwrap());
+    }
+
+    #[test]
+    fn integers_use_the_smallest_form() {
+        let cases: [(Value, u8); 16] = [
+            (json!(0), 0x00),
+            (json!(127), 0x7f),
+            (json!(128), 0xcc),
+            (json!(256), 0xcd),
+            (json!(65536), 0xce),
+            (json!(u32::MAX as u64 + 1), 0xcf),
+            (json!(i64::MAX as u64 + 1), 0xcf),
+            (json!(u64::MAX), 0xcf),
+            (json!(-1), 0xff),
+            (json!(-32), 0xe0),
+            (json!(-33), 0xd0),
+            (json!(-128), 0xd0),
+            (json!(-129), 0xd1),
+            (json!(-32769), 0xd2),
+            (json!(i32::MIN as i64 - 1), 0xd3),
+            (json!(i64::MIN), 0xd3),
+        ];
+        for (value, marker) in cases {
+            assert_eq!(round_trip(&value)[0], marker, "{}", value);
+        }
+        assert_eq!(from_slice::<u64>(&encode(&u64::MAX)).unwrap(), u64::MAX);
+        assert_eq!(from_slice::<i64>(&encode(&i64::MIN)).unwrap(), i64::MIN);
+    }
+
+    #[test]
+    fn lengths_use_the_smallest_header() {
+        let string = |len: usize| json!("x".repeat(len));
+        let array = |len: usize| Value::Array(vec![json!(0); len]);
+        let map = |len: usize| Value::Object((0..len).map(|i| (i.to_string(), json!(0))).collect());
+        let cases: [(Value, &[u8]); 14] = [
+            (string(31), &[0xbf]),
+            (string(32), &[0xd9, 32]),
+            (string(255), &[0xd9, 0xff]),
+            (string(256), &[0xda, 0x01, 0x00]),
+            (string(65535), &[0xda, 0xff, 0xff]),
+            (string(65536), &[0xdb, 0x00, 0x01, 0x00, 0x00]),
+            (array(15), &[0x9f]),
+            (array(16), &[0xdc, 0x00, 0x10]),
+            (array(65535), &[0xdc, 0xff, 0xff]),
+            (array(65536), &[0xdd, 0x00, 0x01, 0x00, 0x00]),
+            (map(15), &[0x8f]),
+            (map(16), &[0xde, 0x00, 0x10]),
+            (map(65535), &[0xde, 0xff, 0xff]),
+            (map(65536), &[0xdf, 0x00, 0x01, 0x00, 0x00]),
+        ];
+        for (value, header) in cases {
+            let encoded = round_trip(&value);
+            assert_eq!(&encoded[..header.len()], header);
+        }
+    }
+
+    // Maps of unknown length have their header written once the entries are counted
+    #[test]
+    fn unknown_lengths_are_filled_in() {
+        struct Unsized(usize);
+        impl Serialize for Unsized {
+            fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                use ser::SerializeMap;
+                let mut map = serializer.serialize_map(None)?;
+                for i in 0..self.0 {
+                    map.serialize_entry(&i.to_string(), &i)?;
+                }
+                map.end()
+            }
+        }
+        for len in [0, 15, 16, 65536] {
+            let decoded: Value = from_slice(&encode(&Unsized(len))).unwrap();
+            assert_eq!(decoded.as_object().map(|map| map.len()), Some(len));
+        }
+    }
+
+    #[test]
+    fn truncated_input_is_an_error() {
+        let value = json!({ "name": "x".repeat(300), "ids": [1, -200, 70000, u64::MAX], "scale": 0.5, "nested": { "a": null } });
+        let encoded = encode(&value);
+        for len in 0..encoded.len() {
+            assert!(from_slice::<Value>(&encoded[..len]).is_err(), "{} of {} bytes", len, encoded.len());
+        }
+        // A header claiming far more elements than follow
+        assert!(from_slice::<Value>(&[0xdd, 0xff, 0xff, 0xff, 0xff, 0x01]).is_err());
+        assert!(from_slice::<Value>(&[0xdb, 0xff, 0xff, 0xff, 0xff, b'x']).is_err());
+    }
+
+    #[test]
+    fn trailing_bytes_are_an_error() {
+        let mut encoded = encode(&json!([1, 2]));
+        encoded.push(0xc0);
+        assert!(from_slice::<Value>(&encoded).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use actix_web::error::InternalError;
+use actix_web::http::header;
+use actix_web::web::{self, Bytes};
+use actix_web::HttpResponse;
+use futures_util::Stream;
+use serde_json::json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tracing::Span;
+
+// Caps how many expensive requests (/nodes/ and /search/) are computed at once. Their
+// work runs on the blocking thread pool, so the async workers stay free for cheap
+// requests; once the cap is reached further ones get a 503 rather than queueing.
+// Clones share the count.
+#[derive(Clone)]
+pub struct HeavyWork {
+    running: Arc<AtomicUsize>,
+    max_running: usize,
+}
+
+// A slot held for as long as a request is computing, including while its body streams
+pub struct Permit {
+    running: Arc<AtomicUsize>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.running.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl HeavyWork {
+    pub fn new(max_running: usize) -> HeavyWork {
+        HeavyWork { running: Arc::new(AtomicUsize::new(0)), max_running }
+    }
+
+    pub fn try_acquire(&self) -> Result<Permit, actix_web::Error> {
+        self.running
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |running| (running < self.max_running).then_some(running + 1))
+            .map_err(|_| {
+                let response = HttpResponse::ServiceUnavailable()
+                    .insert_header((header::RETRY_AFTER, "1"))
+                    .json(json!({ "error": "Server busy, try again shortly" }));
+                actix_web::Error::from(InternalError::from_response("Server busy", response))
+            })?;
+        Ok(Permit { running: self.running.clone() })
+    }
+}
+
+// A streamed body whose chunks are each produced on the blocking thread pool, within
+// `span`, keeping the permit until the body is finished or dropped.
+pub fn stream<I>(chunks: I, permit: Permit, span: Span) -> impl Stream<Item = Result<Bytes, actix_web::Error>>
+where
+    I: Iterator<Item = Result<Bytes, actix_web::Error>> + Send + 'static,
+{
+    futures_util::stream::unfold(Some((chunks, permit)), move |state| {
+        let span = span.clone();
+        async move {
+            let (mut chunks, permit) = state?;
+            // actix_web::Error can't cross threads, so errors come back as their message
+            let next = web::block(move || {
+                let _entered = span.enter();
+                (chunks.next().map(|chunk| chunk.map_err(|e| e.to_string())), chunks)
+            }).await;
+            match next {
+                Ok((Some(Ok(chunk)), chunks)) => Some((Ok(chunk), Some((chunks, permit)))),
+                Ok((Some(Err(e)), _)) => Some((Err(crate::error::ApiError::internal("Failed to serialize response", e).into()), None)),
+                Ok((None, _)) => None,
+                Err(e) => Some((Err(e.into()), None)),
+            }
+        }
+    })
+}
@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use tracing::warn;
+
+use crate::load_error::LoadError;
+use crate::{Metadata, Node};
+
+// Lines handed to a parsing thread at a time
+const BATCH_LINES: usize = 1024;
+
+// Batches each parsing thread may have queued or in hand before reading waits for
+// results, which bounds the memory held by lines not yet parsed
+const BATCHES_PER_THREAD: usize = 4;
+
+// How much of a malformed line is quoted when reporting it
+const SNIPPET_BYTES: usize = 200;
+
+// A line as read, or what was wrong with its bytes
+type Line = Result<String, LoadError>;
+
+// Parses each line as a Node and passes them to `each_node` in line order, with their
+// line numbers, returning how many malformed lines were skipped; `first_line` is the
+// number of the first of them in the file. A malformed line fails the parse, unless
+// `lenient`, in which case it is logged and skipped.
+//
+// With more than one thread, this thread reads lines and batches them while the others
+// parse, and the batches parsed are put back in order before their nodes are passed on.
+pub fn nodes(
+    lines: impl Iterator<Item = io::Result<String>>,
+    first_line: usize,
+    threads: usize,
+    lenient: bool,
+    mut each_node: impl FnMut(usize, Node) -> Result<(), LoadError>,
+) -> Result<usize, LoadError> {
+    let mut skipped = 0;
+    let mut accept = |line: usize, parsed: Result<Node, LoadError>| match parsed {
+        Ok(node) => each_node(line, node),
+        Err(e) if lenient && e.is_malformed_line() => {
+            warn!("Skipping a malformed line: {}", e);
+            skipped += 1;
+            Ok(())
+        }
+        Err(e) => Err(e),
+    };
+    // A line that isn't UTF-8 is malformed like any other; other read errors end the load
+    let mut lines = lines
+        .enumerate()
+        .map(|(i, line)| match line.map_err(|e| LoadError::reading(first_line + i, e)) {
+            Err(e) if !e.is_malformed_line() => Err(e),
+            line => Ok(line),
+        })
+        .peekable();
+
+    if threads <= 1 {
+        for (i, line) in lines.enumerate() {
+            accept(first_line + i, line?.and_then(|line| node(first_line + i, line)))?;
+        }
+        return Ok(skipped);
+    }
+
+    let (batch_tx, batch_rx) = mpsc::channel::<(usize, Vec<Line>)>();
+    let batch_rx = &Mutex::new(batch_rx);
+    // batch_tx moves in, so returning early drops it and the parsing threads stop
+    thread::scope(move |scope| {
+        let (parsed_tx, parsed_rx) = mpsc::channel();
+        for _ in 0..threads {
+            let parsed_tx = parsed_tx.clone();
+            scope.spawn(move || {
+                while let Ok((seq, batch)) = batch_rx.lock().unwrap().recv() {
+                    let start = first_line + seq * BATCH_LINES;
+                    let nodes: Vec<Result<Node, LoadError>> = batch
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, line)| line.and_then(|line| node(start + i, line)))
+                        .collect();
+                    if parsed_tx.send((seq, nodes)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(parsed_tx);
+
+        let mut in_order = InOrder { next: 0, pending: BTreeMap::new() };
+        // The channels only close early if a parsing thread panicked
+        let mut receive = || -> Result<(), LoadError> {
+            let (seq, nodes) = parsed_rx.recv().map_err(|_| LoadError::Panicked)?;
+            in_order.accept(seq, first_line + seq * BATCH_LINES, nodes, &mut accept)
+        };
+        let (mut sent, mut received) = (0, 0);
+        while lines.peek().is_some() {
+            let batch = lines.by_ref().take(BATCH_LINES).collect::<Result<Vec<Line>, LoadError>>()?;
+            batch_tx.send((sent, batch)).map_err(|_| LoadError::Panicked)?;
+            sent += 1;
+            while sent - received >= threads * BATCHES_PER_THREAD {
+                receive()?;
+                received += 1;
+            }
+        }
+        drop(batch_tx);
+        while received < sent {
+            receive()?;
+            received += 1;
+        }
+        Ok::<_, LoadError>(())
+    })?;
+    Ok(skipped)
+}
+
+// Parses the metadata on line `number` of the data file
+pub fn metadata(number: usize, line: &str) -> Result<Metadata, LoadError> {
+    serde_json::from_str(line).map_err(|source| LoadError::MetadataParse { line: number, source })
+}
+
+// Parses the node on line `number` of the data file, quoting the start of it if it's
+// malformed
+fn node(number: usize, line: String) -> Result<Node, LoadError> {
+    serde_json::from_str(&line).map_err(|source| {
+        let mut end = line.len().min(SNIPPET_BYTES);
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        let ellipsis = if end < line.len() { "..." } else { "" };
+        LoadError::NodeParse { line: number, snippet: format!("{}{}", &line[..end], ellipsis), source }
+    })
+}
+
+// Batches of parsed lines, passed on once every earlier batch has been
+struct InOrder {
+    next: usize,
+    pending: BTreeMap<usize, (usize, Vec<Result<Node, LoadError>>)>,
+}
+
+impl InOrder {
+    // Takes batch `seq`, whose first line is `start`
+    fn accept(
+        &mut self,
+        seq: usize,
+        start: usize,
+        nodes: Vec<Result<Node, LoadError>>,
+        accept: &mut impl FnMut(usize, Result<Node, LoadError>) -> Result<(), LoadError>,
+    ) -> Result<(), LoadError> {
+        self.pending.insert(seq, (start, nodes));
+        while let Some((start, nodes)) = self.pending.remove(&self.next) {
+            for (i, node) in nodes.into_iter().enumerate() {
+                accept(start + i, node)?;
+            }
+            self.next += 1;
+        }
+        Ok(())
+    }
+}
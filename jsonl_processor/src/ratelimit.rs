@@ -0,0 +1,102 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Most clients tracked at once; the least recently seen is forgotten beyond this, and
+// starts again with a full bucket if it returns
+const MAX_CLIENTS: usize = 10000;
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    // Recency stamp; the client is under this in `by_use`
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<IpAddr, Bucket>,
+    // last_used -> client, oldest first
+    by_use: BTreeMap<u64, IpAddr>,
+    clock: u64,
+}
+
+// A token bucket per client IP: each request takes a token, and tokens come back at
+// `rate` per second up to `burst`.
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+    rate: f64,
+    burst: f64,
+    // Identify clients by X-Forwarded-For/Forwarded rather than the connection's address
+    trust_proxy: bool,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64, trust_proxy: bool) -> RateLimiter {
+        RateLimiter { buckets: Mutex::new(Buckets::default()), rate, burst, trust_proxy }
+    }
+
+    fn client(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        if self.trust_proxy {
+            let forwarded = req.connection_info().realip_remote_addr().map(str::to_string);
+            if let Some(ip) = forwarded.and_then(|addr| addr.parse().ok().or_else(|| addr.parse::<SocketAddr>().ok().map(|addr| addr.ip()))) {
+                return Some(ip);
+            }
+        }
+        req.peer_addr().map(|addr| addr.ip())
+    }
+
+    // Takes a token for `client`, or returns how many seconds until one is available
+    fn take(&self, client: IpAddr) -> Result<(), u64> {
+        let now = Instant::now();
+        let mut guard = self.buckets.lock().unwrap();
+        let buckets = &mut *guard;
+        buckets.clock += 1;
+        let clock = buckets.clock;
+        let bucket = buckets.buckets.entry(client).or_insert(Bucket { tokens: self.burst, refilled: now, last_used: clock });
+        buckets.by_use.remove(&bucket.last_used);
+        bucket.last_used = clock;
+        buckets.by_use.insert(clock, client);
+
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * self.rate).min(self.burst);
+        bucket.refilled = now;
+        let result = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.rate).ceil().max(1.0) as u64)
+        };
+
+        while buckets.buckets.len() > MAX_CLIENTS {
+            let Some((_, oldest)) = buckets.by_use.pop_first() else {
+                break;
+            };
+            buckets.buckets.remove(&oldest);
+        }
+        result
+    }
+}
+
+// Middleware for the expensive routes, which wrap themselves in it. Does nothing unless
+// --rate-limit registered a RateLimiter; over the limit a client gets a 429.
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    if let Some(limiter) = req.app_data::<web::Data<RateLimiter>>() {
+        if let Some(Err(retry_after)) = limiter.client(&req).map(|client| limiter.take(client)) {
+            let response = HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                .json(json!({ "error": "Too many requests, try again later" }));
+            return Ok(req.into_response(response));
+        }
+    }
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
@@ -0,0 +1,1195 @@
+use regex::RegexBuilder;
+use serde::Deserialize;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::deadline::Deadline;
+use crate::error::ApiError;
+use crate::fuzzy::levenshtein;
+use crate::{AppState, Mutation, Node};
+
+// Number of sites whose reconstructed genotypes are kept in the cache.
+const GENOTYPE_CACHE_SIZE: usize = 256;
+
+// Upper bound on the compiled size of a name_regex pattern. The regex crate
+// matches in linear time, so bounding the program size bounds the work per name.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+// Largest edit distance accepted by name_fuzzy
+const MAX_FUZZY_DISTANCE: usize = 2;
+
+// Default number of hits above which search results are summarized rather than
+// returned in full. total_count always reports the full number of matches.
+pub const DEFAULT_SEARCH_THRESHOLD: usize = 10000;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchRequest {
+    #[serde(flatten)]
+    pub spec: SearchSpec,
+    // Restricts matches to this node and its descendants
+    #[serde(default)]
+    pub root_node_id: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SearchSpec {
+    Name {
+        #[serde(default = "default_method")]
+        method: String,
+        text: String,
+        // Edit distance for name_fuzzy, default 1
+        #[serde(default)]
+        max_distance: Option<usize>,
+    },
+    Meta {
+        key: String,
+        value: Value,
+    },
+    // Nodes whose array-valued metadata field has an element equal to the value
+    MetaContains {
+        key: String,
+        value: Value,
+    },
+    Mutation {
+        gene: String,
+        position: usize,
+        // Any substitution at the position matches when omitted
+        #[serde(default)]
+        new_residue: Option<String>,
+    },
+    Genotype {
+        gene: String,
+        position: usize,
+        new_residue: String,
+    },
+    MetaRange {
+        key: String,
+        #[serde(default)]
+        min: Option<Value>,
+        #[serde(default)]
+        max: Option<Value>,
+    },
+    Clade {
+        key: String,
+        value: String,
+        // Only return the largest matching node, i.e. the clade root
+        #[serde(default)]
+        root_only: bool,
+    },
+    // Tips at or beneath any node whose metadata field equals the value
+    AncestorMeta {
+        key: String,
+        value: Value,
+    },
+    // Tips at or beneath any node carrying the clade label
+    AncestorClade {
+        key: String,
+        value: String,
+    },
+    // Tips where a later mutation restored the residue an earlier one replaced
+    Revertant {
+        #[serde(default)]
+        gene: Option<String>,
+        #[serde(default)]
+        position: Option<usize>,
+    },
+    NumTips {
+        #[serde(default)]
+        min: Option<i32>,
+        #[serde(default)]
+        max: Option<i32>,
+    },
+    Boolean {
+        boolean_method: BooleanMethod,
+        subspecs: Vec<SearchSpec>,
+    },
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum BooleanMethod {
+    And,
+    Or,
+    // Matches of the first subspec that match none of the others
+    Not,
+}
+
+// Tip indexes grouped by their reconstructed residue at one site.
+pub type SiteGenotypes = HashMap<String, Vec<usize>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnKind {
+    Number,
+    // Days since 1970-01-01
+    Date,
+}
+
+// Parsed values of a numeric or date metadata field, parallel to AppState.nodes.
+pub struct NumericColumn {
+    pub kind: ColumnKind,
+    pub values: Vec<Option<f64>>,
+}
+
+#[derive(Default)]
+pub struct GenotypeCache {
+    sites: Mutex<HashMap<(String, usize), Arc<SiteGenotypes>>>,
+}
+
+fn default_method() -> String {
+    "text_match".to_string()
+}
+
+impl SearchSpec {
+    // Rejects specs that parse but can't describe a search, before any of it runs
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            SearchSpec::Name { method, .. } if !matches!(method.as_str(), "text_match" | "name_regex" | "name_fuzzy") => {
+                Err(format!("Unknown search method: {}", method))
+            }
+            SearchSpec::NumTips { min: Some(min), max: Some(max) } if min > max => {
+                Err(format!("num_tips min ({}) must not be greater than max ({})", min, max))
+            }
+            SearchSpec::Boolean { subspecs, .. } if subspecs.is_empty() => Err("A boolean search needs at least one subspec".to_string()),
+            SearchSpec::Boolean { subspecs, .. } => subspecs.iter().try_for_each(SearchSpec::validate),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn scope_to_subtree(state: &AppState, request: &SearchRequest, matches: &mut Vec<usize>) -> Result<(), ApiError> {
+    if let Some(root_node_id) = request.root_node_id {
+        let root_idx = *state.node_index.get(&root_node_id)
+            .ok_or_else(|| ApiError::bad_request(format!("Unknown root_node_id: {}", root_node_id)))?;
+        matches.retain(|&idx| state.is_descendant(idx, root_idx));
+    }
+    Ok(())
+}
+
+// Counts hits without ordering them; genotype counts come straight from the site cache.
+pub fn count_search_request(state: &AppState, request: &SearchRequest, deadline: &Deadline) -> Result<usize, ApiError> {
+    if let (SearchSpec::Genotype { gene, position, new_residue }, None) = (&request.spec, request.root_node_id) {
+        return Ok(site_genotypes(state, gene, *position, deadline)?.get(new_residue).map_or(0, Vec::len));
+    }
+    let mut matches = run_search(state, &request.spec, deadline)?;
+    scope_to_subtree(state, request, &mut matches)?;
+    Ok(matches.len())
+}
+
+// Runs a search and returns its hits in a deterministic order, so pages of the
+// same query never overlap.
+pub fn run_search_request(state: &AppState, request: &SearchRequest, deadline: &Deadline) -> Result<Vec<usize>, ApiError> {
+    let mut matches = run_search(state, &request.spec, deadline)?;
+    scope_to_subtree(state, request, &mut matches)?;
+
+    let nodes = &state.nodes;
+    if let Some(query) = fuzzy_query(&request.spec) {
+        matches.sort_by_cached_key(|&idx| (levenshtein(query.as_bytes(), nodes[idx].name.as_bytes()), nodes[idx].node_id));
+    } else if ranks_by_num_tips(&request.spec) {
+        matches.sort_by_key(|&idx| (std::cmp::Reverse(nodes[idx].num_tips), nodes[idx].node_id));
+    } else {
+        matches.sort_by_key(|&idx| nodes[idx].node_id);
+    }
+    Ok(matches)
+}
+
+// The query text of a name_fuzzy search, whose hits are ranked by distance
+pub fn fuzzy_query(spec: &SearchSpec) -> Option<&str> {
+    match spec {
+        SearchSpec::Name { method, text, .. } if method == "name_fuzzy" => Some(text),
+        _ => None,
+    }
+}
+
+// num_tips searches put the biggest clades first, including when they lead a boolean search
+fn ranks_by_num_tips(spec: &SearchSpec) -> bool {
+    match spec {
+        SearchSpec::NumTips { .. } => true,
+        SearchSpec::Boolean { boolean_method: BooleanMethod::And | BooleanMethod::Not, subspecs } => {
+            subspecs.first().is_some_and(ranks_by_num_tips)
+        }
+        _ => false,
+    }
+}
+
+// The scans below stop early once `deadline` passes, leaving their results short, so
+// the check() that follows each one is what reports it
+pub fn run_search(state: &AppState, spec: &SearchSpec, deadline: &Deadline) -> Result<Vec<usize>, ApiError> {
+    let nodes = &state.nodes;
+    let matches = match spec {
+        SearchSpec::Name { method, text, max_distance } => match method.as_str() {
+            "text_match" => search_by_name(nodes, text, deadline),
+            "name_regex" => search_by_name_regex(nodes, text, deadline).map_err(ApiError::bad_request)?,
+            "name_fuzzy" => search_by_name_fuzzy(state, text, max_distance.unwrap_or(1), deadline).map_err(ApiError::bad_request)?,
+            _ => return Err(ApiError::bad_request(format!("Unknown search method: {}", method))),
+        },
+        SearchSpec::Meta { key, value } => match &state.meta_index {
+            Some(index) => index.lookup(key, value),
+            None => search_by_meta(nodes, key, value, deadline),
+        },
+        SearchSpec::MetaContains { key, value } => match &state.meta_index {
+            Some(index) => index.lookup_contains(key, value),
+            None => search_by_meta_contains(nodes, key, value, deadline),
+        },
+        SearchSpec::Mutation { gene, position, new_residue } => {
+            let ids = matching_mutation_ids(&state.config.mutations, gene, *position, new_residue.as_deref());
+            search_by_mutation(state, &ids, deadline)
+        }
+        SearchSpec::Genotype { gene, position, new_residue } => {
+            let genotypes = site_genotypes(state, gene, *position, deadline)?;
+            genotypes.get(new_residue).cloned().unwrap_or_default()
+        }
+        SearchSpec::MetaRange { key, min, max } => {
+            search_by_meta_range(state, key, min.as_ref(), max.as_ref(), deadline).map_err(ApiError::bad_request)?
+        }
+        SearchSpec::Clade { key, value, root_only } => search_by_clade(state, key, value, *root_only),
+        SearchSpec::AncestorMeta { key, value } => {
+            let ancestors = run_search(state, &SearchSpec::Meta { key: key.clone(), value: value.clone() }, deadline)?;
+            state.tips_beneath(&ancestors)
+        }
+        SearchSpec::AncestorClade { key, value } => state.tips_beneath(&search_by_clade(state, key, value, false)),
+        SearchSpec::Revertant { gene, position } => search_revertants(state, gene.as_deref(), *position, deadline),
+        SearchSpec::NumTips { min, max } => search_by_num_tips(nodes, *min, *max, deadline),
+        SearchSpec::Boolean { boolean_method, subspecs } => {
+            let results = subspecs.iter()
+                .map(|subspec| run_search(state, subspec, deadline))
+                .collect::<Result<Vec<_>, _>>()?;
+            combine_results(*boolean_method, results)
+        }
+    };
+    deadline.check()?;
+    Ok(matches)
+}
+
+// Results keep the order of the first subspec for "and" and "not".
+// When every input is sorted (as postings lists from the metadata index are), "and"
+// and "not" are computed by merging rather than hashing.
+fn combine_results(method: BooleanMethod, results: Vec<Vec<usize>>) -> Vec<usize> {
+    let all_sorted = results.iter().all(|r| r.is_sorted());
+    let mut results = results.into_iter();
+    let Some(first) = results.next() else {
+        return Vec::new();
+    };
+    if all_sorted && !matches!(method, BooleanMethod::Or) {
+        return results.fold(first, |acc, r| match method {
+            BooleanMethod::And => intersect_sorted(&acc, &r),
+            _ => subtract_sorted(&acc, &r),
+        });
+    }
+    let rest: Vec<HashSet<usize>> = results.map(|r| r.into_iter().collect()).collect();
+
+    match method {
+        BooleanMethod::And => first.into_iter().filter(|idx| rest.iter().all(|r| r.contains(idx))).collect(),
+        BooleanMethod::Not => first.into_iter().filter(|idx| !rest.iter().any(|r| r.contains(idx))).collect(),
+        BooleanMethod::Or => {
+            let mut union: Vec<usize> = first.into_iter().chain(rest.into_iter().flatten()).collect();
+            union.sort_unstable();
+            union.dedup();
+            union
+        }
+    }
+}
+
+fn intersect_sorted(a: &[usize], b: &[usize]) -> Vec<usize> {
+    let mut result = Vec::with_capacity(a.len().min(b.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                result.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    result
+}
+
+fn subtract_sorted(a: &[usize], b: &[usize]) -> Vec<usize> {
+    let mut result = Vec::with_capacity(a.len());
+    let mut j = 0;
+    for &x in a {
+        while j < b.len() && b[j] < x {
+            j += 1;
+        }
+        if j >= b.len() || b[j] != x {
+            result.push(x);
+        }
+    }
+    result
+}
+
+// Returns nodes whose num_tips lies within [min, max], biggest clades first.
+pub fn search_by_num_tips(nodes: &[Node], min: Option<i32>, max: Option<i32>, deadline: &Deadline) -> Vec<usize> {
+    let min = min.unwrap_or(i32::MIN);
+    let max = max.unwrap_or(i32::MAX);
+
+    let mut result: Vec<usize> = deadline.watch(nodes.iter().enumerate())
+        .filter(|(_, n)| n.num_tips >= min && n.num_tips <= max)
+        .map(|(idx, _)| idx)
+        .collect();
+    result.sort_by_key(|&idx| std::cmp::Reverse(nodes[idx].num_tips));
+    result
+}
+
+// Returns the indexes of every node whose name contains `text` (case-sensitive).
+pub fn search_by_name(nodes: &[Node], text: &str, deadline: &Deadline) -> Vec<usize> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+
+    deadline.watch(nodes.iter().enumerate())
+        .filter(|(_, n)| n.name.contains(text))
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+// Returns the indexes of every node whose name matches the regular expression `pattern`.
+pub fn search_by_name_regex(nodes: &[Node], pattern: &str, deadline: &Deadline) -> Result<Vec<usize>, String> {
+    let regex = RegexBuilder::new(pattern)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .dfa_size_limit(REGEX_SIZE_LIMIT)
+        .build()
+        .map_err(|e| format!("Invalid regex: {}", e))?;
+
+    Ok(deadline.watch(nodes.iter().enumerate())
+        .filter(|(_, n)| regex.is_match(&n.name))
+        .map(|(idx, _)| idx)
+        .collect())
+}
+
+pub fn build_name_index(nodes: &[Node]) -> HashMap<String, Vec<usize>> {
+    let mut index: HashMap<String, Vec<usize>> = HashMap::new();
+    for (idx, node) in nodes.iter().enumerate() {
+        if !node.name.is_empty() {
+            index.entry(node.name.clone()).or_default().push(idx);
+        }
+    }
+    index
+}
+
+// Resolves exact names through the name index, returning every node carrying
+// each name along with the names that matched nothing.
+pub fn search_by_names(name_index: &HashMap<String, Vec<usize>>, names: &[String]) -> (Vec<usize>, Vec<String>) {
+    let mut matches = Vec::new();
+    let mut not_found = Vec::new();
+    let mut seen = HashSet::new();
+
+    for name in names {
+        if !seen.insert(name) {
+            continue;
+        }
+        match name_index.get(name) {
+            Some(indexes) => matches.extend_from_slice(indexes),
+            None => not_found.push(name.clone()),
+        }
+    }
+
+    (matches, not_found)
+}
+
+// Upper bound on the number of prefix matches examined per autocomplete request
+const AUTOCOMPLETE_SCAN_LIMIT: usize = 10000;
+
+// Node indexes sorted by name for prefix lookups, plus an optional lowercased copy
+// of the names for case-insensitive matching.
+pub struct PrefixIndex {
+    sorted: Vec<usize>,
+    lowercase: Option<Vec<(String, usize)>>,
+}
+
+impl PrefixIndex {
+    pub fn build(nodes: &[Node], case_insensitive: bool) -> PrefixIndex {
+        let mut sorted: Vec<usize> = (0..nodes.len()).filter(|&idx| !nodes[idx].name.is_empty()).collect();
+        sorted.sort_by(|&a, &b| nodes[a].name.cmp(&nodes[b].name));
+
+        let lowercase = case_insensitive.then(|| {
+            let mut names: Vec<(String, usize)> = sorted.iter().map(|&idx| (nodes[idx].name.to_lowercase(), idx)).collect();
+            names.sort();
+            names
+        });
+
+        PrefixIndex { sorted, lowercase }
+    }
+
+    pub fn supports_case_insensitive(&self) -> bool {
+        self.lowercase.is_some()
+    }
+
+    // Returns up to `limit` distinct names starting with `prefix`, tips before internal nodes.
+    pub fn complete(&self, nodes: &[Node], prefix: &str, limit: usize, case_insensitive: bool) -> Vec<String> {
+        let candidates: Vec<usize> = match (&self.lowercase, case_insensitive) {
+            (Some(lowercase), true) => {
+                let prefix = prefix.to_lowercase();
+                let start = lowercase.partition_point(|(name, _)| name.as_str() < prefix.as_str());
+                lowercase[start..].iter()
+                    .take_while(|(name, _)| name.starts_with(&prefix))
+                    .take(AUTOCOMPLETE_SCAN_LIMIT)
+                    .map(|&(_, idx)| idx)
+                    .collect()
+            }
+            _ => {
+                let start = self.sorted.partition_point(|&idx| nodes[idx].name.as_str() < prefix);
+                self.sorted[start..].iter()
+                    .take_while(|&&idx| nodes[idx].name.starts_with(prefix))
+                    .take(AUTOCOMPLETE_SCAN_LIMIT)
+                    .copied()
+                    .collect()
+            }
+        };
+
+        let (tips, internal): (Vec<usize>, Vec<usize>) = candidates.into_iter().partition(|&idx| nodes[idx].num_tips == 1);
+        let mut seen = HashSet::new();
+        tips.into_iter()
+            .chain(internal)
+            .map(|idx| nodes[idx].name.as_str())
+            .filter(|name| seen.insert(*name))
+            .take(limit)
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+// clade key -> clade value -> indexes of nodes carrying that label
+pub type CladeIndex = HashMap<String, HashMap<String, Vec<usize>>>;
+
+pub fn build_clade_index(nodes: &[Node]) -> CladeIndex {
+    let mut index = CladeIndex::new();
+    for (idx, node) in nodes.iter().enumerate() {
+        for (key, value) in &node.clades {
+            index.entry(key.clone()).or_default().entry(value.clone()).or_default().push(idx);
+        }
+    }
+    index
+}
+
+pub fn search_by_clade(state: &AppState, key: &str, value: &str, root_only: bool) -> Vec<usize> {
+    let Some(matches) = state.clade_index.get(key).and_then(|values| values.get(value)) else {
+        return Vec::new();
+    };
+    if root_only {
+        return matches.iter()
+            .copied()
+            .max_by_key(|&idx| state.nodes[idx].num_tips)
+            .into_iter()
+            .collect();
+    }
+    matches.clone()
+}
+
+pub fn search_by_name_fuzzy(state: &AppState, text: &str, max_distance: usize, deadline: &Deadline) -> Result<Vec<usize>, String> {
+    if max_distance > MAX_FUZZY_DISTANCE {
+        return Err(format!("max_distance must be at most {}", MAX_FUZZY_DISTANCE));
+    }
+    let index = state.fuzzy_index.as_ref()
+        .ok_or("Fuzzy name search is not enabled (start the server with --fuzzy-index)")?;
+    Ok(index.find(&state.nodes, text, max_distance, deadline).into_iter().map(|(idx, _)| idx).collect())
+}
+
+// Metadata is stored on nodes with a "meta_" prefix, but clients may use either form.
+pub fn meta_field_name(key: &str) -> Cow<'_, str> {
+    if key.starts_with("meta_") {
+        Cow::Borrowed(key)
+    } else {
+        Cow::Owned(format!("meta_{}", key))
+    }
+}
+
+// Strings compare by their contents; other values by their JSON representation.
+pub fn meta_value_string(value: &Value) -> Cow<'_, str> {
+    match value {
+        Value::String(s) => Cow::Borrowed(s),
+        other => Cow::Owned(other.to_string()),
+    }
+}
+
+type Postings = HashMap<String, HashMap<String, Vec<u32>>>;
+
+// Inverted index over metadata: field -> value -> sorted node indexes. Array-valued
+// fields are also indexed by each of their elements, for meta_contains.
+pub struct MetaIndex {
+    postings: Postings,
+    elements: Postings,
+}
+
+impl MetaIndex {
+    pub fn build(nodes: &[Node]) -> MetaIndex {
+        let mut postings = Postings::new();
+        let mut elements = Postings::new();
+        for (idx, node) in nodes.iter().enumerate() {
+            for (field, value) in &node.meta {
+                postings.entry(field.clone())
+                    .or_default()
+                    .entry(meta_value_string(value).into_owned())
+                    .or_default()
+                    .push(idx as u32);
+                if let Value::Array(items) = value {
+                    let field_elements = elements.entry(field.clone()).or_default();
+                    for item in items {
+                        let indexes = field_elements.entry(meta_value_string(item).into_owned()).or_default();
+                        // An element repeated within the array lists the node once
+                        if indexes.last() != Some(&(idx as u32)) {
+                            indexes.push(idx as u32);
+                        }
+                    }
+                }
+            }
+        }
+        MetaIndex { postings, elements }
+    }
+
+    pub fn lookup(&self, key: &str, value: &Value) -> Vec<usize> {
+        lookup_postings(&self.postings, key, value)
+    }
+
+    pub fn lookup_contains(&self, key: &str, value: &Value) -> Vec<usize> {
+        lookup_postings(&self.elements, key, value)
+    }
+
+    pub fn value_counts(&self, field: &str) -> Option<HashMap<String, usize>> {
+        self.postings.get(field)
+            .map(|values| values.iter().map(|(value, indexes)| (value.clone(), indexes.len())).collect())
+    }
+
+    pub fn num_values(&self) -> usize {
+        self.postings.values().map(HashMap::len).sum()
+    }
+
+    // Rough estimate of the heap memory held by the index
+    pub fn memory_bytes(&self) -> usize {
+        postings_bytes(&self.postings) + postings_bytes(&self.elements)
+    }
+}
+
+fn lookup_postings(postings: &Postings, key: &str, value: &Value) -> Vec<usize> {
+    postings.get(meta_field_name(key).as_ref())
+        .and_then(|values| values.get(meta_value_string(value).as_ref()))
+        .map(|indexes| indexes.iter().map(|&idx| idx as usize).collect())
+        .unwrap_or_default()
+}
+
+fn postings_bytes(postings: &Postings) -> usize {
+    postings.iter()
+        .map(|(field, values)| {
+            field.capacity()
+                + values.iter()
+                    .map(|(value, indexes)| {
+                        value.capacity() + indexes.capacity() * std::mem::size_of::<u32>()
+                            + std::mem::size_of::<(String, Vec<u32>)>()
+                    })
+                    .sum::<usize>()
+        })
+        .sum()
+}
+
+// Number of nodes carrying each distinct value of a metadata field
+pub fn value_counts(state: &AppState, field: &str) -> HashMap<String, usize> {
+    if let Some(counts) = state.meta_index.as_ref().and_then(|index| index.value_counts(field)) {
+        return counts;
+    }
+    let mut counts = HashMap::new();
+    for node in &state.nodes {
+        if let Some(value) = node.meta.get(field) {
+            *counts.entry(meta_value_string(value).into_owned()).or_default() += 1;
+        }
+    }
+    counts
+}
+
+// Returns the indexes of every node whose metadata field `key` equals `value`.
+// Unknown keys simply match nothing.
+pub fn search_by_meta(nodes: &[Node], key: &str, value: &Value, deadline: &Deadline) -> Vec<usize> {
+    let field = meta_field_name(key);
+    let wanted = meta_value_string(value);
+
+    deadline.watch(nodes.iter().enumerate())
+        .filter(|(_, n)| n.meta.get(field.as_ref()).is_some_and(|v| meta_value_string(v) == wanted))
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+// Returns the indexes of every node whose metadata field `key` is an array with an
+// element equal to `value`
+pub fn search_by_meta_contains(nodes: &[Node], key: &str, value: &Value, deadline: &Deadline) -> Vec<usize> {
+    let field = meta_field_name(key);
+    let wanted = meta_value_string(value);
+
+    deadline.watch(nodes.iter().enumerate())
+        .filter(|(_, n)| match n.meta.get(field.as_ref()) {
+            Some(Value::Array(items)) => items.iter().any(|item| meta_value_string(item) == wanted),
+            _ => false,
+        })
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+// Resolves a gene/position/residue query against the mutation dictionary.
+// The pseudo-gene "nt" selects nucleotide mutations.
+pub fn matching_mutation_ids(mutations: &[Mutation], gene: &str, position: usize, new_residue: Option<&str>) -> HashSet<i32> {
+    mutations.iter()
+        .filter(|m| mutation_matches(m, Some(gene), Some(position), new_residue))
+        .map(|m| m.mutation_id() as i32)
+        .collect()
+}
+
+// Omitted criteria match anything.
+fn mutation_matches(m: &Mutation, gene: Option<&str>, position: Option<usize>, new_residue: Option<&str>) -> bool {
+    let gene_matches = match gene {
+        None => true,
+        Some("nt") => matches!(m, Mutation::NT { .. }),
+        Some(gene) => matches!(m, Mutation::AA { .. }) && m.gene() == gene,
+    };
+    gene_matches
+        && position.is_none_or(|p| m.residue_pos() == p)
+        && new_residue.is_none_or(|r| m.new_residue() == r)
+}
+
+fn mutations_by_id<'a>(mutations: &'a [Mutation], gene: Option<&str>, position: Option<usize>) -> HashMap<i32, &'a Mutation> {
+    mutations.iter()
+        .filter(|m| mutation_matches(m, gene, position, None))
+        .map(|m| (m.mutation_id() as i32, m))
+        .collect()
+}
+
+// Returns the indexes of every node with one of `mutation_ids` on its branch.
+pub fn search_by_mutation(state: &AppState, mutation_ids: &HashSet<i32>, deadline: &Deadline) -> Vec<usize> {
+    if mutation_ids.is_empty() {
+        return Vec::new();
+    }
+
+    deadline.watch(state.nodes.iter().enumerate())
+        .filter(|(_, n)| n.mutations.iter().any(|m| mutation_ids.contains(m)))
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+// Reconstructs the residue carried by every tip at one site, caching the result
+// so repeated queries at the same site only pay for the traversal once.
+pub fn site_genotypes(state: &AppState, gene: &str, position: usize, deadline: &Deadline) -> Result<Arc<SiteGenotypes>, ApiError> {
+    // The cache only ever holds whole entries, so one a panicking thread held is still sound
+    let key = (gene.to_string(), position);
+    if let Some(cached) = state.genotype_cache.sites.lock().unwrap_or_else(PoisonError::into_inner).get(&key) {
+        return Ok(cached.clone());
+    }
+
+    let genotypes = Arc::new(compute_site_genotypes(state, gene, position, deadline)?);
+
+    let mut sites = state.genotype_cache.sites.lock().unwrap_or_else(PoisonError::into_inner);
+    if sites.len() >= GENOTYPE_CACHE_SIZE {
+        sites.clear();
+    }
+    sites.insert(key, genotypes.clone());
+    Ok(genotypes)
+}
+
+// Tips still carrying the reference residue take it from the first mutation at the site,
+// or failing that from the --reference; with neither, which residue they carry is unknown.
+// A traversal the deadline cuts short is an error, so it isn't cached.
+fn compute_site_genotypes(state: &AppState, gene: &str, position: usize, deadline: &Deadline) -> Result<SiteGenotypes, ApiError> {
+    let site_mutations = mutations_by_id(&state.config.mutations, Some(gene), Some(position));
+
+    let mut genotypes = SiteGenotypes::new();
+
+    // The first mutation met at this site on any path from the root starts from the reference residue
+    let mut reference: Option<&str> = None;
+
+    // None stands for "still the reference residue"
+    let mut reference_tips = Vec::new();
+    let mut stack: Vec<(usize, Option<&str>)> = state.root_ids.iter().filter_map(|id| state.node_index.get(id)).map(|&idx| (idx, None)).collect();
+    for _ in deadline.watch(iter::repeat(())) {
+        let Some((idx, residue)) = stack.pop() else { break };
+        let node = &state.nodes[idx];
+        let residue = apply_site_mutations(&site_mutations, residue, &node.mutations, &mut reference);
+        match state.children.get(&node.node_id) {
+            Some(children) if !children.is_empty() => {
+                stack.extend(children.iter().map(|&child| (child, residue)));
+            }
+            _ => match residue {
+                Some(r) => genotypes.entry(r.to_string()).or_default().push(idx),
+                None => reference_tips.push(idx),
+            },
+        }
+    }
+
+    if !reference_tips.is_empty() {
+        let reference = match reference {
+            Some(reference) => reference.to_string(),
+            None => reference_residue(state, gene, position).map_err(ApiError::bad_request)?,
+        };
+        genotypes.entry(reference).or_default().extend(reference_tips);
+    }
+    deadline.check()?;
+    for tips in genotypes.values_mut() {
+        tips.sort_unstable();
+    }
+    Ok(genotypes)
+}
+
+// The --reference's residue at a site no mutation in the tree is at
+fn reference_residue(state: &AppState, gene: &str, position: usize) -> Result<String, String> {
+    let unknown = format!("The reference residue at {}:{} is unknown, as no mutation in the tree is at that site", gene, position);
+    let Some(reference) = &state.reference else {
+        return Err(format!("{}; start the server with --reference to take it from the reference genome", unknown));
+    };
+    let index = position.checked_sub(1);
+    let residue = match gene {
+        "nt" => index.and_then(|index| reference.base(index)),
+        gene => {
+            let details = state.config.gene_details.get(gene).ok_or_else(|| format!("{} and gene {} isn't in gene_details", unknown, gene))?;
+            index.and_then(|index| reference.protein(details, &[]).get(index).copied())
+        }
+    };
+    residue.map(|residue| char::from(residue).to_string())
+        .ok_or_else(|| format!("{} and the --reference ends before it", unknown))
+}
+
+fn apply_site_mutations<'a>(
+    site_mutations: &HashMap<i32, &'a Mutation>,
+    residue: Option<&'a str>,
+    mutation_ids: &[i32],
+    reference: &mut Option<&'a str>,
+) -> Option<&'a str> {
+    let mut residue = residue;
+    for id in mutation_ids {
+        if let Some(m) = site_mutations.get(id) {
+            if residue.is_none() && reference.is_none() {
+                *reference = Some(m.previous_residue());
+            }
+            residue = Some(m.new_residue());
+        }
+    }
+    residue
+}
+
+// Detects metadata fields whose values are mostly numbers or dates and parses them once,
+// so range searches don't have to reparse strings per query.
+pub fn build_numeric_columns(nodes: &[Node]) -> HashMap<String, NumericColumn> {
+    let keys: HashSet<&String> = nodes.iter().flat_map(|n| n.meta.keys()).collect();
+    let mut columns = HashMap::new();
+
+    for key in keys {
+        let mut non_empty = 0;
+        let mut numbers = 0;
+        let mut dates = 0;
+        for node in nodes {
+            match node.meta.get(key) {
+                None | Some(Value::Null) => {}
+                Some(Value::String(s)) if s.is_empty() => {}
+                Some(value) => {
+                    non_empty += 1;
+                    if parse_number(value).is_some() {
+                        numbers += 1;
+                    } else if parse_date(&meta_value_string(value)).is_some() {
+                        dates += 1;
+                    }
+                }
+            }
+        }
+
+        let kind = if numbers * 2 > non_empty {
+            ColumnKind::Number
+        } else if dates * 2 > non_empty {
+            ColumnKind::Date
+        } else {
+            continue;
+        };
+        let values = nodes.iter()
+            .map(|n| n.meta.get(key).and_then(|v| parse_column_value(kind, v)))
+            .collect();
+        columns.insert(key.clone(), NumericColumn { kind, values });
+    }
+
+    columns
+}
+
+fn parse_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse::<f64>().ok().filter(|v| v.is_finite()),
+        _ => None,
+    }
+}
+
+fn parse_column_value(kind: ColumnKind, value: &Value) -> Option<f64> {
+    match kind {
+        ColumnKind::Number => parse_number(value),
+        ColumnKind::Date => match value {
+            Value::String(s) => parse_date(s),
+            _ => None,
+        },
+    }
+}
+
+// Accepts YYYY-MM-DD, YYYY-MM and YYYY; partial dates resolve to the start of the period.
+pub fn parse_date(s: &str) -> Option<f64> {
+    let mut parts = s.trim().split('-');
+    let year: i64 = parts.next().filter(|p| p.len() == 4)?.parse().ok()?;
+    let month: i64 = parts.next().map_or(Some(1), |p| p.parse().ok())?;
+    let day: i64 = parts.next().map_or(Some(1), |p| p.parse().ok())?;
+    if parts.next().is_some() || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some(days_from_civil(year, month, day) as f64)
+}
+
+// Howard Hinnant's days_from_civil
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// Returns nodes whose numeric/date field lies within [min, max]; either bound may be omitted.
+pub fn search_by_meta_range(
+    state: &AppState,
+    key: &str,
+    min: Option<&Value>,
+    max: Option<&Value>,
+    deadline: &Deadline,
+) -> Result<Vec<usize>, String> {
+    let field = meta_field_name(key);
+    let Some(column) = state.numeric_columns.get(field.as_ref()) else {
+        return Ok(Vec::new());
+    };
+
+    let bound = |value: Option<&Value>, name: &str| -> Result<Option<f64>, String> {
+        value
+            .map(|v| parse_column_value(column.kind, v).ok_or_else(|| format!("Invalid {} for {}: {}", name, key, v)))
+            .transpose()
+    };
+    let min = bound(min, "min")?.unwrap_or(f64::NEG_INFINITY);
+    let max = bound(max, "max")?.unwrap_or(f64::INFINITY);
+    if min > max {
+        return Err(format!("min must not be greater than max for {}", key));
+    }
+
+    Ok(deadline.watch(column.values.iter().enumerate())
+        .filter(|(_, v)| v.is_some_and(|v| v >= min && v <= max))
+        .map(|(idx, _)| idx)
+        .collect())
+}
+
+// Per-site state along the current root-to-node path: the residue replaced by the
+// first mutation at the site, and the residue after the most recent one.
+type SiteState<'a> = (&'a str, &'a str);
+type Site<'a> = (&'a str, usize);
+
+struct PathSites<'a> {
+    site_mutations: HashMap<i32, &'a Mutation>,
+    sites: HashMap<Site<'a>, SiteState<'a>>,
+    undo: Vec<(Site<'a>, Option<SiteState<'a>>)>,
+    // Number of sites currently back at their original residue after mutating
+    reverted: usize,
+}
+
+impl<'a> PathSites<'a> {
+    fn set(&mut self, site: Site<'a>, value: Option<SiteState<'a>>) {
+        let was_reverted = self.sites.get(&site).is_some_and(|(original, current)| original == current);
+        let is_reverted = value.is_some_and(|(original, current)| original == current);
+        match value {
+            Some(value) => self.sites.insert(site, value),
+            None => self.sites.remove(&site),
+        };
+        self.reverted = self.reverted + is_reverted as usize - was_reverted as usize;
+    }
+
+    fn apply(&mut self, mutation_ids: &[i32]) {
+        for id in mutation_ids {
+            let Some(&m) = self.site_mutations.get(id) else { continue };
+            let site = (m.gene(), m.residue_pos());
+            let previous = self.sites.get(&site).copied();
+            let original = previous.map_or(m.previous_residue(), |(original, _)| original);
+            self.undo.push((site, previous));
+            self.set(site, Some((original, m.new_residue())));
+        }
+    }
+
+    fn rollback(&mut self, undo_len: usize) {
+        while self.undo.len() > undo_len {
+            let (site, previous) = self.undo.pop().unwrap();
+            self.set(site, previous);
+        }
+    }
+}
+
+// Returns tips at which the most recent mutation at some matching site restores the
+// residue recorded as previous_residue by the first mutation at that site on the path.
+pub fn search_revertants(state: &AppState, gene: Option<&str>, position: Option<usize>, deadline: &Deadline) -> Vec<usize> {
+    let mut path = PathSites {
+        site_mutations: mutations_by_id(&state.config.mutations, gene, position),
+        sites: HashMap::new(),
+        undo: Vec::new(),
+        reverted: 0,
+    };
+
+    let mut result = Vec::new();
+    // (node index, Some(undo length to restore) once the node has been entered)
+    let mut stack: Vec<(usize, Option<usize>)> = state.root_ids.iter().filter_map(|id| state.node_index.get(id)).map(|&idx| (idx, None)).collect();
+    for _ in deadline.watch(iter::repeat(())) {
+        let Some((idx, entered)) = stack.pop() else { break };
+        if let Some(undo_len) = entered {
+            path.rollback(undo_len);
+            continue;
+        }
+
+        let node = &state.nodes[idx];
+        stack.push((idx, Some(path.undo.len())));
+        path.apply(&node.mutations);
+
+        match state.children.get(&node.node_id) {
+            Some(children) if !children.is_empty() => {
+                stack.extend(children.iter().map(|&child| (child, None)));
+            }
+            _ => {
+                if path.reverted > 0 {
+                    result.push(idx);
+                }
+            }
+        }
+    }
+
+    result.sort_unstable();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{fixture, state};
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn node_ids(state: &AppState, indexes: impl IntoIterator<Item = usize>) -> Vec<i32> {
+        indexes.into_iter().map(|idx| state.nodes[idx].node_id).collect()
+    }
+
+    //   0 ─┬─ 1 clade_a (UK) ─┬─ 2 alpha_1 (UK, tags x y)
+    //      │                  ├─ 3 alpha_2 (UK, tags y)
+    //      │                  └─ 4 Alpha_3 (FR, tags x x)
+    //      └─ 5 clade_b ──────┬─ 6 beta_1 (FR, no tags)
+    //                         ├─ 7 beta_2 (tags z)
+    //                         └─ 8 clade_z
+    fn search_tree() -> PathBuf {
+        let node = |id: i32, parent: i32, name: &str, meta: &str| {
+            format!(r#"{{"name":"{}","x_dist":{},"mutations":[],"node_id":{},"parent_id":{},"clades":{{}}{}}}"#, name, id.min(2), id, parent, meta)
+        };
+        let lines = [
+            r#"{"version":"1","mutations":[],"total_nodes":9,"config":{"gene_details":{},"num_tips":6}}"#.to_string(),
+            node(0, 0, "", ""),
+            node(1, 0, "clade_a", r#","meta_country":"UK""#),
+            node(2, 1, "alpha_1", r#","meta_country":"UK","meta_tags":["x","y"]"#),
+            node(3, 1, "alpha_2", r#","meta_country":"UK","meta_tags":["y"]"#),
+            node(4, 1, "Alpha_3", r#","meta_country":"FR","meta_tags":["x","x"]"#),
+            node(5, 0, "clade_b", ""),
+            node(6, 5, "beta_1", r#","meta_country":"FR","meta_tags":[]"#),
+            node(7, 5, "beta_2", r#","meta_tags":["z"]"#),
+            node(8, 5, "clade_z", ""),
+        ];
+        fixture("search_tree.jsonl", lines.join("\n").as_bytes())
+    }
+
+    // A search's hits by node_id, in the order run_search_request gives them
+    fn search(state: &AppState, spec: serde_json::Value) -> Vec<i32> {
+        let request: SearchRequest = serde_json::from_value(spec).unwrap();
+        node_ids(state, run_search_request(state, &request, &Deadline::none()).unwrap())
+    }
+
+    #[test]
+    fn boolean_searches() {
+        let state = state(&search_tree(), &[]);
+        let uk = json!({ "type": "meta", "key": "country", "value": "UK" });
+        let alpha = json!({ "type": "name", "text": "alpha" });
+        let has_y = json!({ "type": "meta_contains", "key": "tags", "value": "y" });
+        let boolean = |method: &str, subspecs: &[&Value]| json!({ "type": "boolean", "boolean_method": method, "subspecs": subspecs });
+
+        assert_eq!(search(&state, boolean("and", &[&uk, &alpha])), [2, 3]);
+        assert_eq!(search(&state, boolean("or", &[&alpha, &has_y, &uk])), [1, 2, 3]);
+        assert_eq!(search(&state, boolean("not", &[&uk, &has_y])), [1]);
+        assert_eq!(search(&state, boolean("not", &[&uk, &alpha, &has_y])), [1]);
+        // Nested, with the name scan and index lookups mixed
+        assert_eq!(search(&state, boolean("or", &[&boolean("not", &[&uk, &alpha]), &json!({ "type": "name", "text": "beta" })])), [1, 6, 7]);
+    }
+
+    #[test]
+    fn sorted_boolean_inputs_merge_to_the_same_hits_as_hashing() {
+        let inputs = [vec![1, 3, 5, 7, 9, 11], vec![0, 3, 4, 5, 11], vec![5, 6, 7, 8]];
+        for method in [BooleanMethod::And, BooleanMethod::Or, BooleanMethod::Not] {
+            let merged = combine_results(method, inputs.to_vec());
+            // Reversed, the inputs aren't sorted, so are combined through HashSets
+            let hashed = combine_results(method, inputs.iter().map(|r| r.iter().rev().copied().collect()).collect());
+            let mut hashed_sorted = hashed.clone();
+            hashed_sorted.sort_unstable();
+            assert_eq!(merged, hashed_sorted, "{:?}", method);
+            if !matches!(method, BooleanMethod::Or) {
+                // Both keep the first input's order
+                assert!(hashed.iter().rev().is_sorted(), "{:?}", method);
+            }
+        }
+        assert_eq!(combine_results(BooleanMethod::And, inputs.to_vec()), [5]);
+        assert_eq!(combine_results(BooleanMethod::Not, inputs.to_vec()), [1, 9]);
+        assert_eq!(combine_results(BooleanMethod::Or, inputs[1..].to_vec()), [0, 3, 4, 5, 6, 7, 8, 11]);
+        assert!(combine_results(BooleanMethod::And, Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn search_pages() {
+        let state = state(&search_tree(), &["--max-search-limit", "3"]);
+        let page = |offset: Option<usize>, limit: Option<usize>| {
+            let query: crate::SearchQuery = serde_json::from_value(json!({ "text": "_", "offset": offset, "limit": limit })).unwrap();
+            let result = crate::run_search_query(&state, &query, &Deadline::none()).unwrap();
+            let Some(crate::SearchHits::Full { matches, .. }) = result.hits else { panic!("expected every hit of the page") };
+            assert_eq!(result.fields["total_count"], 8);
+            (node_ids(&state, matches), result.fields["limit"].clone())
+        };
+        assert_eq!(page(Some(0), Some(2)), (vec![1, 2], json!(2)));
+        assert_eq!(page(Some(2), Some(2)), (vec![3, 4], json!(2)));
+        assert_eq!(page(Some(7), Some(2)), (vec![8], json!(2)));
+        assert_eq!(page(Some(20), Some(2)), (vec![], json!(2)));
+        // The limit is capped at --max-search-limit, and is the default with only an offset
+        assert_eq!(page(Some(1), Some(100)), (vec![2, 3, 4], json!(3)));
+        assert_eq!(page(Some(5), None), (vec![6, 7, 8], json!(3)));
+    }
+
+    #[test]
+    fn num_tips_searches_put_the_biggest_clades_first() {
+        let state = state(&search_tree(), &[]);
+        let nodes = &state.nodes;
+        let by_size = search_by_num_tips(nodes, Some(2), None, &Deadline::none());
+        assert_eq!(node_ids(&state, by_size.iter().copied()), [0, 1, 5]);
+        assert!(search_by_num_tips(nodes, Some(7), None, &Deadline::none()).is_empty());
+
+        // Ties are ordered by node_id, and a leading num_tips search ranks a boolean one
+        assert_eq!(search(&state, json!({ "type": "num_tips", "min": 3 })), [0, 1, 5]);
+        assert_eq!(search(&state, json!({ "type": "num_tips", "max": 1 })), [2, 3, 4, 6, 7, 8]);
+        let clades = json!({ "type": "boolean", "boolean_method": "and", "subspecs": [
+            { "type": "num_tips", "min": 2 },
+            { "type": "name", "text": "clade" },
+        ] });
+        assert_eq!(search(&state, clades), [1, 5]);
+    }
+
+    #[test]
+    fn autocomplete_puts_tips_first() {
+        let case_sensitive = state(&search_tree(), &[]);
+        let state = state(&search_tree(), &["--autocomplete-case-insensitive"]);
+        let complete = |prefix: &str, limit: usize, case_insensitive: bool| state.prefix_index.complete(&state.nodes, prefix, limit, case_insensitive);
+        assert_eq!(complete("alpha", 10, false), ["alpha_1", "alpha_2"]);
+        assert_eq!(complete("alpha", 10, true), ["alpha_1", "alpha_2", "Alpha_3"]);
+        assert_eq!(complete("ALPHA_3", 10, true), ["Alpha_3"]);
+        assert_eq!(complete("clade", 10, false), ["clade_z", "clade_a", "clade_b"]);
+        assert_eq!(complete("clade", 2, false), ["clade_z", "clade_a"]);
+        assert!(complete("gamma", 10, false).is_empty());
+
+        // Without the lowercased index a case-insensitive request matches case
+        assert!(!case_sensitive.prefix_index.supports_case_insensitive());
+        assert_eq!(case_sensitive.prefix_index.complete(&case_sensitive.nodes, "alpha", 10, true), ["alpha_1", "alpha_2"]);
+    }
+
+    #[test]
+    fn meta_index_lists_array_elements() {
+        let state = state(&search_tree(), &[]);
+        let index = MetaIndex::build(&state.nodes);
+        for (element, expected) in [("x", vec![2, 4]), ("y", vec![2, 3]), ("z", vec![7]), ("w", vec![])] {
+            let hits = index.lookup_contains("tags", &json!(element));
+            // Postings are sorted and list a node once, however often the element repeats
+            assert!(hits.windows(2).all(|pair| pair[0] < pair[1]), "{}", element);
+            let mut ids = node_ids(&state, hits.iter().copied());
+            ids.sort_unstable();
+            assert_eq!(ids, expected, "{}", element);
+            // The same as a scan
+            assert_eq!(hits, search_by_meta_contains(&state.nodes, "tags", &json!(element), &Deadline::none()), "{}", element);
+        }
+        // The whole array is indexed as a value too
+        let mut ids = node_ids(&state, index.lookup("meta_tags", &json!(["x", "x"])));
+        ids.sort_unstable();
+        assert_eq!(ids, [4]);
+        assert!(index.lookup_contains("country", &json!("UK")).is_empty());
+    }
+
+    // nt:5 is A>G on node 1 and back G>A on tip 2 beneath it:
+    //
+    //   0 ─┬─ 1 (A5G) ─┬─ 2 (G5A)
+    //      │           └─ 3
+    //      └─ 4
+    fn site_tree() -> PathBuf {
+        let lines = [
+            r#"{"version":"1","mutations":[{"gene":"nt","previous_residue":"A","residue_pos":5,"new_residue":"G","mutation_id":0,"type":"nt"},{"gene":"nt","previous_residue":"G","residue_pos":5,"new_residue":"A","mutation_id":1,"type":"nt"}],"total_nodes":5,"config":{"gene_details":{},"num_tips":3}}"#,
+            r#"{"name":"","x_dist":0,"mutations":[],"node_id":0,"parent_id":0,"clades":{}}"#,
+            r#"{"name":"","x_dist":1,"mutations":[0],"node_id":1,"parent_id":0,"clades":{}}"#,
+            r#"{"name":"tip_2","x_dist":2,"mutations":[1],"node_id":2,"parent_id":1,"clades":{}}"#,
+            r#"{"name":"tip_3","x_dist":2,"mutations":[],"node_id":3,"parent_id":1,"clades":{}}"#,
+            r#"{"name":"tip_4","x_dist":1,"mutations":[],"node_id":4,"parent_id":0,"clades":{}}"#,
+        ];
+        fixture("site_tree.jsonl", lines.join("\n").as_bytes())
+    }
+
+    // Each residue's tips, by node_id
+    fn genotypes(state: &AppState, gene: &str, position: usize) -> Result<Vec<(String, Vec<i32>)>, ApiError> {
+        let genotypes = site_genotypes(state, gene, position, &Deadline::none())?;
+        let mut genotypes: Vec<(String, Vec<i32>)> = genotypes.iter()
+            .map(|(residue, tips)| (residue.clone(), node_ids(state, tips.iter().copied())))
+            .collect();
+        for (_, tips) in &mut genotypes {
+            tips.sort_unstable();
+        }
+        genotypes.sort();
+        Ok(genotypes)
+    }
+
+    fn residues(genotypes: &[(&str, &[i32])]) -> Vec<(String, Vec<i32>)> {
+        genotypes.iter().map(|&(residue, tips)| (residue.to_string(), tips.to_vec())).collect()
+    }
+
+    #[test]
+    fn site_reverted_beneath_a_mutation() {
+        let state = state(&site_tree(), &[]);
+        // Tip 4 never mutated, so carries the first mutation's previous residue
+        assert_eq!(genotypes(&state, "nt", 5).unwrap(), residues(&[("A", &[2, 4]), ("G", &[3])]));
+    }
+
+    #[test]
+    fn site_never_mutated_needs_the_reference() {
+        let error = genotypes(&state(&site_tree(), &[]), "nt", 3).unwrap_err();
+        assert!(error.to_string().contains("--reference"), "{}", error);
+
+        let reference = fixture("site_tree.fa", b">ref\nACGTACGTACGT\n");
+        let state = state(&site_tree(), &["--reference", reference.to_str().unwrap()]);
+        assert_eq!(genotypes(&state, "nt", 3).unwrap(), residues(&[("G", &[2, 3, 4])]));
+        // The tree's own mutations still win over the reference at a site they are at
+        assert_eq!(genotypes(&state, "nt", 5).unwrap(), residues(&[("A", &[2, 4]), ("G", &[3])]));
+        let error = genotypes(&state, "nt", 13).unwrap_err();
+        assert!(error.to_string().contains("ends before it"), "{}", error);
+    }
+
+    #[test]
+    fn site_genotypes_survive_a_poisoned_cache() {
+        let state = state(&site_tree(), &[]);
+        let poisoned = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let _sites = state.genotype_cache.sites.lock().unwrap();
+                panic!("poisoning the genotype cache");
+            }).join()
+        });
+        assert!(poisoned.is_err() && state.genotype_cache.sites.is_poisoned());
+        assert_eq!(genotypes(&state, "nt", 5).unwrap(), residues(&[("A", &[2, 4]), ("G", &[3])]));
+    }
+
+    #[test]
+    fn revertants_are_tips_back_at_the_original_residue() {
+        let state = state(&site_tree(), &[]);
+        let revertants = |gene, position| node_ids(&state, search_revertants(&state, gene, position, &Deadline::none()));
+        assert_eq!(revertants(None, None), [2]);
+        assert_eq!(revertants(Some("nt"), Some(5)), [2]);
+        assert!(revertants(Some("nt"), Some(3)).is_empty());
+        assert!(revertants(Some("S"), None).is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::load_error::LoadError;
+use crate::{GeneDetail, Mutation};
+
+// Amino acids of the standard genetic code, by codon with the bases in TCAG order
+const CODONS: &[u8; 64] = b"FFLLSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG";
+
+// The genome from --reference, that nodes' sequences are rebuilt from
+pub struct Reference {
+    pub name: String,
+    // Upper case
+    sequence: Vec<u8>,
+}
+
+impl Reference {
+    // Reads the first record of the FASTA file at `path`
+    pub fn load(path: &str) -> Result<Reference, LoadError> {
+        let source = crate::describe_path(path);
+        let error = |message: &str| LoadError::Reference(format!("{}: {}", source, message));
+        let mut name = None;
+        let mut sequence = Vec::new();
+        for (i, line) in crate::open_reader(Path::new(path))?.lines().enumerate() {
+            let line = line.map_err(|e| LoadError::reading(i + 1, e))?;
+            let line = line.trim();
+            match line.strip_prefix('>') {
+                Some(_) if name.is_some() => {
+                    warn!("{} holds more than one sequence; only the first is used", source);
+                    break;
+                }
+                Some(header) => name = Some(header.split_whitespace().next().unwrap_or("").to_string()),
+                None if name.is_none() && !line.is_empty() => return Err(error("it doesn't start with a >header line")),
+                None => sequence.extend(line.bytes().map(|base| base.to_ascii_uppercase())),
+            }
+        }
+        let name = name.ok_or_else(|| error("the file holds no sequence"))?;
+        if sequence.is_empty() {
+            return Err(error("the sequence is empty"));
+        }
+        info!("Loaded reference {} ({} bases) from {}", name, sequence.len(), source);
+        Ok(Reference { name, sequence })
+    }
+
+    // Warns about genes that don't fit in the reference
+    pub fn check_genes(&self, genes: &HashMap<String, GeneDetail>) {
+        for gene in genes.values().filter(|gene| gene.end > self.sequence.len()) {
+            warn!("Gene {} ends at {}, beyond the end of reference {} at {}", gene.name, gene.end, self.name, self.sequence.len());
+        }
+    }
+
+    // The base at 0-based `index`
+    pub fn base(&self, index: usize) -> Option<u8> {
+        self.sequence.get(index).copied()
+    }
+
+    // The genome with the nucleotide mutations in `path`, root first, applied; where
+    // several hit a site, the last wins
+    pub fn genome(&self, path: &[&Mutation]) -> Vec<u8> {
+        let mut genome = self.sequence.clone();
+        for mutation in path {
+            if let Mutation::NT { residue_pos, new_residue, .. } = mutation {
+                if let (Some(base), Some(&new)) = (residue_pos.checked_sub(1).and_then(|i| genome.get_mut(i)), new_residue.as_bytes().first()) {
+                    *base = new;
+                }
+            }
+        }
+        genome
+    }
+
+    // The coding sequence of `gene` in the genome `path` leads to, reverse complemented
+    // for genes on the reverse strand
+    pub fn coding(&self, gene: &GeneDetail, path: &[&Mutation]) -> Vec<u8> {
+        let genome = self.genome(path);
+        let whole = [[gene.start, gene.end]];
+        let segments = if gene.segments.is_empty() { &whole[..] } else { &gene.segments[..] };
+        let mut coding: Vec<u8> = segments.iter()
+            .flat_map(|&[start, end]| &genome[start.min(genome.len())..end.min(genome.len())])
+            .copied()
+            .collect();
+        if gene.strand < 0 {
+            coding.reverse();
+            for base in &mut coding {
+                *base = complement(*base);
+            }
+        }
+        coding
+    }
+
+    // The reference's protein for `gene` with the gene's amino acid mutations in `path`
+    // applied, the last winning at each residue, as the mutations in the tree are what
+    // say which residues changed
+    pub fn protein(&self, gene: &GeneDetail, path: &[&Mutation]) -> Vec<u8> {
+        let mut protein = translate(&self.coding(gene, &[]));
+        for mutation in path {
+            if let Mutation::AA { gene: name, residue_pos, new_residue, .. } = mutation {
+                if *name != gene.name {
+                    continue;
+                }
+                if let (Some(residue), Some(&new)) = (residue_pos.checked_sub(1).and_then(|i| protein.get_mut(i)), new_residue.as_bytes().first()) {
+                    *residue = new;
+                }
+            }
+        }
+        protein
+    }
+}
+
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'T' => b'A',
+        b'C' => b'G',
+        b'G' => b'C',
+        other => other,
+    }
+}
+
+// X for codons with anything but ACGT; a trailing partial codon is dropped
+fn translate(coding: &[u8]) -> Vec<u8> {
+    let index = |base: u8| b"TCAG".iter().position(|&b| b == base);
+    coding.chunks_exact(3)
+        .map(|codon| match (index(codon[0]), index(codon[1]), index(codon[2])) {
+            (Some(a), Some(b), Some(c)) => CODONS[a * 16 + b * 4 + c],
+            _ => b'X',
+        })
+        .collect()
+}
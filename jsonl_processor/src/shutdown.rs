@@ -0,0 +1,38 @@
+use actix_web::dev::ServerHandle;
+use actix_web::rt::signal;
+use std::time::Duration;
+use tracing::{error, info};
+
+// Stops the server gracefully on SIGTERM or SIGINT: the listeners close at once, and
+// requests in flight, streamed /nodes/ bodies included, get up to `grace` to finish
+// before their connections are dropped.
+pub async fn on_signal(server: ServerHandle, grace: Duration) {
+    let signal = match wait_for_signal().await {
+        Ok(signal) => signal,
+        Err(e) => {
+            error!("Failed to listen for shutdown signals: {}", e);
+            return;
+        }
+    };
+    info!("{} received; finishing in-flight requests for up to {:?}", signal, grace);
+    server.stop(true).await;
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> std::io::Result<&'static str> {
+    use futures_util::FutureExt;
+    use signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    futures_util::select_biased! {
+        _ = terminate.recv().fuse() => Ok("SIGTERM"),
+        _ = interrupt.recv().fuse() => Ok("SIGINT"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> std::io::Result<&'static str> {
+    signal::ctrl_c().await?;
+    Ok("Ctrl-C")
+}
@@ -0,0 +1,157 @@
+use flate2::Crc;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::args::{Args, KeyFilter};
+use crate::watch::FileSignature;
+use crate::{msgpack, Metadata, Node};
+
+const MAGIC: &[u8] = b"taxrust snapshot\n";
+
+// Bump whenever Metadata, Node or Header change shape, so older snapshots are ignored
+// rather than misread
+const FORMAT: u32 = 3;
+
+// A snapshot is MAGIC, then length-prefixed MessagePack records: the header, the
+// metadata line and then each node in file order, followed by a CRC-32 of the records
+// so corruption is caught rather than served
+#[derive(Serialize, Deserialize, PartialEq)]
+struct Header {
+    format: u32,
+    version: String,
+    // The data file it was made from, as modification time in nanoseconds and size
+    source_modified: u64,
+    source_size: u64,
+    // The metadata fields its nodes were loaded with
+    key_filter: KeyFilter,
+    // The options deciding which nodes were kept: --lenient skips and detaches nodes a
+    // strict load would fail on, and --forest keeps the trees besides the first
+    lenient: bool,
+    lenient_max_skipped: f64,
+    forest: bool,
+    nodes: u64,
+}
+
+impl Header {
+    fn new(source: FileSignature, args: &Args, nodes: usize) -> Header {
+        Header {
+            format: FORMAT,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            source_modified: source.0.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64,
+            source_size: source.1,
+            key_filter: args.key_filter.clone(),
+            lenient: args.lenient,
+            lenient_max_skipped: args.lenient_max_skipped,
+            forest: args.forest,
+            nodes: nodes as u64,
+        }
+    }
+}
+
+// A node as stored: its fields in order, without the flattened metadata map that makes
+// deserializing a Node buffer every field first
+type NodeRecord = (String, f64, f64, Option<f64>, Vec<i32>, i32, i32, i32, BTreeMap<String, String>, BTreeMap<String, Value>);
+
+fn to_record(node: &Node) -> impl Serialize + '_ {
+    (&node.name, node.x_dist, node.y, node.x_time, &node.mutations, node.parent_id, node.node_id, node.num_tips, &node.clades, &node.meta)
+}
+
+fn from_record(record: NodeRecord) -> Node {
+    let (name, x_dist, y, x_time, mutations, parent_id, node_id, num_tips, clades, meta) = record;
+    Node { name, x_dist, y, x_time, mutations, parent_id, node_id, num_tips, clades, meta }
+}
+
+// Why a snapshot wasn't used
+pub enum Unusable {
+    Missing,
+    // Made from another version of the data file, or by another build
+    Stale,
+    Corrupt(String),
+}
+
+// The metadata and nodes saved in the snapshot at `path`, if it was made from the data
+// file as it is now, i.e. with signature `source`, under the same loading options in
+// `args`. Nodes are passed to `each_node` as they are read.
+pub fn read(path: &Path, source: FileSignature, args: &Args, mut each_node: impl FnMut(Node)) -> Result<Metadata, Unusable> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(Unusable::Missing),
+        Err(e) => return Err(Unusable::Corrupt(e.to_string())),
+    };
+    let mut reader = BufReader::new(file);
+    let mut magic = vec![0; MAGIC.len()];
+    reader.read_exact(&mut magic).map_err(|e| Unusable::Corrupt(e.to_string()))?;
+    if magic != MAGIC {
+        return Err(Unusable::Corrupt("not a snapshot file".to_string()));
+    }
+
+    let mut record = Vec::new();
+    let mut crc = Crc::new();
+    // A header that can't be decoded is from a build with another layout
+    let header: Header = next(&mut reader, &mut record, &mut crc).map_err(|_| Unusable::Stale)?;
+    if header != Header::new(source, args, header.nodes as usize) {
+        return Err(Unusable::Stale);
+    }
+    let metadata = next(&mut reader, &mut record, &mut crc).map_err(Unusable::Corrupt)?;
+    for _ in 0..header.nodes {
+        each_node(from_record(next(&mut reader, &mut record, &mut crc).map_err(Unusable::Corrupt)?));
+    }
+    let mut expected = [0; 4];
+    reader.read_exact(&mut expected).map_err(|e| Unusable::Corrupt(e.to_string()))?;
+    if u32::from_le_bytes(expected) != crc.sum() {
+        return Err(Unusable::Corrupt("checksum mismatch".to_string()));
+    }
+    Ok(metadata)
+}
+
+// Saves a snapshot of the data file with signature `source` at `path`. It is written
+// beside it first and renamed into place, so a crash can't leave half a snapshot.
+pub fn write(path: &Path, source: FileSignature, args: &Args, metadata: &Metadata, nodes: &[Node]) -> io::Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let mut writer = BufWriter::new(File::create(&partial)?);
+    writer.write_all(MAGIC)?;
+    let mut record = Vec::new();
+    let mut crc = Crc::new();
+    write_record(&mut writer, &mut record, &mut crc, &Header::new(source, args, nodes.len()))?;
+    write_record(&mut writer, &mut record, &mut crc, metadata)?;
+    for node in nodes {
+        write_record(&mut writer, &mut record, &mut crc, &to_record(node))?;
+    }
+    writer.write_all(&crc.sum().to_le_bytes())?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&partial, path)
+}
+
+fn write_record<T: Serialize>(writer: &mut impl Write, record: &mut Vec<u8>, crc: &mut Crc, value: &T) -> io::Result<()> {
+    record.clear();
+    msgpack::to_writer(record, value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    crc.update(record);
+    writer.write_all(&(record.len() as u32).to_le_bytes())?;
+    writer.write_all(record)
+}
+
+// Reads the next record into `record` and decodes it
+fn next<T: DeserializeOwned>(reader: &mut impl Read, record: &mut Vec<u8>, crc: &mut Crc) -> Result<T, String> {
+    read_record(reader, record).map_err(|e| e.to_string())?;
+    crc.update(record);
+    msgpack::from_slice(record).map_err(|e| e.to_string())
+}
+
+fn read_record(reader: &mut impl Read, record: &mut Vec<u8>) -> io::Result<()> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    // Read through take() so a corrupt length can't ask for more memory than the file holds
+    let len = u32::from_le_bytes(len) as u64;
+    record.clear();
+    if reader.take(len).read_to_end(record)? as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
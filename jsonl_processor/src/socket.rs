@@ -0,0 +1,36 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use tracing::warn;
+
+// Binds the Unix socket for --unix-socket, then applies --unix-socket-mode and
+// --unix-socket-owner. actix removes the socket when the server stops; one left by a
+// server that crashed is removed here first, but not one with a server still accepting
+// on it, nor anything that isn't a socket.
+pub fn bind(path: &Path, mode: Option<u32>, owner: (Option<u32>, Option<u32>)) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "a file that isn't a socket is in the way"));
+        }
+        Ok(_) => match UnixStream::connect(path) {
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse, "another server is listening on it")),
+            Err(_) => {
+                warn!("Removing stale socket {}", path.display());
+                fs::remove_file(path)?;
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    if owner != (None, None) {
+        std::os::unix::fs::chown(path, owner.0, owner.1)?;
+    }
+    Ok(listener)
+}
@@ -0,0 +1,172 @@
+use std::ops::{Range, RangeInclusive};
+
+use crate::Node;
+
+// Average number of nodes per grid cell, used to size the grid
+const TARGET_NODES_PER_CELL: usize = 16;
+
+// Uniform grid over node coordinates. Each cell's node indexes are stored
+// contiguously in `entries`, starting at `cell_starts[cell]`.
+pub struct SpatialGrid {
+    min_x: f64,
+    min_y: f64,
+    cell_width: f64,
+    cell_height: f64,
+    cols: usize,
+    rows: usize,
+    cell_starts: Vec<u32>,
+    entries: Vec<u32>,
+}
+
+impl SpatialGrid {
+    // `coords` picks the (x, y) used for each node; nodes with non-finite coordinates are left out
+    pub fn build(nodes: &[Node], coords: impl Fn(&Node) -> (f64, f64)) -> SpatialGrid {
+        let points: Vec<(usize, f64, f64)> = nodes.iter()
+            .enumerate()
+            .map(|(idx, node)| {
+                let (x, y) = coords(node);
+                (idx, x, y)
+            })
+            .filter(|&(_, x, y)| x.is_finite() && y.is_finite())
+            .collect();
+
+        let (mut min_x, mut max_x, mut min_y, mut max_y) = (f64::MAX, f64::MIN, f64::MAX, f64::MIN);
+        for &(_, x, y) in &points {
+            min_x = min_x.min(x);
+            max_x = max_x.max(x);
+            min_y = min_y.min(y);
+            max_y = max_y.max(y);
+        }
+        if points.is_empty() {
+            (min_x, max_x, min_y, max_y) = (0.0, 0.0, 0.0, 0.0);
+        }
+
+        // Cell size follows the density: roughly TARGET_NODES_PER_CELL nodes per cell on average
+        let side = ((points.len() / TARGET_NODES_PER_CELL) as f64).sqrt().ceil().max(1.0) as usize;
+        let extent = |min: f64, max: f64| if max > min { (max - min) / side as f64 } else { 1.0 };
+        let mut grid = SpatialGrid {
+            min_x,
+            min_y,
+            cell_width: extent(min_x, max_x),
+            cell_height: extent(min_y, max_y),
+            cols: side,
+            rows: side,
+            cell_starts: vec![0; side * side + 1],
+            entries: vec![0; points.len()],
+        };
+
+        let cells: Vec<usize> = points.iter().map(|&(_, x, y)| grid.cell(x, y)).collect();
+        for &cell in &cells {
+            grid.cell_starts[cell + 1] += 1;
+        }
+        for cell in 0..side * side {
+            grid.cell_starts[cell + 1] += grid.cell_starts[cell];
+        }
+        let mut next = grid.cell_starts.clone();
+        for (&(idx, _, _), &cell) in points.iter().zip(&cells) {
+            grid.entries[next[cell] as usize] = idx as u32;
+            next[cell] += 1;
+        }
+        grid
+    }
+
+    fn col(&self, x: f64) -> usize {
+        (((x - self.min_x) / self.cell_width).floor().max(0.0) as usize).min(self.cols - 1)
+    }
+
+    fn row(&self, y: f64) -> usize {
+        (((y - self.min_y) / self.cell_height).floor().max(0.0) as usize).min(self.rows - 1)
+    }
+
+    fn cell(&self, x: f64, y: f64) -> usize {
+        self.row(y) * self.cols + self.col(x)
+    }
+
+    fn cell_entries(&self, col: usize, row: usize) -> &[u32] {
+        let cell = row * self.cols + col;
+        &self.entries[self.cell_starts[cell] as usize..self.cell_starts[cell + 1] as usize]
+    }
+
+    // Columns and rows of the cells overlapping a box, or None if it misses the grid
+    fn cells_overlapping(&self, min_x: f64, max_x: f64, min_y: f64, max_y: f64) -> Option<(RangeInclusive<usize>, RangeInclusive<usize>)> {
+        let grid_max_x = self.min_x + self.cell_width * self.cols as f64;
+        let grid_max_y = self.min_y + self.cell_height * self.rows as f64;
+        if self.entries.is_empty() || max_x < self.min_x || min_x > grid_max_x || max_y < self.min_y || min_y > grid_max_y {
+            return None;
+        }
+        Some((self.col(min_x)..=self.col(max_x), self.row(min_y)..=self.row(max_y)))
+    }
+
+    // The stretch of `entries` for each row of cells overlapping a box; a row's cells are adjacent
+    fn overlapping_spans(&self, min_x: f64, max_x: f64, min_y: f64, max_y: f64) -> impl Iterator<Item = Range<usize>> + '_ {
+        self.cells_overlapping(min_x, max_x, min_y, max_y).into_iter().flat_map(move |(cols, rows)| {
+            rows.map(move |row| {
+                let first = row * self.cols + cols.start();
+                let last = row * self.cols + cols.end();
+                self.cell_starts[first] as usize..self.cell_starts[last + 1] as usize
+            })
+        })
+    }
+
+    // How many nodes `candidates` would return, without visiting them
+    pub fn candidate_count(&self, min_x: f64, max_x: f64, min_y: f64, max_y: f64) -> usize {
+        self.overlapping_spans(min_x, max_x, min_y, max_y).map(|span| span.len()).sum()
+    }
+
+    // Node indexes in the cells overlapping a box: a superset of the nodes inside it
+    pub fn candidates(&self, min_x: f64, max_x: f64, min_y: f64, max_y: f64) -> impl Iterator<Item = usize> + '_ {
+        self.overlapping_spans(min_x, max_x, min_y, max_y)
+            .flat_map(|span| &self.entries[span])
+            .map(|&idx| idx as usize)
+    }
+
+    pub fn memory_bytes(&self) -> usize {
+        (self.cell_starts.len() + self.entries.len()) * std::mem::size_of::<u32>()
+    }
+
+    // Closest node to (x, y) accepted by `keep`, by Euclidean distance after multiplying
+    // each axis by its scale. Returns (node index, scaled distance).
+    pub fn nearest(
+        &self,
+        nodes: &[Node],
+        coords: impl Fn(&Node) -> (f64, f64),
+        (x, y): (f64, f64),
+        (scale_x, scale_y): (f64, f64),
+        keep: impl Fn(usize) -> bool,
+    ) -> Option<(usize, f64)> {
+        let (center_col, center_row) = (self.col(x), self.row(y));
+        // Anything in ring r + 1 or beyond is at least this far away per ring
+        let ring_gap = (self.cell_width * scale_x).min(self.cell_height * scale_y);
+        let mut best: Option<(usize, f64)> = None;
+
+        for ring in 0..self.cols.max(self.rows) {
+            if let Some((_, distance)) = best {
+                if ring > 0 && (ring - 1) as f64 * ring_gap > distance {
+                    break;
+                }
+            }
+            let cols = center_col.saturating_sub(ring)..=(center_col + ring).min(self.cols - 1);
+            let rows = center_row.saturating_sub(ring)..=(center_row + ring).min(self.rows - 1);
+            for row in rows.clone() {
+                for col in cols.clone() {
+                    // Only the cells on this ring; inner ones were visited already
+                    if row.abs_diff(center_row) != ring && col.abs_diff(center_col) != ring {
+                        continue;
+                    }
+                    for &idx in self.cell_entries(col, row) {
+                        let idx = idx as usize;
+                        if !keep(idx) {
+                            continue;
+                        }
+                        let (node_x, node_y) = coords(&nodes[idx]);
+                        let distance = ((node_x - x) * scale_x).hypot((node_y - y) * scale_y);
+                        if best.is_none_or(|(_, best_distance)| distance < best_distance) {
+                            best = Some((idx, distance));
+                        }
+                    }
+                }
+            }
+        }
+        best
+    }
+}
@@ -0,0 +1,146 @@
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::web::Bytes;
+use serde::Serialize;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use crate::msgpack;
+
+// Approximate size of each chunk of a streamed response
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Serialization formats for structured responses
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    MsgPack,
+}
+
+impl Format {
+    // From an explicit format= parameter, falling back to the Accept header
+    pub fn from_request(requested: Option<&str>, headers: &HeaderMap) -> Result<Format, String> {
+        match requested {
+            Some("json") => Ok(Format::Json),
+            Some("msgpack") => Ok(Format::MsgPack),
+            Some(other) => Err(format!("Unknown format: {}", other)),
+            None => {
+                let accepts_msgpack = headers.get(header::ACCEPT)
+                    .and_then(|accept| accept.to_str().ok())
+                    .is_some_and(|accept| accept.contains("application/msgpack") || accept.contains("application/x-msgpack"));
+                Ok(if accepts_msgpack { Format::MsgPack } else { Format::Json })
+            }
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MsgPack => "application/msgpack",
+        }
+    }
+
+    pub fn write(self, out: &mut Vec<u8>, value: &(impl Serialize + ?Sized)) -> Result<(), Box<dyn Error>> {
+        match self {
+            Format::Json => serde_json::to_writer(out, value)?,
+            Format::MsgPack => msgpack::to_writer(out, value)?,
+        }
+        Ok(())
+    }
+
+    pub fn to_vec(self, value: &(impl Serialize + ?Sized)) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut out = Vec::new();
+        self.write(&mut out, value)?;
+        Ok(out)
+    }
+}
+
+// Streams a document containing one large array, serializing the array's items one at
+// a time so memory per response is bounded by the chunk size rather than the size of
+// the whole body.
+pub struct ArrayChunks<F> {
+    format: Format,
+    // Everything up to and including the start of the array
+    prefix: Option<Vec<u8>>,
+    // Everything after the array
+    suffix: Option<Vec<u8>>,
+    len: usize,
+    position: usize,
+    write_item: F,
+    // Time spent serializing items, reported once the body is complete
+    serialize_time: Duration,
+}
+
+impl<F> ArrayChunks<F>
+where
+    F: FnMut(usize, &mut Vec<u8>) -> Result<(), Box<dyn Error>>,
+{
+    // `document` is the response with the array at `key` left empty; `write_item(i, out)`
+    // appends the i-th of `len` items, in `format`.
+    pub fn new(format: Format, document: &impl Serialize, key: &str, len: usize, write_item: F) -> ArrayChunks<F> {
+        let document = format.to_vec(document).unwrap_or_default();
+        // The empty array to split at, and what replaces it at the start of the array
+        let (marker, opening) = match format {
+            Format::Json => (format!("\"{}\":[]", key).into_bytes(), format!("\"{}\":[", key).into_bytes()),
+            Format::MsgPack => {
+                let mut marker = format.to_vec(key).unwrap_or_default();
+                let mut opening = marker.clone();
+                marker.push(0x90);
+                opening.push(0xdd);
+                opening.extend_from_slice(&(len as u32).to_be_bytes());
+                (marker, opening)
+            }
+        };
+        let (prefix, suffix) = match document.windows(marker.len()).position(|window| window == marker) {
+            Some(at) => {
+                let mut prefix = document[..at].to_vec();
+                prefix.extend_from_slice(&opening);
+                let closing = if format == Format::Json { "]" } else { "" };
+                (prefix, [closing.as_bytes(), &document[at + marker.len()..]].concat())
+            }
+            None => (document, Vec::new()),
+        };
+        ArrayChunks {
+            format,
+            prefix: Some(prefix),
+            suffix: Some(suffix),
+            len,
+            position: 0,
+            write_item,
+            serialize_time: Duration::ZERO,
+        }
+    }
+}
+
+impl<F> Iterator for ArrayChunks<F>
+where
+    F: FnMut(usize, &mut Vec<u8>) -> Result<(), Box<dyn Error>>,
+{
+    type Item = Result<Bytes, actix_web::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut out = self.prefix.take().unwrap_or_default();
+        let start = Instant::now();
+        while out.len() < CHUNK_SIZE && self.position < self.len {
+            if self.position > 0 && self.format == Format::Json {
+                out.push(b',');
+            }
+            if let Err(e) = (self.write_item)(self.position, &mut out) {
+                return Some(Err(crate::error::ApiError::internal("Failed to serialize response", e).into()));
+            }
+            self.position += 1;
+        }
+        self.serialize_time += start.elapsed();
+        if self.position == self.len {
+            if let Some(suffix) = self.suffix.take() {
+                out.extend_from_slice(&suffix);
+                tracing::debug!(items = self.len, format = ?self.format, "Serialized in {:?}", self.serialize_time);
+            }
+        }
+
+        if out.is_empty() {
+            None
+        } else {
+            Some(Ok(Bytes::from(out)))
+        }
+    }
+}
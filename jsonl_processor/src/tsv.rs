@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::path::Path;
+use tracing::warn;
+
+use crate::load_error::LoadError;
+
+// Unmatched names quoted when reporting what a TSV matched
+const UNMATCHED_EXAMPLES: usize = 5;
+
+// A TSV whose rows are matched to nodes by the names in one column, as --metadata-tsv
+// and --acknowledgements take. Fields are plain text with no quoting; the header names
+// the columns.
+pub struct KeyedTsv {
+    // The option it was given with, for messages
+    flag: &'static str,
+    path: String,
+    pub columns: Vec<String>,
+    pub key_index: usize,
+    lines: io::Lines<Box<dyn BufRead>>,
+}
+
+// How a TSV's rows matched the nodes
+pub struct Matched {
+    pub rows: usize,
+    pub unmatched: Vec<String>,
+}
+
+impl Matched {
+    // A few names that matched nothing, to go after the summary
+    pub fn examples(&self) -> String {
+        match self.unmatched.is_empty() {
+            true => String::new(),
+            false => format!("; no node is named e.g. {}", self.unmatched[..self.unmatched.len().min(UNMATCHED_EXAMPLES)].join(", ")),
+        }
+    }
+}
+
+impl KeyedTsv {
+    pub fn open(flag: &'static str, path: &str, key_column: &str) -> Result<KeyedTsv, LoadError> {
+        let mut lines = crate::open_reader(Path::new(path))?.lines();
+        let header = lines.next().ok_or_else(|| error(flag, path, "the file is empty"))?.map_err(|e| LoadError::reading(1, e))?;
+        let columns: Vec<String> = header.trim_end_matches('\r').split('\t').map(str::to_string).collect();
+        let key_index = columns.iter().position(|column| column == key_column).ok_or_else(|| {
+            error(flag, path, format_args!("there is no {} column to match node names against (see --metadata-key)", key_column))
+        })?;
+        Ok(KeyedTsv { flag, path: path.to_string(), columns, key_index, lines })
+    }
+
+    pub fn describe_path(&self) -> &str {
+        crate::describe_path(&self.path)
+    }
+
+    // Passes each row naming nodes in `by_name` to `each_row`, with the indexes of those
+    // nodes. A name given more than once takes its last row.
+    pub fn match_rows(
+        self,
+        by_name: &HashMap<String, Vec<usize>>,
+        mut each_row: impl FnMut(&[usize], &[&str]),
+    ) -> Result<Matched, LoadError> {
+        let KeyedTsv { flag, path, columns, key_index, lines } = self;
+        let mut rows: HashMap<String, usize> = HashMap::new();
+        let mut unmatched = Vec::new();
+        for (i, line) in lines.enumerate() {
+            let number = i + 2;
+            let line = line.map_err(|e| LoadError::reading(number, e))?;
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != columns.len() {
+                let message = format_args!("line {} has {} fields, but the header has {}", number, fields.len(), columns.len());
+                return Err(error(flag, &path, message));
+            }
+            let name = fields[key_index];
+            let Some(indexes) = by_name.get(name) else {
+                unmatched.push(name.to_string());
+                continue;
+            };
+            if let Some(previous) = rows.insert(name.to_string(), number) {
+                warn!("{} has rows for {} on both line {} and line {}; the last is used", crate::describe_path(&path), name, previous, number);
+            }
+            each_row(indexes, &fields);
+        }
+        Ok(Matched { rows: rows.len(), unmatched })
+    }
+}
+
+fn error(flag: &'static str, path: &str, message: impl fmt::Display) -> LoadError {
+    LoadError::Tsv { flag, message: format!("{}: {}", crate::describe_path(path), message) }
+}
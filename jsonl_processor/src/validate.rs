@@ -0,0 +1,104 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+// Offending nodes quoted of each kind when reporting them
+const EXAMPLES: usize = 5;
+
+// Why some nodes can't reach a root by following their parents
+#[derive(Debug, Default)]
+pub struct TreeProblems {
+    // (node_id, parent_id) of nodes whose parent isn't in the file
+    pub dangling: Vec<(i32, i32)>,
+    // The node_ids around each cycle of parents
+    pub cycles: Vec<Vec<i32>>,
+    // Nodes besides the roots that are their own parent
+    pub extra_roots: Vec<i32>,
+    // Nodes beneath any of those, which are detached along with them
+    pub detached: usize,
+}
+
+impl TreeProblems {
+    pub fn is_empty(&self) -> bool {
+        self.detached == 0
+    }
+}
+
+impl fmt::Display for TreeProblems {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut problems = Vec::new();
+        if !self.dangling.is_empty() {
+            let examples: Vec<String> = self.dangling.iter().take(EXAMPLES).map(|(id, parent)| format!("{} (parent {})", id, parent)).collect();
+            problems.push(format!("{} nodes have a parent_id no node has, e.g. {}", self.dangling.len(), examples.join(", ")));
+        }
+        if !self.cycles.is_empty() {
+            let examples: Vec<String> = self.cycles.iter()
+                .take(EXAMPLES)
+                .map(|cycle| cycle.iter().chain(cycle.first()).map(i32::to_string).collect::<Vec<_>>().join(" -> "))
+                .collect();
+            problems.push(format!("{} cycles of parents, e.g. {}", self.cycles.len(), examples.join("; ")));
+        }
+        if !self.extra_roots.is_empty() {
+            let examples: Vec<String> = self.extra_roots.iter().take(EXAMPLES).map(i32::to_string).collect();
+            problems.push(format!(
+                "{} more nodes are their own parent, e.g. {}, which --forest loads as trees of their own",
+                self.extra_roots.len(),
+                examples.join(", ")
+            ));
+        }
+        write!(f, "{}; {} nodes in all don't lead to a root", problems.join("; "), self.detached)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Reaches {
+    // On the path being followed
+    Visiting,
+    Root,
+    Nowhere,
+}
+
+// Follows parents from every node in `ids` to check that each leads to one of `root_ids`
+// within as many steps as there are nodes. Returns the nodes that don't, with what they
+// run into instead. `child_to_parent` has every node but those that are their own parent.
+pub fn check_tree(ids: &[i32], child_to_parent: &HashMap<i32, i32>, root_ids: &[i32]) -> (HashSet<i32>, TreeProblems) {
+    let known: HashSet<i32> = ids.iter().copied().collect();
+    let mut reaches: HashMap<i32, Reaches> = HashMap::with_capacity(ids.len());
+    reaches.extend(root_ids.iter().map(|&id| (id, Reaches::Root)));
+    let mut problems = TreeProblems::default();
+    let mut path = Vec::new();
+    for &id in ids {
+        let mut current = id;
+        // Each node is on the path at most once, so this ends within ids.len() steps
+        let outcome = loop {
+            match reaches.get(&current) {
+                Some(Reaches::Visiting) => {
+                    let start = path.iter().position(|&n| n == current).unwrap_or(0);
+                    problems.cycles.push(path[start..].to_vec());
+                    break Reaches::Nowhere;
+                }
+                Some(&outcome) => break outcome,
+                None => {}
+            }
+            reaches.insert(current, Reaches::Visiting);
+            path.push(current);
+            match child_to_parent.get(&current) {
+                Some(parent) if known.contains(parent) => current = *parent,
+                Some(&parent) => {
+                    problems.dangling.push((current, parent));
+                    break Reaches::Nowhere;
+                }
+                None => {
+                    problems.extra_roots.push(current);
+                    break Reaches::Nowhere;
+                }
+            }
+        };
+        for node in path.drain(..) {
+            reaches.insert(node, outcome);
+        }
+    }
+
+    let detached: HashSet<i32> = reaches.into_iter().filter(|&(_, r)| r == Reaches::Nowhere).map(|(id, _)| id).collect();
+    problems.detached = detached.len();
+    (detached, problems)
+}
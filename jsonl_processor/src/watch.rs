@@ -0,0 +1,67 @@
+use actix_web::web;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, info_span, warn};
+
+use crate::dataset::Dataset;
+
+// How often the data file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// What identifies a version of the data file: its modification time and size
+pub type FileSignature = (SystemTime, u64);
+
+// None while the file is missing, e.g. between a pipeline removing and rewriting it
+pub fn file_signature(path: &Path) -> Option<FileSignature> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+// Watches the data file for --watch by polling its signature, reloading once a change
+// has stayed put for --watch-debounce so a file still being written isn't read half
+// way. `loaded` is the signature of the version currently served. A version that fails
+// to load is not retried; the next change to the file is.
+pub fn spawn(dataset: web::Data<Dataset>, mut loaded: Option<FileSignature>) {
+    let debounce = Duration::from_secs(dataset.args.watch_debounce);
+    info!("Watching {} for changes", dataset.path);
+    thread::spawn(move || {
+        let path = Path::new(&dataset.path);
+        // The changed signature last seen, and since when it has held
+        let mut pending: Option<(FileSignature, Instant)> = None;
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let current = file_signature(path);
+            if current == loaded {
+                pending = None;
+                continue;
+            }
+            let Some(current) = current else {
+                continue;
+            };
+            match pending {
+                Some((signature, since)) if signature == current => {
+                    if since.elapsed() < debounce {
+                        continue;
+                    }
+                }
+                _ => {
+                    pending = Some((current, Instant::now()));
+                    continue;
+                }
+            }
+
+            // A reload through /admin/reload/ is already running; try again next time
+            let Some(_reloading) = dataset.try_begin_reload() else {
+                continue;
+            };
+            loaded = Some(current);
+            pending = None;
+            let _entered = info_span!("watch").entered();
+            info!("{} changed, reloading", dataset.path);
+            if dataset.reload().is_err() {
+                warn!("Waiting for {} to change again", dataset.path);
+            }
+        }
+    });
+}
@@ -0,0 +1,987 @@
+use std::io::{self, BufRead, Read};
+
+use flate2::Crc;
+
+// Decoders for .xz files, whose blocks must use the LZMA2 filter alone (as xz writes
+// them unless told otherwise), and for legacy .lzma files. Output is decoded a chunk at a
+// time into a dictionary that keeps the window matches may refer back into.
+
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const FOOTER_MAGIC: &[u8] = b"YZ";
+const LZMA2_FILTER: u64 = 0x21;
+
+// Most output decoded before it is handed on, so a read never has to wait on much more
+// than it asked for
+const STEP_BYTES: usize = 1 << 16;
+
+fn corrupt(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+// The compressed bytes, counted so chunk and block sizes can be checked, and optionally
+// kept so the index's CRC can be
+struct Input<R> {
+    inner: R,
+    consumed: u64,
+    recording: Option<Vec<u8>>,
+}
+
+impl<R: BufRead> Input<R> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let Some(&byte) = self.inner.fill_buf()?.first() else {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the compressed data ends early"));
+        };
+        self.inner.consume(1);
+        self.consumed += 1;
+        if let Some(recording) = &mut self.recording {
+            recording.push(byte);
+        }
+        Ok(byte)
+    }
+
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        for byte in &mut bytes {
+            *byte = self.byte()?;
+        }
+        Ok(bytes)
+    }
+
+    fn u16_be(&mut self) -> io::Result<usize> {
+        Ok(u16::from_be_bytes(self.bytes()?) as usize)
+    }
+
+    fn at_end(&mut self) -> io::Result<bool> {
+        Ok(self.inner.fill_buf()?.is_empty())
+    }
+}
+
+// xz's variable-length integers: seven bits a byte, least significant first
+fn multibyte(mut next: impl FnMut() -> io::Result<u8>) -> io::Result<u64> {
+    let mut value = 0;
+    for i in 0..9 {
+        let byte = next()?;
+        value |= ((byte & 0x7f) as u64) << (i * 7);
+        if byte & 0x80 == 0 {
+            if i > 0 && byte == 0 {
+                return Err(corrupt("an xz integer has a needless trailing byte"));
+            }
+            return Ok(value);
+        }
+    }
+    Err(corrupt("an xz integer is longer than nine bytes"))
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+const fn crc64_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xc96c_5795_d787_0f42 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const CRC64_TABLE: [u64; 256] = crc64_table();
+
+// The integrity check a stream's blocks end with. SHA-256 and the kinds the format
+// reserves are skipped over rather than verified.
+enum Check {
+    None,
+    Crc32(Crc),
+    Crc64(u64),
+    Unverified(usize),
+}
+
+impl Check {
+    fn new(kind: u8) -> Check {
+        match kind {
+            0x00 => Check::None,
+            0x01 => Check::Crc32(Crc::new()),
+            0x04 => Check::Crc64(!0),
+            kind => Check::Unverified([0, 4, 4, 4, 8, 8, 8, 16, 16, 16, 32, 32, 32, 64, 64, 64][kind as usize]),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Check::None => 0,
+            Check::Crc32(_) => 4,
+            Check::Crc64(_) => 8,
+            Check::Unverified(size) => *size,
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Check::Crc32(crc) => crc.update(bytes),
+            Check::Crc64(crc) => {
+                for &byte in bytes {
+                    *crc = CRC64_TABLE[((*crc as u8) ^ byte) as usize] ^ (*crc >> 8);
+                }
+            }
+            Check::None | Check::Unverified(_) => {}
+        }
+    }
+
+    // Whether the stored check matches the block's output, resetting for the next block
+    fn verify(&mut self, stored: &[u8]) -> bool {
+        let matches = match self {
+            Check::Crc32(crc) => crc.sum().to_le_bytes() == stored,
+            Check::Crc64(crc) => (!*crc).to_le_bytes() == stored,
+            Check::None | Check::Unverified(_) => true,
+        };
+        match self {
+            Check::Crc32(crc) => crc.reset(),
+            Check::Crc64(crc) => *crc = !0,
+            Check::None | Check::Unverified(_) => {}
+        }
+        matches
+    }
+}
+
+// The output since the last dictionary reset, trimmed from the front once it has been
+// read and is more than `size` back
+struct Dictionary {
+    bytes: Vec<u8>,
+    size: usize,
+    flushed: usize,
+    // Bytes since the reset, whose low bits are part of each symbol's context
+    total: u64,
+}
+
+impl Dictionary {
+    fn new(size: usize) -> Dictionary {
+        Dictionary { bytes: Vec::new(), size, flushed: 0, total: 0 }
+    }
+
+    fn reset(&mut self) {
+        self.bytes.clear();
+        self.flushed = 0;
+        self.total = 0;
+    }
+
+    fn unflushed(&self) -> &[u8] {
+        &self.bytes[self.flushed..]
+    }
+
+    // Hands on up to `out.len()` decoded bytes
+    fn flush(&mut self, out: &mut [u8], check: &mut Check) -> usize {
+        let n = self.unflushed().len().min(out.len());
+        out[..n].copy_from_slice(&self.bytes[self.flushed..self.flushed + n]);
+        check.update(&out[..n]);
+        self.flushed += n;
+        if self.bytes.len() > self.size.saturating_mul(2).saturating_add(STEP_BYTES) {
+            let cut = self.flushed.min(self.bytes.len() - self.size);
+            self.bytes.drain(..cut);
+            self.flushed -= cut;
+        }
+        n
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.bytes.push(byte);
+        self.total += 1;
+    }
+
+    // The byte `distance` + 1 back
+    fn back(&self, distance: u32) -> io::Result<u8> {
+        if distance as u64 >= self.total.min(self.size as u64) {
+            return Err(corrupt("a match refers back past the start of the dictionary"));
+        }
+        Ok(self.bytes[self.bytes.len() - 1 - distance as usize])
+    }
+
+    fn repeat(&mut self, distance: u32, len: usize) -> io::Result<()> {
+        self.back(distance)?;
+        let start = self.bytes.len() - 1 - distance as usize;
+        if len <= distance as usize + 1 {
+            self.bytes.extend_from_within(start..start + len);
+        } else {
+            for i in 0..len {
+                self.bytes.push(self.bytes[start + i]);
+            }
+        }
+        self.total += len as u64;
+        Ok(())
+    }
+}
+
+const PROBABILITY_BITS: u32 = 11;
+const PROBABILITY_INIT: u16 = 1 << (PROBABILITY_BITS - 1);
+
+struct RangeDecoder {
+    range: u32,
+    code: u32,
+}
+
+impl RangeDecoder {
+    fn init<R: BufRead>(&mut self, input: &mut Input<R>) -> io::Result<()> {
+        if input.byte()? != 0 {
+            return Err(corrupt("an LZMA range coder doesn't start with a zero byte"));
+        }
+        self.code = u32::from_be_bytes(input.bytes()?);
+        self.range = u32::MAX;
+        Ok(())
+    }
+
+    fn normalize<R: BufRead>(&mut self, input: &mut Input<R>) -> io::Result<()> {
+        if self.range < 1 << 24 {
+            self.range <<= 8;
+            self.code = (self.code << 8) | input.byte()? as u32;
+        }
+        Ok(())
+    }
+
+    fn bit<R: BufRead>(&mut self, input: &mut Input<R>, probability: &mut u16) -> io::Result<u32> {
+        self.normalize(input)?;
+        let bound = (self.range >> PROBABILITY_BITS) * *probability as u32;
+        if self.code < bound {
+            self.range = bound;
+            *probability += ((1 << PROBABILITY_BITS) - *probability) >> 5;
+            Ok(0)
+        } else {
+            self.range -= bound;
+            self.code -= bound;
+            *probability -= *probability >> 5;
+            Ok(1)
+        }
+    }
+
+    fn bit_tree<R: BufRead>(&mut self, input: &mut Input<R>, probabilities: &mut [u16], bits: u32) -> io::Result<u32> {
+        let mut symbol = 1;
+        for _ in 0..bits {
+            symbol = (symbol << 1) | self.bit(input, &mut probabilities[symbol as usize])?;
+        }
+        Ok(symbol - (1 << bits))
+    }
+
+    // A bit tree read least significant bit first, over probabilities[offset..]
+    fn reverse_bit_tree<R: BufRead>(&mut self, input: &mut Input<R>, probabilities: &mut [u16], offset: usize, bits: u32) -> io::Result<u32> {
+        let mut index = 1;
+        let mut symbol = 0;
+        for i in 0..bits {
+            let bit = self.bit(input, &mut probabilities[offset + index - 1])?;
+            index = (index << 1) | bit as usize;
+            symbol |= bit << i;
+        }
+        Ok(symbol)
+    }
+
+    fn direct_bits<R: BufRead>(&mut self, input: &mut Input<R>, bits: u32) -> io::Result<u32> {
+        let mut value: u32 = 0;
+        for _ in 0..bits {
+            self.normalize(input)?;
+            self.range >>= 1;
+            self.code = self.code.wrapping_sub(self.range);
+            let mask = 0u32.wrapping_sub(self.code >> 31);
+            self.code = self.code.wrapping_add(self.range & mask);
+            value = (value << 1).wrapping_add(mask.wrapping_add(1));
+        }
+        Ok(value)
+    }
+}
+
+const STATES: usize = 12;
+const LITERAL_STATES: usize = 7;
+const POSITION_STATES: usize = 16;
+const MATCH_LEN_MIN: usize = 2;
+const DISTANCE_MODEL_END: u32 = 14;
+const FULL_DISTANCES: usize = 128;
+const END_MARKER: u32 = u32::MAX;
+
+struct LenDecoder {
+    choice: u16,
+    choice2: u16,
+    low: [[u16; 8]; POSITION_STATES],
+    mid: [[u16; 8]; POSITION_STATES],
+    high: [u16; 256],
+}
+
+impl LenDecoder {
+    fn new() -> LenDecoder {
+        LenDecoder {
+            choice: PROBABILITY_INIT,
+            choice2: PROBABILITY_INIT,
+            low: [[PROBABILITY_INIT; 8]; POSITION_STATES],
+            mid: [[PROBABILITY_INIT; 8]; POSITION_STATES],
+            high: [PROBABILITY_INIT; 256],
+        }
+    }
+
+    fn decode<R: BufRead>(&mut self, rc: &mut RangeDecoder, input: &mut Input<R>, position_state: usize) -> io::Result<usize> {
+        Ok(if rc.bit(input, &mut self.choice)? == 0 {
+            MATCH_LEN_MIN + rc.bit_tree(input, &mut self.low[position_state], 3)? as usize
+        } else if rc.bit(input, &mut self.choice2)? == 0 {
+            MATCH_LEN_MIN + 8 + rc.bit_tree(input, &mut self.mid[position_state], 3)? as usize
+        } else {
+            MATCH_LEN_MIN + 16 + rc.bit_tree(input, &mut self.high, 8)? as usize
+        })
+    }
+}
+
+// What decoding one symbol gave
+enum Symbol {
+    Bytes(usize),
+    EndMarker,
+}
+
+struct Lzma {
+    literal_context_bits: u32,
+    literal_position_bits: u32,
+    position_bits: u32,
+    state: usize,
+    reps: [u32; 4],
+    rc: RangeDecoder,
+    is_match: [[u16; POSITION_STATES]; STATES],
+    is_rep: [u16; STATES],
+    is_rep0: [u16; STATES],
+    is_rep1: [u16; STATES],
+    is_rep2: [u16; STATES],
+    is_rep0_long: [[u16; POSITION_STATES]; STATES],
+    distance_slot: [[u16; 64]; 4],
+    distance_special: [u16; FULL_DISTANCES - DISTANCE_MODEL_END as usize],
+    distance_align: [u16; 16],
+    match_len: LenDecoder,
+    rep_len: LenDecoder,
+    literal: Vec<u16>,
+}
+
+impl Lzma {
+    fn new() -> Lzma {
+        Lzma {
+            literal_context_bits: 0,
+            literal_position_bits: 0,
+            position_bits: 0,
+            state: 0,
+            reps: [0; 4],
+            rc: RangeDecoder { range: 0, code: 0 },
+            is_match: [[PROBABILITY_INIT; POSITION_STATES]; STATES],
+            is_rep: [PROBABILITY_INIT; STATES],
+            is_rep0: [PROBABILITY_INIT; STATES],
+            is_rep1: [PROBABILITY_INIT; STATES],
+            is_rep2: [PROBABILITY_INIT; STATES],
+            is_rep0_long: [[PROBABILITY_INIT; POSITION_STATES]; STATES],
+            distance_slot: [[PROBABILITY_INIT; 64]; 4],
+            distance_special: [PROBABILITY_INIT; FULL_DISTANCES - DISTANCE_MODEL_END as usize],
+            distance_align: [PROBABILITY_INIT; 16],
+            match_len: LenDecoder::new(),
+            rep_len: LenDecoder::new(),
+            literal: Vec::new(),
+        }
+    }
+
+    // From the properties byte, (pb * 5 + lp) * 9 + lc; resets the state
+    fn set_properties(&mut self, properties: u8) -> io::Result<()> {
+        if properties >= 9 * 5 * 5 {
+            return Err(corrupt("the LZMA properties are invalid"));
+        }
+        self.literal_context_bits = (properties % 9) as u32;
+        self.literal_position_bits = (properties / 9 % 5) as u32;
+        self.position_bits = (properties / 45) as u32;
+        self.reset();
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        let literal_position_bits = self.literal_position_bits;
+        let literal_context_bits = self.literal_context_bits;
+        let position_bits = self.position_bits;
+        let mut literal = std::mem::take(&mut self.literal);
+        literal.clear();
+        literal.resize(0x300 << (literal_context_bits + literal_position_bits), PROBABILITY_INIT);
+        *self = Lzma { literal_context_bits, literal_position_bits, position_bits, literal, ..Lzma::new() };
+    }
+
+    fn decode<R: BufRead>(&mut self, input: &mut Input<R>, dict: &mut Dictionary) -> io::Result<Symbol> {
+        let rc = &mut self.rc;
+        let position_state = (dict.total & ((1 << self.position_bits) - 1)) as usize;
+        let state = self.state;
+
+        if rc.bit(input, &mut self.is_match[state][position_state])? == 0 {
+            let previous = if dict.total > 0 { dict.back(0)? } else { 0 };
+            let context = ((dict.total as u32 & ((1 << self.literal_position_bits) - 1)) << self.literal_context_bits)
+                + (previous as u32 >> (8 - self.literal_context_bits));
+            let probabilities = &mut self.literal[0x300 * context as usize..][..0x300];
+            let mut symbol: u32 = 1;
+            if state >= LITERAL_STATES {
+                // After a match the byte at rep0 predicts the bits, until one differs
+                let mut match_byte = dict.back(self.reps[0])? as u32;
+                while symbol < 0x100 {
+                    let match_bit = (match_byte >> 7) & 1;
+                    match_byte <<= 1;
+                    let bit = rc.bit(input, &mut probabilities[(((1 + match_bit) << 8) + symbol) as usize])?;
+                    symbol = (symbol << 1) | bit;
+                    if bit != match_bit {
+                        break;
+                    }
+                }
+            }
+            while symbol < 0x100 {
+                symbol = (symbol << 1) | rc.bit(input, &mut probabilities[symbol as usize])?;
+            }
+            dict.push(symbol as u8);
+            self.state = match state {
+                0..=3 => 0,
+                4..=9 => state - 3,
+                _ => state - 6,
+            };
+            return Ok(Symbol::Bytes(1));
+        }
+
+        let len = if rc.bit(input, &mut self.is_rep[state])? == 0 {
+            let len = self.match_len.decode(rc, input, position_state)?;
+            let distance_state = (len - MATCH_LEN_MIN).min(3);
+            let slot = rc.bit_tree(input, &mut self.distance_slot[distance_state], 6)?;
+            let distance = if slot < 4 {
+                slot
+            } else {
+                let bits = (slot >> 1) - 1;
+                let base = (2 | (slot & 1)) << bits;
+                if slot < DISTANCE_MODEL_END {
+                    base + rc.reverse_bit_tree(input, &mut self.distance_special, (base - slot) as usize, bits)?
+                } else {
+                    let high = rc.direct_bits(input, bits - 4)? << 4;
+                    base.wrapping_add(high).wrapping_add(rc.reverse_bit_tree(input, &mut self.distance_align, 0, 4)?)
+                }
+            };
+            if distance == END_MARKER {
+                return Ok(Symbol::EndMarker);
+            }
+            self.reps = [distance, self.reps[0], self.reps[1], self.reps[2]];
+            self.state = if state < LITERAL_STATES { 7 } else { 10 };
+            len
+        } else {
+            if rc.bit(input, &mut self.is_rep0[state])? == 0 {
+                if rc.bit(input, &mut self.is_rep0_long[state][position_state])? == 0 {
+                    dict.repeat(self.reps[0], 1)?;
+                    self.state = if state < LITERAL_STATES { 9 } else { 11 };
+                    return Ok(Symbol::Bytes(1));
+                }
+            } else {
+                let rep = if rc.bit(input, &mut self.is_rep1[state])? == 0 {
+                    1
+                } else if rc.bit(input, &mut self.is_rep2[state])? == 0 {
+                    2
+                } else {
+                    3
+                };
+                self.reps[..=rep].rotate_right(1);
+            }
+            self.state = if state < LITERAL_STATES { 8 } else { 11 };
+            self.rep_len.decode(rc, input, position_state)?
+        };
+        dict.repeat(self.reps[0], len)?;
+        Ok(Symbol::Bytes(len))
+    }
+}
+
+// The size LZMA2's one-byte dictionary property stands for
+fn lzma2_dictionary_size(property: u8) -> io::Result<usize> {
+    match property {
+        0..=39 => Ok((2 | (property as usize & 1)) << (property / 2 + 11)),
+        40 => Ok(u32::MAX as usize),
+        _ => Err(corrupt("the LZMA2 dictionary size is invalid")),
+    }
+}
+
+enum Chunk {
+    // Next is a control byte
+    Control,
+    Uncompressed { remaining: usize },
+    Lzma { remaining: usize, compressed: usize, start: u64 },
+}
+
+// LZMA2 data: chunks of LZMA or stored bytes, each saying what state it resets
+struct Lzma2 {
+    lzma: Lzma,
+    chunk: Chunk,
+    need_dictionary_reset: bool,
+    need_properties: bool,
+}
+
+impl Lzma2 {
+    fn new() -> Lzma2 {
+        Lzma2 { lzma: Lzma::new(), chunk: Chunk::Control, need_dictionary_reset: true, need_properties: true }
+    }
+
+    // Decodes up to about STEP_BYTES into the dictionary, or returns false at the end of the data
+    fn step<R: BufRead>(&mut self, input: &mut Input<R>, dict: &mut Dictionary) -> io::Result<bool> {
+        if let Chunk::Control = self.chunk {
+            let control = input.byte()?;
+            if control == 0x00 {
+                return Ok(false);
+            }
+            if control >= 0xe0 || control == 0x01 {
+                dict.reset();
+                self.need_dictionary_reset = false;
+                self.need_properties = true;
+            } else if self.need_dictionary_reset {
+                return Err(corrupt("the LZMA2 data doesn't start with a dictionary reset"));
+            }
+            self.chunk = match control {
+                0x01 | 0x02 => Chunk::Uncompressed { remaining: input.u16_be()? + 1 },
+                0x80..=0xff => {
+                    let remaining = (((control & 0x1f) as usize) << 16) + input.u16_be()? + 1;
+                    let compressed = input.u16_be()? + 1;
+                    if control >= 0xc0 {
+                        let properties = input.byte()?;
+                        self.lzma.set_properties(properties)?;
+                        if self.lzma.literal_context_bits + self.lzma.literal_position_bits > 4 {
+                            return Err(corrupt("the LZMA2 properties are invalid"));
+                        }
+                        self.need_properties = false;
+                    } else if self.need_properties {
+                        return Err(corrupt("an LZMA2 chunk comes before the properties it needs"));
+                    } else if control >= 0xa0 {
+                        self.lzma.reset();
+                    }
+                    let start = input.consumed;
+                    self.lzma.rc.init(input)?;
+                    Chunk::Lzma { remaining, compressed, start }
+                }
+                _ => return Err(corrupt(format!("an LZMA2 control byte is invalid: 0x{:02x}", control))),
+            };
+        }
+
+        match &mut self.chunk {
+            Chunk::Control => unreachable!(),
+            Chunk::Uncompressed { remaining } => {
+                let n = (*remaining).min(STEP_BYTES);
+                for _ in 0..n {
+                    dict.push(input.byte()?);
+                }
+                *remaining -= n;
+                if *remaining == 0 {
+                    self.chunk = Chunk::Control;
+                }
+            }
+            Chunk::Lzma { remaining, compressed, start } => {
+                let mut decoded = 0;
+                while *remaining > 0 && decoded < STEP_BYTES {
+                    let Symbol::Bytes(n) = self.lzma.decode(input, dict)? else {
+                        return Err(corrupt("an LZMA2 chunk has an end marker"));
+                    };
+                    *remaining = remaining.checked_sub(n).ok_or_else(|| corrupt("an LZMA2 chunk decodes to more than its size"))?;
+                    decoded += n;
+                }
+                if *remaining == 0 {
+                    // The encoder's flush leaves the range coder's code at zero
+                    self.lzma.rc.normalize(input)?;
+                    if input.consumed - *start != *compressed as u64 || self.lzma.rc.code != 0 {
+                        return Err(corrupt("an LZMA2 chunk doesn't end where its size says"));
+                    }
+                    self.chunk = Chunk::Control;
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+// Where an xz decoder is in the file
+enum Stage {
+    StreamHeader,
+    // Next is a block header, or the index
+    Blocks,
+    Block { lzma2: Box<Lzma2>, header_size: u64, start: u64, sizes: (Option<u64>, Option<u64>) },
+    // Stream padding, then another stream or the end of the file
+    StreamEnd { padding: u64 },
+    Done,
+}
+
+pub struct XzDecoder<R> {
+    input: Input<R>,
+    stage: Stage,
+    flags: [u8; 2],
+    check: Check,
+    dict: Dictionary,
+    // (unpadded size, uncompressed size) of each of the stream's blocks so far, for the index
+    blocks: Vec<(u64, u64)>,
+    index_size: u64,
+    // Output of the current block handed on so far
+    uncompressed: u64,
+}
+
+impl<R: BufRead> XzDecoder<R> {
+    pub fn new(inner: R) -> XzDecoder<R> {
+        XzDecoder {
+            input: Input { inner, consumed: 0, recording: None },
+            stage: Stage::StreamHeader,
+            flags: [0; 2],
+            check: Check::None,
+            dict: Dictionary::new(0),
+            blocks: Vec::new(),
+            index_size: 0,
+            uncompressed: 0,
+        }
+    }
+
+    // Moves through the file until there is output to hand on; false at its end
+    fn step(&mut self) -> io::Result<bool> {
+        match &mut self.stage {
+            Stage::StreamHeader => {
+                let header: [u8; 12] = self.input.bytes()?;
+                if &header[..6] != XZ_MAGIC {
+                    return Err(corrupt("the data isn't an xz stream"));
+                }
+                if crc32(&header[6..8]).to_le_bytes() != header[8..12] {
+                    return Err(corrupt("the xz stream header's CRC doesn't match"));
+                }
+                if header[6] != 0 || header[7] > 0x0f {
+                    return Err(corrupt("the xz stream header has unsupported flags"));
+                }
+                self.flags = [header[6], header[7]];
+                self.check = Check::new(header[7]);
+                self.blocks.clear();
+                self.stage = Stage::Blocks;
+            }
+            Stage::Blocks => {
+                let size = self.input.byte()?;
+                if size == 0 {
+                    self.read_index()?;
+                    self.read_footer()?;
+                    self.stage = Stage::StreamEnd { padding: 0 };
+                } else {
+                    self.stage = self.read_block_header(size)?;
+                }
+            }
+            Stage::Block { lzma2, header_size, start, sizes } => {
+                if lzma2.step(&mut self.input, &mut self.dict)? {
+                    return Ok(true);
+                }
+                let compressed = self.input.consumed - *start;
+                let uncompressed = self.uncompressed;
+                if sizes.0.is_some_and(|size| size != compressed) || sizes.1.is_some_and(|size| size != uncompressed) {
+                    return Err(corrupt("an xz block's size doesn't match its header"));
+                }
+                let unpadded = *header_size + compressed + self.check.size() as u64;
+                for _ in 0..(4 - compressed % 4) % 4 {
+                    if self.input.byte()? != 0 {
+                        return Err(corrupt("an xz block's padding isn't zero"));
+                    }
+                }
+                let mut stored = vec![0; self.check.size()];
+                for byte in &mut stored {
+                    *byte = self.input.byte()?;
+                }
+                if !self.check.verify(&stored) {
+                    return Err(corrupt("an xz block's check doesn't match its contents"));
+                }
+                self.blocks.push((unpadded, uncompressed));
+                self.stage = Stage::Blocks;
+            }
+            Stage::StreamEnd { padding } => {
+                if self.input.at_end()? {
+                    if !padding.is_multiple_of(4) {
+                        return Err(corrupt("the xz stream padding isn't a multiple of four bytes"));
+                    }
+                    self.stage = Stage::Done;
+                } else if self.input.inner.fill_buf()?[0] == 0 {
+                    self.input.byte()?;
+                    *padding += 1;
+                } else if !padding.is_multiple_of(4) {
+                    return Err(corrupt("the xz stream padding isn't a multiple of four bytes"));
+                } else {
+                    self.stage = Stage::StreamHeader;
+                }
+            }
+            Stage::Done => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn read_block_header(&mut self, size: u8) -> io::Result<Stage> {
+        let header_size = (size as usize + 1) * 4;
+        let mut header = vec![size];
+        for _ in 1..header_size {
+            header.push(self.input.byte()?);
+        }
+        let (fields, crc) = header.split_at(header_size - 4);
+        if crc32(fields).to_le_bytes() != crc {
+            return Err(corrupt("an xz block header's CRC doesn't match"));
+        }
+        let mut fields = fields[1..].iter().copied();
+        let mut next = || fields.next().ok_or_else(|| corrupt("an xz block header is cut short"));
+        let flags = next()?;
+        if flags & 0x3c != 0 {
+            return Err(corrupt("an xz block header has unsupported flags"));
+        }
+        let compressed = if flags & 0x40 != 0 { Some(multibyte(&mut next)?) } else { None };
+        let uncompressed = if flags & 0x80 != 0 { Some(multibyte(&mut next)?) } else { None };
+        let filters = (flags & 0x03) + 1;
+        let id = multibyte(&mut next)?;
+        let properties_size = multibyte(&mut next)?;
+        if filters != 1 || id != LZMA2_FILTER || properties_size != 1 {
+            return Err(corrupt("the xz file uses a filter other than LZMA2 alone, such as BCJ or delta, which isn't supported"));
+        }
+        let dictionary_size = lzma2_dictionary_size(next()?)?;
+        if fields.any(|byte| byte != 0) {
+            return Err(corrupt("an xz block header's padding isn't zero"));
+        }
+
+        // A block starts afresh, but its dictionary's buffer is worth keeping
+        let mut bytes = std::mem::take(&mut self.dict.bytes);
+        bytes.clear();
+        self.dict = Dictionary { bytes, ..Dictionary::new(dictionary_size) };
+        self.uncompressed = 0;
+        let start = self.input.consumed;
+        Ok(Stage::Block { lzma2: Box::new(Lzma2::new()), header_size: header_size as u64, start, sizes: (compressed, uncompressed) })
+    }
+
+    // The list of the stream's blocks' sizes, checked against the blocks read
+    fn read_index(&mut self) -> io::Result<()> {
+        self.input.recording = Some(vec![0]);
+        let count = multibyte(|| self.input.byte())?;
+        if count != self.blocks.len() as u64 {
+            return Err(corrupt("the xz index lists a different number of blocks"));
+        }
+        for i in 0..self.blocks.len() {
+            let unpadded = multibyte(|| self.input.byte())?;
+            let uncompressed = multibyte(|| self.input.byte())?;
+            if (unpadded, uncompressed) != self.blocks[i] {
+                return Err(corrupt("the xz index doesn't match the blocks"));
+            }
+        }
+        while !self.input.recording.as_ref().unwrap().len().is_multiple_of(4) {
+            if self.input.byte()? != 0 {
+                return Err(corrupt("the xz index padding isn't zero"));
+            }
+        }
+        let index = self.input.recording.take().unwrap();
+        if crc32(&index).to_le_bytes() != self.input.bytes::<4>()? {
+            return Err(corrupt("the xz index's CRC doesn't match"));
+        }
+        self.index_size = index.len() as u64 + 4;
+        Ok(())
+    }
+
+    fn read_footer(&mut self) -> io::Result<()> {
+        let footer: [u8; 12] = self.input.bytes()?;
+        if crc32(&footer[4..10]).to_le_bytes() != footer[..4] {
+            return Err(corrupt("the xz stream footer's CRC doesn't match"));
+        }
+        let backward_size = (u32::from_le_bytes(footer[4..8].try_into().unwrap()) as u64 + 1) * 4;
+        if &footer[10..] != FOOTER_MAGIC || footer[8..10] != self.flags || backward_size != self.index_size {
+            return Err(corrupt("the xz stream footer doesn't match its header and index"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for XzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if !self.dict.unflushed().is_empty() {
+                let n = self.dict.flush(buf, &mut self.check);
+                self.uncompressed += n as u64;
+                return Ok(n);
+            }
+            if !self.step()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+// The legacy .lzma format: the properties byte, the dictionary size, the uncompressed size
+// (all ones when unknown, the data then ending with an end marker) and one LZMA stream
+pub struct LzmaDecoder<R> {
+    input: Input<R>,
+    lzma: Lzma,
+    dict: Dictionary,
+    // Bytes still to decode, if known; None before the header is read
+    remaining: Option<Option<u64>>,
+}
+
+impl<R: BufRead> LzmaDecoder<R> {
+    pub fn new(inner: R) -> LzmaDecoder<R> {
+        LzmaDecoder { input: Input { inner, consumed: 0, recording: None }, lzma: Lzma::new(), dict: Dictionary::new(0), remaining: None }
+    }
+
+    fn read_header(&mut self) -> io::Result<Option<u64>> {
+        let properties = self.input.byte()?;
+        let dictionary_size = u32::from_le_bytes(self.input.bytes()?);
+        let size = u64::from_le_bytes(self.input.bytes()?);
+        self.lzma.set_properties(properties)?;
+        self.dict = Dictionary::new((dictionary_size as usize).max(4096));
+        self.lzma.rc.init(&mut self.input)?;
+        Ok(if size == u64::MAX { None } else { Some(size) })
+    }
+
+    // Decodes up to about STEP_BYTES; false at the end of the data
+    fn step(&mut self) -> io::Result<bool> {
+        let mut remaining = match self.remaining {
+            Some(remaining) => remaining,
+            None => self.read_header()?,
+        };
+        let mut decoded = 0;
+        while remaining != Some(0) && decoded < STEP_BYTES {
+            match self.lzma.decode(&mut self.input, &mut self.dict)? {
+                Symbol::Bytes(n) => {
+                    decoded += n;
+                    if let Some(remaining) = &mut remaining {
+                        *remaining = remaining.checked_sub(n as u64).ok_or_else(|| corrupt("the LZMA data decodes to more than its size"))?;
+                    }
+                }
+                Symbol::EndMarker if remaining.is_none() => {
+                    remaining = Some(0);
+                }
+                Symbol::EndMarker => return Err(corrupt("the LZMA data ends before its size")),
+            }
+        }
+        self.remaining = Some(remaining);
+        Ok(decoded > 0)
+    }
+}
+
+impl<R: BufRead> Read for LzmaDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if !self.dict.unflushed().is_empty() {
+                return Ok(self.dict.flush(buf, &mut Check::None));
+            }
+            if !self.step()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    // The file the fixtures were made from
+    fn contents() -> String {
+        (0..20).map(|i| format!("{{\"node_id\":{},\"parent_id\":{},\"name\":\"sample_{}\"}}\n", i, i / 2, i % 7)).collect()
+    }
+
+    // Bytes that don't compress, which LZMA2 stores in an uncompressed chunk
+    fn incompressible() -> Vec<u8> {
+        let mut x: u32 = 1;
+        (0..48).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 24) as u8
+        }).collect()
+    }
+
+    // xz -c contents
+    const XZ: &[u8] = &[
+        0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00, 0x00, 0x04, 0xe6, 0xd6, 0xb4, 0x46, 0x04, 0xc0, 0x92, 0x01,
+        0xa2, 0x07, 0x21, 0x01, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xb7, 0x39, 0x09, 0xd5,
+        0xe0, 0x03, 0xa1, 0x00, 0x8a, 0x5d, 0x00, 0x3d, 0x88, 0x89, 0xc7, 0x33, 0xa8, 0xe7, 0x77, 0x5a,
+        0x34, 0xed, 0xc3, 0xce, 0x36, 0x76, 0xee, 0x11, 0xc6, 0x7f, 0x90, 0xbb, 0xff, 0x7d, 0x26, 0x53,
+        0x02, 0x7c, 0x0b, 0x95, 0x5c, 0xbd, 0x42, 0xc6, 0xc9, 0x68, 0x76, 0xe8, 0x4b, 0x2d, 0x5d, 0xb7,
+        0x43, 0xa2, 0xaa, 0x49, 0xed, 0xed, 0x97, 0x71, 0xe0, 0xef, 0xd3, 0x28, 0x0d, 0xad, 0x4b, 0x19,
+        0x4a, 0x3c, 0x0d, 0xc1, 0x62, 0xee, 0xab, 0x1d, 0xce, 0xd4, 0xa8, 0xeb, 0xae, 0x6b, 0x39, 0x91,
+        0x34, 0xc0, 0xe4, 0x52, 0x99, 0xa9, 0xcb, 0xe7, 0x46, 0x20, 0x21, 0xa8, 0xa0, 0xe2, 0x29, 0xff,
+        0x01, 0x30, 0x89, 0x80, 0x61, 0xb5, 0x75, 0x68, 0x92, 0x05, 0x18, 0x9e, 0xac, 0xbe, 0xc0, 0x3f,
+        0x5f, 0x15, 0x39, 0xc8, 0xc1, 0x7f, 0x97, 0xbb, 0xbe, 0x2e, 0x3c, 0x23, 0x71, 0xef, 0x71, 0xfd,
+        0x53, 0x79, 0x60, 0x81, 0x34, 0x5a, 0x62, 0x94, 0x77, 0xc0, 0x2b, 0x17, 0xba, 0xf2, 0x9d, 0xb7,
+        0x00, 0x00, 0x00, 0x00, 0xc2, 0x5d, 0x67, 0xca, 0x3f, 0xfe, 0x41, 0xf8, 0x00, 0x01, 0xae, 0x01,
+        0xa2, 0x07, 0x00, 0x00, 0xf8, 0x4a, 0x0e, 0x3f, 0xb1, 0xc4, 0x67, 0xfb, 0x02, 0x00, 0x00, 0x00,
+        0x00, 0x04, 0x59, 0x5a,
+    ];
+
+    // xz -c --check=crc32 --block-size=400 contents, four bytes of stream padding, then
+    // xz -c --check=none incompressible
+    const XZ_BLOCKS: &[u8] = &[
+        0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00, 0x00, 0x01, 0x69, 0x22, 0xde, 0x36, 0x03, 0xc0, 0x5e, 0x90,
+        0x03, 0x21, 0x01, 0x16, 0x00, 0x00, 0x00, 0x00, 0x66, 0x48, 0x13, 0x03, 0xe0, 0x01, 0x8f, 0x00,
+        0x56, 0x5d, 0x00, 0x3d, 0x88, 0x89, 0xc7, 0x33, 0xa8, 0xe7, 0x77, 0x5a, 0x34, 0xed, 0xc3, 0xce,
+        0x36, 0x76, 0xee, 0x11, 0xc6, 0x7f, 0x90, 0xbb, 0xff, 0x7d, 0x26, 0x53, 0x02, 0x7c, 0x0b, 0x95,
+        0x5c, 0xbd, 0x42, 0xc6, 0xc9, 0x68, 0x76, 0xe8, 0x4b, 0x2d, 0x5d, 0xb7, 0x43, 0xa2, 0xaa, 0x49,
+        0xed, 0xed, 0x97, 0x71, 0xe0, 0xef, 0xd3, 0x28, 0x0d, 0xad, 0x4b, 0x19, 0x4a, 0x3c, 0x0d, 0xc1,
+        0x62, 0xee, 0xab, 0x1d, 0xce, 0xd4, 0xa8, 0xeb, 0xae, 0x6b, 0x39, 0x91, 0x34, 0xc0, 0xe4, 0x52,
+        0x99, 0xa9, 0xcb, 0xe7, 0x47, 0xba, 0x38, 0x7c, 0x00, 0x00, 0x00, 0x00, 0xe1, 0x3c, 0x49, 0x36,
+        0x03, 0xc0, 0x62, 0x90, 0x03, 0x21, 0x01, 0x16, 0x00, 0x00, 0x00, 0x00, 0xd0, 0x2a, 0xdc, 0x71,
+        0xe0, 0x01, 0x8f, 0x00, 0x5a, 0x5d, 0x00, 0x11, 0x0e, 0x80, 0x6b, 0x22, 0xc5, 0xf4, 0x5b, 0xa5,
+        0xe3, 0xaf, 0xd5, 0x50, 0xaa, 0xe6, 0x16, 0x56, 0xc5, 0x37, 0x36, 0x48, 0x1d, 0xd1, 0x77, 0x83,
+        0xc5, 0x9d, 0x84, 0x93, 0xd3, 0xe5, 0xcf, 0xb3, 0xff, 0x9a, 0x28, 0xf6, 0xf4, 0xe1, 0x1b, 0xdb,
+        0x4e, 0xbf, 0xfd, 0x75, 0x0a, 0xb5, 0x4e, 0xa7, 0x07, 0xeb, 0x03, 0x6e, 0x88, 0x47, 0xbf, 0xc9,
+        0xa1, 0xcd, 0x4a, 0x1f, 0x51, 0x85, 0x02, 0x23, 0x30, 0x93, 0x86, 0xb4, 0x14, 0x15, 0xd1, 0x60,
+        0x1b, 0xb7, 0x92, 0x12, 0xe9, 0x89, 0xf2, 0xae, 0x3c, 0x9f, 0x00, 0x32, 0x00, 0x2f, 0x1c, 0x9c,
+        0x00, 0x00, 0x00, 0x00, 0xe3, 0xa1, 0xf5, 0xc9, 0x03, 0xc0, 0x45, 0x82, 0x01, 0x21, 0x01, 0x16,
+        0x00, 0x00, 0x00, 0x00, 0xf3, 0x5f, 0x4e, 0x1c, 0xe0, 0x00, 0x81, 0x00, 0x3d, 0x5d, 0x00, 0x18,
+        0x8d, 0xc1, 0xb9, 0x57, 0xb1, 0x1c, 0x1e, 0xe4, 0x52, 0xf2, 0xff, 0xae, 0x09, 0x14, 0x8a, 0x61,
+        0x6f, 0x7a, 0xab, 0x42, 0x96, 0x6b, 0x05, 0x66, 0x45, 0x17, 0xec, 0xe1, 0x58, 0xc5, 0x8b, 0x9c,
+        0xc5, 0x21, 0x68, 0x9f, 0xc3, 0x1a, 0x93, 0xe1, 0x00, 0x53, 0x65, 0x41, 0x5a, 0x01, 0xd5, 0xae,
+        0x6e, 0x1e, 0x41, 0x5b, 0x43, 0x57, 0xd2, 0xa4, 0xac, 0xda, 0x2d, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x20, 0x5b, 0x0d, 0xa9, 0x00, 0x03, 0x72, 0x90, 0x03, 0x76, 0x90, 0x03, 0x59, 0x82, 0x01, 0x00,
+        0x13, 0x87, 0x40, 0xcc, 0x9b, 0xe3, 0x51, 0x40, 0x03, 0x00, 0x00, 0x00, 0x00, 0x01, 0x59, 0x5a,
+        0x00, 0x00, 0x00, 0x00, 0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00, 0x00, 0x00, 0xff, 0x12, 0xd9, 0x41,
+        0x04, 0xc0, 0x34, 0x30, 0x21, 0x01, 0x16, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x6c, 0x13, 0x5f, 0x61, 0x01, 0x00, 0x2f, 0x41, 0x96, 0x27, 0xc4, 0xf9, 0x95, 0xd9, 0x9c, 0xbf,
+        0x0f, 0x0a, 0x31, 0x23, 0xaf, 0x7d, 0xc4, 0xe2, 0xd2, 0xe2, 0xe3, 0xe9, 0x93, 0x50, 0x28, 0x2c,
+        0x75, 0x42, 0xb3, 0x4d, 0xe4, 0xf7, 0xef, 0xee, 0x56, 0xe1, 0xca, 0x31, 0xad, 0x99, 0x69, 0xb5,
+        0x3b, 0x7d, 0x10, 0x1b, 0x7a, 0xde, 0xb4, 0x00, 0x00, 0x01, 0x48, 0x30, 0x8a, 0x40, 0xff, 0x3e,
+        0x06, 0x72, 0x9e, 0x7a, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x59, 0x5a,
+    ];
+
+    // xz -c --format=lzma contents
+    const LZMA: &[u8] = &[
+        0x5d, 0x00, 0x00, 0x80, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x3d, 0x88,
+        0x89, 0xc7, 0x33, 0xa8, 0xe7, 0x77, 0x5a, 0x34, 0xed, 0xc3, 0xce, 0x36, 0x76, 0xee, 0x11, 0xc6,
+        0x7f, 0x90, 0xbb, 0xff, 0x7d, 0x26, 0x53, 0x02, 0x7c, 0x0b, 0x95, 0x5c, 0xbd, 0x42, 0xc6, 0xc9,
+        0x68, 0x76, 0xe8, 0x4b, 0x2d, 0x5d, 0xb7, 0x43, 0xa2, 0xaa, 0x49, 0xed, 0xed, 0x97, 0x71, 0xe0,
+        0xef, 0xd3, 0x28, 0x0d, 0xad, 0x4b, 0x19, 0x4a, 0x3c, 0x0d, 0xc1, 0x62, 0xee, 0xab, 0x1d, 0xce,
+        0xd4, 0xa8, 0xeb, 0xae, 0x6b, 0x39, 0x91, 0x34, 0xc0, 0xe4, 0x52, 0x99, 0xa9, 0xcb, 0xe7, 0x46,
+        0x20, 0x21, 0xa8, 0xa0, 0xe2, 0x29, 0xff, 0x01, 0x30, 0x89, 0x80, 0x61, 0xb5, 0x75, 0x68, 0x92,
+        0x05, 0x18, 0x9e, 0xac, 0xbe, 0xc0, 0x3f, 0x5f, 0x15, 0x39, 0xc8, 0xc1, 0x7f, 0x97, 0xbb, 0xbe,
+        0x2e, 0x3c, 0x23, 0x71, 0xef, 0x71, 0xfd, 0x53, 0x79, 0x60, 0x81, 0x34, 0x5a, 0x62, 0x94, 0x77,
+        0xc0, 0x2b, 0x17, 0xba, 0xf6, 0x68, 0x81, 0xe3, 0xff, 0xff, 0x0c, 0xfd, 0x87, 0x00,
+    ];
+
+    fn decode(decoder: impl Read) -> io::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        BufReader::new(decoder).read_to_end(&mut decoded)?;
+        Ok(decoded)
+    }
+
+    #[test]
+    fn decodes_xz() {
+        assert_eq!(decode(XzDecoder::new(XZ)).unwrap(), contents().as_bytes());
+        let mut expected = contents().into_bytes();
+        expected.extend(incompressible());
+        assert_eq!(decode(XzDecoder::new(XZ_BLOCKS)).unwrap(), expected);
+    }
+
+    #[test]
+    fn decodes_lzma() {
+        assert_eq!(decode(LzmaDecoder::new(LZMA)).unwrap(), contents().as_bytes());
+    }
+
+    #[test]
+    fn rejects_corrupt_xz() {
+        let cut = &XZ[..XZ.len() / 2];
+        assert_eq!(decode(XzDecoder::new(cut)).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        // A flipped bit anywhere, whether in a header, the compressed data, the check, the
+        // index or the footer, is noticed
+        for i in 0..XZ.len() {
+            let mut corrupt = XZ.to_vec();
+            corrupt[i] ^= 0x10;
+            assert!(decode(XzDecoder::new(&corrupt[..])).is_err(), "flipped a bit of byte {}", i);
+        }
+    }
+}